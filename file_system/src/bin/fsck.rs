@@ -0,0 +1,47 @@
+// Check the consistency of a Rarmo filesystem image on host.
+//
+// Usage: fsck [-r] fs.img
+//
+// With `-r`, the problems found are also repaired in place.
+use std::{env, process, ptr};
+use file_system::block_device::BlockDevice;
+use file_system::defines::{BLOCK_SIZE, SuperBlock};
+use file_system::file_device::FileBlockDevice;
+use file_system::fsck::{fsck, Problem};
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let (repair, path) = match args.as_slice() {
+        [flag, path] if flag == "-r" => (true, path),
+        [path] => (false, path),
+        _ => {
+            eprintln!("Usage: fsck [-r] fs.img");
+            process::exit(2);
+        }
+    };
+
+    let mut device = FileBlockDevice::open(path).unwrap_or_else(|e| {
+        eprintln!("fsck: {}: {}", path, e);
+        process::exit(2);
+    });
+    let mut buf = [0u8; BLOCK_SIZE];
+    device.read(1, &mut buf);
+    let sblock: SuperBlock = unsafe { ptr::read_unaligned(buf.as_ptr() as *const SuperBlock) };
+    if device.num_blocks().unwrap() < sblock.num_blocks as usize {
+        eprintln!("fsck: {}: image is smaller than {} blocks", path, sblock.num_blocks);
+        process::exit(2);
+    }
+
+    let problems = fsck(&mut device, &sblock, repair);
+    for problem in &problems {
+        println!("{}", problem);
+    }
+    if problems.is_empty() {
+        println!("{}: clean", path);
+    } else if repair && !matches!(problems[0], Problem::BadSuperBlock(_)) {
+        println!("{}: {} problems repaired", path, problems.len());
+    } else {
+        println!("{}: {} problems found", path, problems.len());
+        process::exit(1);
+    }
+}
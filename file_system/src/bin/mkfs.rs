@@ -0,0 +1,173 @@
+// Build a Rarmo filesystem image on host.
+//
+// Usage: mkfs fs.img files...
+//
+// Regular files are copied into the root directory. Directories are copied recursively as
+// subdirectories of the same name. A leading `_` in a name is dropped, so that binaries can be
+// named `_rm`, `_cat`, etc. without clashing with the ones of the host.
+use std::{env, fs, process, ptr};
+use std::mem::size_of;
+use std::path::Path;
+use file_system::block_device::BlockDevice;
+use file_system::cache::{init_bcache, SCACHE};
+use file_system::defines::{BIT_PER_BLOCK, BLOCK_SIZE, FILE_NAME_MAX_LENGTH, INODE_DIRECTORY, INODE_MAX_SIZE, INODE_PER_BLOCK, INODE_REGULAR, LOG_MAX_SIZE, ROOT_INODE_NO, SuperBlock};
+use file_system::file_device::FileBlockDevice;
+use file_system::inode::{init_inodes, Inode, INODES, InodeTree};
+
+// Size of the filesystem in blocks.
+const FS_SIZE: usize = 1000;
+const NUM_INODES: usize = 200;
+
+// Disk layout:
+// [ boot block | super block | log | inode blocks | bitmap blocks | data blocks ]
+fn layout() -> SuperBlock {
+    let num_log_blocks = LOG_MAX_SIZE;
+    let num_inode_blocks = NUM_INODES / INODE_PER_BLOCK + 1;
+    let num_bitmap_blocks = FS_SIZE / BIT_PER_BLOCK + 1;
+    let num_meta_blocks = 2 + num_log_blocks + num_inode_blocks + num_bitmap_blocks;
+    SuperBlock {
+        num_blocks: FS_SIZE as u32,
+        num_data_blocks: (FS_SIZE - num_meta_blocks) as u32,
+        num_inodes: NUM_INODES as u32,
+        num_log_blocks: num_log_blocks as u32,
+        log_start: 2,
+        inode_start: (2 + num_log_blocks) as u32,
+        bitmap_start: (2 + num_log_blocks + num_inode_blocks) as u32,
+    }
+}
+
+fn fail(msg: String) -> ! {
+    eprintln!("mkfs: {}", msg);
+    process::exit(1);
+}
+
+// Turn a host file name into a name in the filesystem.
+fn file_name(path: &Path) -> String {
+    let name = path.file_name()
+        .and_then(|name| name.to_str())
+        .unwrap_or_else(|| fail(format!("{}: invalid file name", path.display())));
+    let name = name.strip_prefix('_').unwrap_or(name);
+    let mut len = name.len().min(FILE_NAME_MAX_LENGTH);
+    while !name.is_char_boundary(len) {
+        len -= 1;
+    }
+    if len < name.len() {
+        eprintln!("mkfs: {}: name truncated to {}", path.display(), &name[..len]);
+    }
+    name[..len].to_string()
+}
+
+// Make sure every file under `path` fits in an inode, before anything is written to the image.
+fn check_size(path: &Path) {
+    if path.is_dir() {
+        for entry in fs::read_dir(path).unwrap_or_else(|e| fail(format!("{}: {}", path.display(), e))) {
+            check_size(&entry.unwrap().path());
+        }
+        return;
+    }
+    let len = fs::metadata(path)
+        .unwrap_or_else(|e| fail(format!("{}: {}", path.display(), e)))
+        .len() as usize;
+    if len > INODE_MAX_SIZE {
+        fail(format!("{}: file is too large ({} bytes, at most {})", path.display(), len, INODE_MAX_SIZE));
+    }
+}
+
+unsafe fn alloc_inode(typ: u16) -> *mut Inode {
+    let inode = INODES.get(INODES.alloc(ptr::null_mut(), typ));
+    INODES.lock(inode);
+    (*inode).entry.num_links = 1;
+    INODES.sync(ptr::null_mut(), inode);
+    inode
+}
+
+// Link `inode` into the locked directory `dir` as `name`.
+unsafe fn link(dir: *mut Inode, name: &str, inode: *mut Inode) {
+    if INODES.insert(ptr::null_mut(), dir, name, (*inode).inode_no).is_none() {
+        fail(format!("{}: duplicate file name, or the directory is full", name));
+    }
+}
+
+// Copy the host file or directory at `path` into the locked directory `dir`.
+unsafe fn copy(dir: *mut Inode, path: &Path) {
+    let name = file_name(path);
+    println!("input: '{}' -> '{}'", path.display(), name);
+    if path.is_dir() {
+        let inode = alloc_inode(INODE_DIRECTORY);
+        link(inode, ".", inode);
+        link(inode, "..", dir);
+        link(dir, &name, inode);
+        let mut entries: Vec<_> = fs::read_dir(path)
+            .unwrap_or_else(|e| fail(format!("{}: {}", path.display(), e)))
+            .map(|entry| entry.unwrap().path())
+            .collect();
+        entries.sort();
+        for entry in entries {
+            copy(inode, &entry);
+        }
+        INODES.unlock(inode);
+        INODES.put(ptr::null_mut(), inode);
+    } else {
+        let data = fs::read(path).unwrap_or_else(|e| fail(format!("{}: {}", path.display(), e)));
+        let inode = alloc_inode(INODE_REGULAR);
+        assert_eq!(INODES.write(ptr::null_mut(), inode, &data, 0), data.len());
+        link(dir, &name, inode);
+        INODES.unlock(inode);
+        INODES.put(ptr::null_mut(), inode);
+    }
+}
+
+fn main() {
+    let args: Vec<String> = env::args().collect();
+    if args.len() < 2 {
+        eprintln!("Usage: mkfs fs.img files...");
+        process::exit(1);
+    }
+
+    for arg in &args[2..] {
+        check_size(Path::new(arg));
+    }
+
+    let sblock = layout();
+    let num_meta_blocks = (sblock.num_blocks - sblock.num_data_blocks) as usize;
+    println!("nmeta {} (boot, super, log blocks {} inode blocks {}, bitmap blocks {}) blocks {} total {}",
+             num_meta_blocks,
+             sblock.num_log_blocks,
+             sblock.bitmap_start - sblock.inode_start,
+             num_meta_blocks - sblock.bitmap_start as usize,
+             sblock.num_data_blocks,
+             sblock.num_blocks);
+
+    let device = FileBlockDevice::create(&args[1], FS_SIZE)
+        .unwrap_or_else(|e| fail(format!("{}: {}", args[1], e)));
+    let device: &'static mut FileBlockDevice = Box::leak(Box::new(device));
+
+    let mut buf = [0u8; BLOCK_SIZE];
+    unsafe { ptr::copy_nonoverlapping(&sblock as *const _ as *const u8, buf.as_mut_ptr(), size_of::<SuperBlock>()); }
+    device.write(1, &buf);
+
+    // Mark all the metadata blocks as used, so that data blocks are allocated after them.
+    let num_bitmap_blocks = num_meta_blocks - sblock.bitmap_start as usize;
+    for i in 0..num_bitmap_blocks {
+        let mut bitmap = [0u8; BLOCK_SIZE];
+        for j in i * BIT_PER_BLOCK..num_meta_blocks.min((i + 1) * BIT_PER_BLOCK) {
+            bitmap[j % BIT_PER_BLOCK / 8] |= 1 << (j % 8);
+        }
+        device.write(sblock.bitmap_start as usize + i, &bitmap);
+    }
+
+    unsafe {
+        init_bcache(&sblock, device);
+        init_inodes(&sblock, &mut SCACHE);
+
+        let root = alloc_inode(INODE_DIRECTORY);
+        assert_eq!((*root).inode_no, ROOT_INODE_NO);
+        link(root, ".", root);
+        link(root, "..", root);
+        for arg in &args[2..] {
+            copy(root, Path::new(arg));
+        }
+        INODES.unlock(root);
+        INODES.put(ptr::null_mut(), root);
+    }
+}
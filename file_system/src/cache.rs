@@ -1,39 +1,51 @@
-use parking_lot::Mutex;
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use core::cmp::min;
+use core::mem::{MaybeUninit, size_of};
+use core::slice;
 use crate::block_device::BlockDevice;
-use crate::defines::{BLOCK_SIZE, SuperBlock};
+use crate::defines::{BIT_PER_BLOCK, BLOCK_SIZE, LOG_MAX_SIZE, LogHeader, SuperBlock};
+use crate::sync::{Condvar, Mutex, RawMutex, RawMutexTrait};
 
 pub const OP_MAX_NUM_BLOCKS: usize = 10;
 pub const EVICTION_THRESHOLD: usize = 10;
 
 pub struct OpContext {
     pub ts: usize,
+    // number of new blocks this operation can still put into the log.
+    pub rm: usize,
 }
 
 impl OpContext {
     pub const fn new() -> Self {
         Self {
             ts: 0,
+            rm: 0,
         }
     }
 }
 
-static CACHE_LOCK: Mutex<()> = Mutex::new(());
-
 pub struct Block {
     pub block_no: usize,
     pub acquired: bool,
     pub pinned: bool,
-
-    // todo sleeplock
+    // number of `acquire`s which have not been `release`d yet, including the ones still waiting for `lock`.
+    // A block can only be evicted when it is zero.
+    ref_count: usize,
+    // sleep lock of the block, held between `acquire` and `release`.
+    lock: RawMutex,
     pub valid: bool,
     pub data: [u8; BLOCK_SIZE],
 }
-impl Block{
+
+impl Block {
     pub const fn new() -> Self {
         Self {
             block_no: 0,
             acquired: false,
             pinned: false,
+            ref_count: 0,
+            lock: RawMutex::INIT,
             valid: false,
             data: [0; BLOCK_SIZE],
         }
@@ -45,6 +57,7 @@ impl Block{
         }
     }
 }
+
 pub trait BlockCache {
     fn get_num_cached_blocks(&self) -> usize;
 
@@ -64,44 +77,309 @@ pub trait BlockCache {
     fn free(&mut self, ctx: *mut OpContext, block_no: usize);
 }
 
+struct CacheList {
+    // All cached blocks, ordered from the least recently used to the most recently used.
+    blocks: Vec<*mut Block>,
+}
+
+unsafe impl Send for CacheList {}
+
+impl CacheList {
+    const fn new() -> Self {
+        Self {
+            blocks: Vec::new(),
+        }
+    }
+
+    fn find(&self, block_no: usize) -> Option<usize> {
+        self.blocks.iter().position(|b| unsafe { (**b).block_no } == block_no)
+    }
+
+    // Evict the least recently used blocks until there are no more than `EVICTION_THRESHOLD` blocks,
+    // or all the remaining blocks are in use (acquired or pinned by the log).
+    fn evict(&mut self) {
+        while self.blocks.len() > EVICTION_THRESHOLD {
+            let victim = self.blocks.iter()
+                .position(|b| unsafe { (**b).ref_count == 0 && !(**b).pinned });
+            match victim {
+                Some(i) => {
+                    let block = self.blocks.remove(i);
+                    unsafe { drop(Box::from_raw(block)); }
+                }
+                None => break,
+            }
+        }
+    }
+
+    fn clear(&mut self) {
+        for block in self.blocks.drain(..) {
+            unsafe { drop(Box::from_raw(block)); }
+        }
+    }
+}
+
+struct Log {
+    header: LogHeader,
+    // number of operations between `begin_op` and `end_op`.
+    outstanding: usize,
+    // number of new blocks the outstanding operations can still put into the log, i.e. the sum of their `rm`.
+    reserved: usize,
+    committing: bool,
+    // the sequence number of the current transaction group, increased after each commit.
+    epoch: usize,
+}
+
+impl Log {
+    const fn new() -> Self {
+        Self {
+            header: LogHeader {
+                num_blocks: 0,
+                block_no: [0; LOG_MAX_SIZE],
+            },
+            outstanding: 0,
+            reserved: 0,
+            committing: false,
+            epoch: 0,
+        }
+    }
+
+    fn contains(&self, block_no: usize) -> bool {
+        self.header.block_no[..self.header.num_blocks].contains(&block_no)
+    }
+}
+
+// Lock order: `LOG` before `CACHE`.
+static CACHE: Mutex<CacheList> = Mutex::new(CacheList::new());
+static LOG: Mutex<Log> = Mutex::new(Log::new());
+static LOG_CV: Condvar = Condvar::new();
+
+static mut SBLOCK: MaybeUninit<SuperBlock> = MaybeUninit::uninit();
+static mut DEVICE: MaybeUninit<*mut dyn BlockDevice> = MaybeUninit::uninit();
+
+fn sblock() -> &'static SuperBlock {
+    unsafe { SBLOCK.assume_init_ref() }
+}
+
+fn device() -> &'static mut dyn BlockDevice {
+    unsafe { &mut **DEVICE.assume_init_ref() }
+}
+
+// The log area is `[header | log blocks...]`, and the header can record at most `LOG_MAX_SIZE` blocks.
+fn log_capacity() -> usize {
+    min(sblock().num_log_blocks as usize - 1, LOG_MAX_SIZE)
+}
+
+fn read_header(header: &mut LogHeader) {
+    let buf = unsafe { slice::from_raw_parts_mut(header as *mut _ as *mut u8, size_of::<LogHeader>()) };
+    device().read(sblock().log_start as usize, buf);
+}
+
+fn write_header(header: &LogHeader) {
+    let buf = unsafe { slice::from_raw_parts(header as *const _ as *const u8, size_of::<LogHeader>()) };
+    device().write(sblock().log_start as usize, buf);
+}
+
+// Copy every block recorded in `header` from the log area to its home location.
+fn replay(header: &LogHeader) {
+    let mut buf = [0u8; BLOCK_SIZE];
+    let log_start = sblock().log_start as usize;
+    for i in 0..header.num_blocks {
+        device().read(log_start + 1 + i, &mut buf);
+        device().write(header.block_no[i], &buf);
+    }
+}
+
 pub struct BlockCacheImpl;
 
+impl BlockCacheImpl {
+    fn set_pinned(&self, block: *mut Block, pinned: bool) {
+        let _lock = CACHE.lock();
+        unsafe { (*block).pinned = pinned; }
+    }
+
+    // Write all the blocks in the log to disk. Only called by the last `end_op` of a transaction group,
+    // so no operation can touch the log during the commit.
+    fn commit(&mut self, header: &mut LogHeader) {
+        if header.num_blocks == 0 {
+            return;
+        }
+        let log_start = sblock().log_start as usize;
+        // 1. Copy the modified blocks from the cache to the log area.
+        for i in 0..header.num_blocks {
+            let block = self.acquire(header.block_no[i]);
+            device().write(log_start + 1 + i, unsafe { &(*block).data });
+            self.release(block);
+        }
+        // 2. Write the header. This is the real commit point.
+        write_header(header);
+        // 3. Install the blocks to their home locations.
+        for i in 0..header.num_blocks {
+            let block = self.acquire(header.block_no[i]);
+            device().write(header.block_no[i], unsafe { &(*block).data });
+            self.set_pinned(block, false);
+            self.release(block);
+        }
+        // 4. Erase the transaction from the log.
+        header.num_blocks = 0;
+        write_header(header);
+    }
+}
+
 impl BlockCache for BlockCacheImpl {
     fn get_num_cached_blocks(&self) -> usize {
-        todo!()
+        CACHE.lock().blocks.len()
     }
 
     fn acquire(&mut self, block_no: usize) -> *mut Block {
-        todo!()
+        let block = {
+            let mut cache = CACHE.lock();
+            let block = match cache.find(block_no) {
+                Some(i) => cache.blocks.remove(i),
+                None => {
+                    let mut block = Box::new(Block::new());
+                    block.block_no = block_no;
+                    Box::into_raw(block)
+                }
+            };
+            // Move it to the most recently used position.
+            cache.blocks.push(block);
+            unsafe { (*block).ref_count += 1; }
+            cache.evict();
+            block
+        };
+        let block = unsafe { &mut *block };
+        // Sleep until other holders release the block.
+        block.lock.lock();
+        block.acquired = true;
+        if !block.valid {
+            device().read(block_no, &mut block.data);
+            block.valid = true;
+        }
+        block
     }
 
     fn release(&mut self, block: *mut Block) {
-        todo!()
+        let block = unsafe { &mut *block };
+        assert!(block.acquired, "release: block {} is not acquired", block.block_no);
+        block.acquired = false;
+        unsafe { block.lock.unlock(); }
+        let mut cache = CACHE.lock();
+        block.ref_count -= 1;
+        cache.evict();
     }
 
     fn begin_op(&mut self, ctx: &mut OpContext) {
-        todo!()
+        let mut log = LOG.lock();
+        // Wait until the log has room for what the outstanding operations can still put into it,
+        // and for `OP_MAX_NUM_BLOCKS` new blocks of this one.
+        LOG_CV.wait_while(&mut log, |log| {
+            log.committing || log.header.num_blocks + log.reserved + OP_MAX_NUM_BLOCKS > log_capacity()
+        });
+        log.outstanding += 1;
+        log.reserved += OP_MAX_NUM_BLOCKS;
+        ctx.ts = log.epoch;
+        ctx.rm = OP_MAX_NUM_BLOCKS;
     }
 
     fn sync(&mut self, ctx: *mut OpContext, block: *mut Block) {
-        todo!()
+        if ctx.is_null() {
+            // Not in an atomic operation, write it through.
+            let block = unsafe { &*block };
+            device().write(block.block_no, &block.data);
+            return;
+        }
+        let ctx = unsafe { &mut *ctx };
+        let block_no = unsafe { (*block).block_no };
+        let mut log = LOG.lock();
+        if !log.contains(block_no) {
+            assert!(ctx.rm > 0, "sync: operation writes more than {} blocks", OP_MAX_NUM_BLOCKS);
+            assert!(log.header.num_blocks < log_capacity(), "sync: log is full");
+            ctx.rm -= 1;
+            log.reserved -= 1;
+            let n = log.header.num_blocks;
+            log.header.block_no[n] = block_no;
+            log.header.num_blocks += 1;
+        }
+        // Keep the block in the cache until it is installed by `commit`.
+        self.set_pinned(block, true);
     }
 
     fn end_op(&mut self, ctx: &mut OpContext) {
-        todo!()
+        let mut log = LOG.lock();
+        log.outstanding -= 1;
+        log.reserved -= ctx.rm;
+        ctx.rm = 0;
+        if log.outstanding > 0 {
+            // Someone else is still in this transaction group. Wait for the last one to commit.
+            // The room we did not use is free now, which may let a new operation in.
+            LOG_CV.notify_all();
+            LOG_CV.wait_while(&mut log, |log| log.epoch == ctx.ts);
+            return;
+        }
+        // `committing` keeps new operations out, so we can release the lock during disk I/O.
+        log.committing = true;
+        let mut header = log.header.clone();
+        drop(log);
+        self.commit(&mut header);
+        let mut log = LOG.lock();
+        log.header.num_blocks = 0;
+        log.committing = false;
+        log.epoch += 1;
+        LOG_CV.notify_all();
     }
 
     fn alloc(&mut self, ctx: *mut OpContext) -> usize {
-        todo!()
+        let sblock = sblock();
+        let num_blocks = sblock.num_blocks as usize;
+        for i in (0..num_blocks).step_by(BIT_PER_BLOCK) {
+            let bitmap = self.acquire(sblock.bitmap_start as usize + i / BIT_PER_BLOCK);
+            let data = unsafe { &mut (*bitmap).data };
+            for j in 0..min(BIT_PER_BLOCK, num_blocks - i) {
+                if data[j / 8] & (1 << (j % 8)) == 0 {
+                    data[j / 8] |= 1 << (j % 8);
+                    self.sync(ctx, bitmap);
+                    self.release(bitmap);
+
+                    let block = self.acquire(i + j);
+                    unsafe { (*block).fill_zero(); }
+                    self.sync(ctx, block);
+                    self.release(block);
+                    return i + j;
+                }
+            }
+            self.release(bitmap);
+        }
+        panic!("alloc: no free block");
     }
 
     fn free(&mut self, ctx: *mut OpContext, block_no: usize) {
-        todo!()
+        let bitmap = self.acquire(sblock().bitmap_start as usize + block_no / BIT_PER_BLOCK);
+        let data = unsafe { &mut (*bitmap).data };
+        let j = block_no % BIT_PER_BLOCK;
+        assert_ne!(data[j / 8] & (1 << (j % 8)), 0, "free: block {} is not allocated", block_no);
+        data[j / 8] &= !(1 << (j % 8));
+        self.sync(ctx, bitmap);
+        self.release(bitmap);
     }
 }
 
-pub fn init_bcache(sblock: &SuperBlock, device: &dyn BlockDevice) {
-    todo!()
+pub fn init_bcache(sblock: &SuperBlock, device: &'static mut dyn BlockDevice) {
+    unsafe {
+        SBLOCK = MaybeUninit::new(sblock.clone());
+        DEVICE = MaybeUninit::new(device);
+    }
+    CACHE.lock().clear();
+
+    let mut log = LOG.lock();
+    log.outstanding = 0;
+    log.reserved = 0;
+    log.committing = false;
+    log.epoch = 0;
+    // Replay the transaction that has been committed but not installed before the last crash.
+    read_header(&mut log.header);
+    replay(&log.header);
+    log.header.num_blocks = 0;
+    write_header(&log.header);
 }
 
-pub static mut SCACHE: BlockCacheImpl = BlockCacheImpl;
\ No newline at end of file
+pub static mut SCACHE: BlockCacheImpl = BlockCacheImpl;
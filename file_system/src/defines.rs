@@ -1,4 +1,4 @@
-use std::mem::size_of;
+use core::mem::size_of;
 
 pub const BLOCK_SIZE: usize = 512;
 pub const LOG_MAX_SIZE: usize = (BLOCK_SIZE - size_of::<usize>()) / size_of::<usize>();
@@ -20,7 +20,7 @@ pub const BIT_PER_BLOCK: usize = BLOCK_SIZE * 8;
 pub const ROOT_INODE_NO: usize = 1;
 
 #[repr(C)]
-#[derive(Clone)]
+#[derive(Clone, Debug)]
 pub struct SuperBlock {
     pub num_blocks: u32,
     // total number of blocks in filesystem.
@@ -35,6 +35,30 @@ pub struct SuperBlock {
     pub bitmap_start: u32,    // the first block of bitmap area.
 }
 
+impl SuperBlock {
+    // Check that the log, inode, bitmap and data areas follow each other without overlapping,
+    // or tell what is wrong with them.
+    pub fn check_layout(&self) -> Result<(), &'static str> {
+        if self.num_data_blocks > self.num_blocks {
+            return Err("more data blocks than blocks");
+        }
+        let num_inode_blocks = (self.num_inodes as usize + INODE_PER_BLOCK - 1) / INODE_PER_BLOCK;
+        let num_bitmap_blocks = (self.num_blocks as usize + BIT_PER_BLOCK - 1) / BIT_PER_BLOCK;
+        let data_start = (self.num_blocks - self.num_data_blocks) as usize;
+        if self.log_start < 2 || self.num_log_blocks < 2 {
+            Err("bad log area")
+        } else if (self.inode_start as usize) < self.log_start as usize + self.num_log_blocks as usize {
+            Err("inode area overlaps the log")
+        } else if (self.bitmap_start as usize) < self.inode_start as usize + num_inode_blocks {
+            Err("bitmap area overlaps inodes")
+        } else if data_start < self.bitmap_start as usize + num_bitmap_blocks {
+            Err("data area overlaps the bitmap")
+        } else {
+            Ok(())
+        }
+    }
+}
+
 #[repr(C)]
 pub struct InodeEntry {
     pub typ: u16,
@@ -62,7 +86,26 @@ pub struct DirEntry {
     pub name: [u8; FILE_NAME_MAX_LENGTH],
 }
 
+impl DirEntry {
+    pub fn new(inode_no: usize, name: &str) -> Self {
+        assert!(name.len() <= FILE_NAME_MAX_LENGTH, "file name {} is too long", name);
+        let mut entry = Self {
+            inode_no: inode_no as u16,
+            name: [0; FILE_NAME_MAX_LENGTH],
+        };
+        entry.name[..name.len()].copy_from_slice(name.as_bytes());
+        entry
+    }
+
+    // The name without trailing zeros. A name of exactly `FILE_NAME_MAX_LENGTH` bytes is not zero-terminated.
+    pub fn name(&self) -> &[u8] {
+        let len = self.name.iter().position(|&c| c == 0).unwrap_or(FILE_NAME_MAX_LENGTH);
+        &self.name[..len]
+    }
+}
+
 #[repr(C)]
+#[derive(Clone)]
 pub struct LogHeader {
     pub num_blocks: usize,
     pub block_no: [usize; LOG_MAX_SIZE],
@@ -0,0 +1,43 @@
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use crate::block_device::BlockDevice;
+use crate::defines::BLOCK_SIZE;
+
+// A block device backed by an image file on host, used by host tools like `mkfs`.
+pub struct FileBlockDevice {
+    file: File,
+}
+
+impl FileBlockDevice {
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let file = OpenOptions::new().read(true).write(true).open(path)?;
+        Ok(Self { file })
+    }
+
+    // Create an image of `num_blocks` zeroed blocks, or truncate the existing one.
+    pub fn create<P: AsRef<Path>>(path: P, num_blocks: usize) -> io::Result<Self> {
+        let file = OpenOptions::new().read(true).write(true).create(true).truncate(true).open(path)?;
+        file.set_len((num_blocks * BLOCK_SIZE) as u64)?;
+        Ok(Self { file })
+    }
+
+    pub fn num_blocks(&self) -> io::Result<usize> {
+        Ok(self.file.metadata()?.len() as usize / BLOCK_SIZE)
+    }
+}
+
+impl BlockDevice for FileBlockDevice {
+    fn write(&mut self, block_no: usize, buf: &[u8]) {
+        self.file.seek(SeekFrom::Start((block_no * BLOCK_SIZE) as u64))
+            .and_then(|_| self.file.write_all(&buf[..BLOCK_SIZE]))
+            .unwrap_or_else(|e| panic!("write block {}: {}", block_no, e));
+    }
+
+    fn read(&mut self, block_no: usize, buf: &mut [u8]) {
+        self.file.seek(SeekFrom::Start((block_no * BLOCK_SIZE) as u64))
+            .and_then(|_| self.file.read_exact(&mut buf[..BLOCK_SIZE]))
+            .unwrap_or_else(|e| panic!("read block {}: {}", block_no, e));
+    }
+}
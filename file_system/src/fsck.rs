@@ -0,0 +1,320 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fmt::{Display, Formatter};
+use std::mem::size_of;
+use std::ptr;
+use crate::block_device::BlockDevice;
+use crate::defines::{BIT_PER_BLOCK, BLOCK_SIZE, DirEntry, INODE_DEVICE, INODE_DIRECTORY, INODE_INVALID, INODE_MAX_SIZE, INODE_NUM_DIRECT, INODE_NUM_INDIRECT, INODE_PER_BLOCK, InodeEntry, LOG_MAX_SIZE, LogHeader, ROOT_INODE_NO, SuperBlock};
+
+pub enum Problem {
+    // The superblock describes an impossible layout. Nothing else is checked.
+    BadSuperBlock(&'static str),
+    // The log header is not empty, i.e. a committed transaction has not been installed.
+    UnreplayedLog { num_blocks: usize },
+    // The log header is garbage and cannot be replayed.
+    BadLog,
+    BadInodeType { inode_no: usize, typ: u16 },
+    SizeTooLarge { inode_no: usize, num_bytes: usize },
+    // An inode points to a block outside the data area.
+    BadBlock { inode_no: usize, block_no: usize },
+    // A block is used by more than one inode, or twice by the same one.
+    DuplicateBlock { inode_no: usize, block_no: usize, owner: usize },
+    // A directory entry refers to an inode which is out of range or not allocated.
+    DanglingEntry { dir: usize, name: String, inode_no: usize },
+    WrongLinkCount { inode_no: usize, found: usize, expected: usize },
+    // A block is marked as used in the bitmap but no one uses it.
+    LeakedBlock { block_no: usize },
+    // A block is used but marked as free in the bitmap.
+    UnmarkedBlock { block_no: usize },
+}
+
+impl Display for Problem {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Problem::BadSuperBlock(reason) => write!(f, "bad super block: {}", reason),
+            Problem::UnreplayedLog { num_blocks } => write!(f, "log has {} blocks not replayed", num_blocks),
+            Problem::BadLog => write!(f, "log header is corrupted"),
+            Problem::BadInodeType { inode_no, typ } => write!(f, "inode {}: bad type {}", inode_no, typ),
+            Problem::SizeTooLarge { inode_no, num_bytes } => write!(f, "inode {}: size {} is larger than {}", inode_no, num_bytes, INODE_MAX_SIZE),
+            Problem::BadBlock { inode_no, block_no } => write!(f, "inode {}: block {} is out of the data area", inode_no, block_no),
+            Problem::DuplicateBlock { inode_no, block_no, owner } => write!(f, "inode {}: block {} is already used by inode {}", inode_no, block_no, owner),
+            Problem::DanglingEntry { dir, name, inode_no } => write!(f, "directory {}: entry {} refers to free inode {}", dir, name, inode_no),
+            Problem::WrongLinkCount { inode_no, found, expected } => write!(f, "inode {}: num_links is {}, should be {}", inode_no, found, expected),
+            Problem::LeakedBlock { block_no } => write!(f, "block {} is marked used but not referenced", block_no),
+            Problem::UnmarkedBlock { block_no } => write!(f, "block {} is referenced but marked free", block_no),
+        }
+    }
+}
+
+type Buf = [u8; BLOCK_SIZE];
+
+struct Checker<'a> {
+    device: &'a mut dyn BlockDevice,
+    sblock: &'a SuperBlock,
+    repair: bool,
+    problems: Vec<Problem>,
+    inodes: Vec<InodeEntry>,
+    // block number -> the inode using it.
+    used: HashMap<usize, usize>,
+}
+
+impl<'a> Checker<'a> {
+    fn read(&mut self, block_no: usize) -> Buf {
+        let mut buf = [0; BLOCK_SIZE];
+        self.device.read(block_no, &mut buf);
+        buf
+    }
+
+    fn write(&mut self, block_no: usize, buf: &Buf) {
+        if self.repair {
+            self.device.write(block_no, buf);
+        }
+    }
+
+    fn data_start(&self) -> usize {
+        (self.sblock.num_blocks - self.sblock.num_data_blocks) as usize
+    }
+
+    fn check_super_block(&mut self) -> bool {
+        match self.sblock.check_layout() {
+            Ok(()) => true,
+            Err(reason) => {
+                self.problems.push(Problem::BadSuperBlock(reason));
+                false
+            }
+        }
+    }
+
+    fn check_log(&mut self) {
+        let log_start = self.sblock.log_start as usize;
+        let buf = self.read(log_start);
+        let mut header: LogHeader = unsafe { ptr::read_unaligned(buf.as_ptr() as *const LogHeader) };
+        if header.num_blocks == 0 {
+            return;
+        }
+        let capacity = (self.sblock.num_log_blocks as usize - 1).min(LOG_MAX_SIZE);
+        let valid = header.num_blocks <= capacity
+            && header.block_no[..header.num_blocks].iter().all(|&b| b < self.sblock.num_blocks as usize);
+        if valid {
+            self.problems.push(Problem::UnreplayedLog { num_blocks: header.num_blocks });
+            for i in 0..header.num_blocks {
+                let block = self.read(log_start + 1 + i);
+                self.write(header.block_no[i], &block);
+            }
+        } else {
+            self.problems.push(Problem::BadLog);
+        }
+        if self.repair {
+            header.num_blocks = 0;
+            let mut buf = [0; BLOCK_SIZE];
+            unsafe { ptr::copy_nonoverlapping(&header as *const _ as *const u8, buf.as_mut_ptr(), size_of::<LogHeader>()); }
+            self.write(log_start, &buf);
+        }
+    }
+
+    fn load_inodes(&mut self) {
+        for i in 0..self.sblock.num_inodes as usize {
+            let block = self.read(self.sblock.inode_start as usize + i / INODE_PER_BLOCK);
+            let offset = i % INODE_PER_BLOCK * size_of::<InodeEntry>();
+            self.inodes.push(unsafe { ptr::read_unaligned(block.as_ptr().add(offset) as *const InodeEntry) });
+        }
+    }
+
+    fn store_inodes(&mut self) {
+        for i in 0..self.sblock.num_inodes as usize {
+            let block_no = self.sblock.inode_start as usize + i / INODE_PER_BLOCK;
+            let mut block = self.read(block_no);
+            let offset = i % INODE_PER_BLOCK * size_of::<InodeEntry>();
+            unsafe { ptr::copy_nonoverlapping(&self.inodes[i] as *const _ as *const u8, block.as_mut_ptr().add(offset), size_of::<InodeEntry>()); }
+            self.write(block_no, &block);
+        }
+    }
+
+    // Check a block pointer of `inode_no`. Return false if the pointer should be cleared.
+    fn use_block(&mut self, inode_no: usize, block_no: usize) -> bool {
+        if block_no < self.data_start() || block_no >= self.sblock.num_blocks as usize {
+            self.problems.push(Problem::BadBlock { inode_no, block_no });
+            return false;
+        }
+        if let Some(&owner) = self.used.get(&block_no) {
+            self.problems.push(Problem::DuplicateBlock { inode_no, block_no, owner });
+            return false;
+        }
+        self.used.insert(block_no, inode_no);
+        true
+    }
+
+    fn check_inodes(&mut self) {
+        for i in 1..self.inodes.len() {
+            let typ = self.inodes[i].typ;
+            if typ == INODE_INVALID {
+                continue;
+            }
+            if typ > INODE_DEVICE {
+                self.problems.push(Problem::BadInodeType { inode_no: i, typ });
+                self.inodes[i].typ = INODE_INVALID;
+                continue;
+            }
+            let num_bytes = self.inodes[i].num_bytes as usize;
+            if num_bytes > INODE_MAX_SIZE {
+                self.problems.push(Problem::SizeTooLarge { inode_no: i, num_bytes });
+                self.inodes[i].num_bytes = INODE_MAX_SIZE as u32;
+            }
+            for j in 0..INODE_NUM_DIRECT {
+                let addr = self.inodes[i].addrs[j] as usize;
+                if addr != 0 && !self.use_block(i, addr) {
+                    self.inodes[i].addrs[j] = 0;
+                }
+            }
+            let indirect = self.inodes[i].indirect as usize;
+            if indirect == 0 {
+                continue;
+            }
+            if !self.use_block(i, indirect) {
+                self.inodes[i].indirect = 0;
+                continue;
+            }
+            let mut block = self.read(indirect);
+            let mut modified = false;
+            for j in 0..INODE_NUM_INDIRECT {
+                let addr = u32::from_ne_bytes(block[j * 4..j * 4 + 4].try_into().unwrap()) as usize;
+                if addr != 0 && !self.use_block(i, addr) {
+                    block[j * 4..j * 4 + 4].fill(0);
+                    modified = true;
+                }
+            }
+            if modified {
+                self.write(indirect, &block);
+            }
+        }
+    }
+
+    // The blocks of an inode in file order, up to its size. Missing blocks are 0.
+    fn blocks_of(&mut self, inode_no: usize) -> Vec<usize> {
+        let num_blocks = (self.inodes[inode_no].num_bytes as usize + BLOCK_SIZE - 1) / BLOCK_SIZE;
+        let mut blocks: Vec<usize> = self.inodes[inode_no].addrs.iter().map(|&b| b as usize).collect();
+        if num_blocks > INODE_NUM_DIRECT && self.inodes[inode_no].indirect != 0 {
+            let indirect = self.read(self.inodes[inode_no].indirect as usize);
+            blocks.extend((0..INODE_NUM_INDIRECT).map(|j| u32::from_ne_bytes(indirect[j * 4..j * 4 + 4].try_into().unwrap()) as usize));
+        }
+        blocks.resize(num_blocks, 0);
+        blocks
+    }
+
+    // Walk all the directories reachable from root, return the number of links to each inode.
+    fn check_directories(&mut self) -> Vec<usize> {
+        let mut links = vec![0; self.inodes.len()];
+        links[ROOT_INODE_NO] = 1;
+        if self.inodes[ROOT_INODE_NO].typ != INODE_DIRECTORY {
+            return links;
+        }
+        let mut visited = HashSet::from([ROOT_INODE_NO]);
+        let mut queue = VecDeque::from([ROOT_INODE_NO]);
+        while let Some(dir) = queue.pop_front() {
+            let num_entries = self.inodes[dir].num_bytes as usize / size_of::<DirEntry>();
+            let blocks = self.blocks_of(dir);
+            for (k, &block_no) in blocks.iter().enumerate() {
+                if block_no == 0 {
+                    continue;
+                }
+                let mut block = self.read(block_no);
+                let mut modified = false;
+                let per_block = BLOCK_SIZE / size_of::<DirEntry>();
+                for j in 0..per_block.min(num_entries.saturating_sub(k * per_block)) {
+                    let offset = j * size_of::<DirEntry>();
+                    let entry: DirEntry = unsafe { ptr::read_unaligned(block.as_ptr().add(offset) as *const DirEntry) };
+                    let inode_no = entry.inode_no as usize;
+                    if inode_no == 0 {
+                        continue;
+                    }
+                    let name = String::from_utf8_lossy(entry.name()).into_owned();
+                    if inode_no >= self.inodes.len() || self.inodes[inode_no].typ == INODE_INVALID {
+                        self.problems.push(Problem::DanglingEntry { dir, name, inode_no });
+                        block[offset..offset + size_of::<DirEntry>()].fill(0);
+                        modified = true;
+                        continue;
+                    }
+                    if name == "." || name == ".." {
+                        continue;
+                    }
+                    links[inode_no] += 1;
+                    if self.inodes[inode_no].typ == INODE_DIRECTORY && visited.insert(inode_no) {
+                        queue.push_back(inode_no);
+                    }
+                }
+                if modified {
+                    self.write(block_no, &block);
+                }
+            }
+        }
+        links
+    }
+
+    fn check_links(&mut self, links: &[usize]) {
+        for i in 1..self.inodes.len() {
+            let found = self.inodes[i].num_links as usize;
+            if self.inodes[i].typ == INODE_INVALID || found == links[i] {
+                continue;
+            }
+            self.problems.push(Problem::WrongLinkCount { inode_no: i, found, expected: links[i] });
+            self.inodes[i].num_links = links[i] as u16;
+            if links[i] == 0 {
+                // Not reachable from root, free it together with its blocks.
+                self.inodes[i].typ = INODE_INVALID;
+                self.used.retain(|_, owner| *owner != i);
+            }
+        }
+    }
+
+    fn check_bitmap(&mut self) {
+        let num_blocks = self.sblock.num_blocks as usize;
+        let data_start = self.data_start();
+        for i in 0..(num_blocks + BIT_PER_BLOCK - 1) / BIT_PER_BLOCK {
+            let block_no = self.sblock.bitmap_start as usize + i;
+            let mut bitmap = self.read(block_no);
+            let mut modified = false;
+            for j in i * BIT_PER_BLOCK..num_blocks.min((i + 1) * BIT_PER_BLOCK) {
+                let k = j % BIT_PER_BLOCK;
+                let marked = bitmap[k / 8] & (1 << (k % 8)) != 0;
+                let used = j < data_start || self.used.contains_key(&j);
+                if marked == used {
+                    continue;
+                }
+                self.problems.push(if marked {
+                    Problem::LeakedBlock { block_no: j }
+                } else {
+                    Problem::UnmarkedBlock { block_no: j }
+                });
+                bitmap[k / 8] ^= 1 << (k % 8);
+                modified = true;
+            }
+            if modified {
+                self.write(block_no, &bitmap);
+            }
+        }
+    }
+}
+
+// Check the filesystem on `device` described by `sblock`, and return all the problems found.
+// If `repair` is set, also fix them on disk. The device must not be in use by the block cache.
+pub fn fsck(device: &mut dyn BlockDevice, sblock: &SuperBlock, repair: bool) -> Vec<Problem> {
+    let mut checker = Checker {
+        device,
+        sblock,
+        repair,
+        problems: Vec::new(),
+        inodes: Vec::new(),
+        used: HashMap::new(),
+    };
+    if !checker.check_super_block() {
+        return checker.problems;
+    }
+    // Replay the log first, since the rest of the filesystem may depend on it.
+    checker.check_log();
+    checker.load_inodes();
+    checker.check_inodes();
+    let links = checker.check_directories();
+    checker.check_links(&links);
+    checker.check_bitmap();
+    checker.store_inodes();
+    checker.problems
+}
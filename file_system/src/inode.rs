@@ -0,0 +1,428 @@
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use core::cmp::min;
+use core::mem::{MaybeUninit, size_of};
+use core::{ptr, slice};
+use crate::cache::{Block, BlockCache, OpContext};
+use crate::defines::{BLOCK_SIZE, DirEntry, FILE_NAME_MAX_LENGTH, INODE_DIRECTORY, INODE_INVALID, INODE_MAX_BLOCKS, INODE_MAX_SIZE, INODE_NUM_DIRECT, INODE_PER_BLOCK, InodeEntry, SuperBlock};
+use crate::sync::{Mutex, RawMutex, RawMutexTrait};
+
+pub struct Inode {
+    pub inode_no: usize,
+    // number of `get`s and `share`s which have not been `put` yet, protected by the table lock.
+    // An inode is removed from the table when it drops to zero.
+    ref_count: usize,
+    // sleep lock of the inode, held between `lock` and `unlock`.
+    lock: RawMutex,
+    // whether `entry` has been loaded from disk.
+    pub valid: bool,
+    pub entry: InodeEntry,
+}
+
+impl Inode {
+    fn new(inode_no: usize) -> Self {
+        Self {
+            inode_no,
+            ref_count: 0,
+            lock: RawMutex::INIT,
+            valid: false,
+            entry: unsafe { MaybeUninit::zeroed().assume_init() },
+        }
+    }
+}
+
+pub trait InodeTree {
+    // Allocate a free on-disk inode of type `typ` and return its number.
+    fn alloc(&mut self, ctx: *mut OpContext, typ: u16) -> usize;
+
+    // Get the in-memory inode of `inode_no`. The inode is neither locked nor loaded from disk.
+    fn get(&mut self, inode_no: usize) -> *mut Inode;
+
+    // Add one more reference to an inode already got.
+    fn share(&mut self, inode: *mut Inode) -> *mut Inode;
+
+    // Drop a reference. The last reference to an inode without any link frees it on disk.
+    fn put(&mut self, ctx: *mut OpContext, inode: *mut Inode);
+
+    fn lock(&mut self, inode: *mut Inode);
+
+    fn unlock(&mut self, inode: *mut Inode);
+
+    // Write `entry` of a locked inode back to disk.
+    fn sync(&mut self, ctx: *mut OpContext, inode: *mut Inode);
+
+    // Free all data blocks of a locked inode and set its size to zero.
+    fn truncate(&mut self, ctx: *mut OpContext, inode: *mut Inode);
+
+    // Read from a locked inode at `offset`, return the number of bytes read.
+    fn read(&mut self, inode: *mut Inode, dest: &mut [u8], offset: usize) -> usize;
+
+    // Write to a locked inode at `offset`, return the number of bytes written.
+    // It writes less than asked, maybe nothing, if `offset` is past the end of file, the file would grow
+    // beyond `INODE_MAX_SIZE`, or the operation `ctx` cannot log any more blocks.
+    fn write(&mut self, ctx: *mut OpContext, inode: *mut Inode, src: &[u8], offset: usize) -> usize;
+
+    // Read the `index`-th entry of a locked directory, or `None` past the end.
+    // Empty slots are returned as entries with `inode_no == 0`.
+    fn read_dir(&mut self, inode: *mut Inode, index: usize) -> Option<DirEntry>;
+
+    // Look up `name` in a locked directory, return the inode number and the index of the entry.
+    fn lookup(&mut self, inode: *mut Inode, name: &str) -> Option<(usize, usize)>;
+
+    // Insert an entry into a locked directory, return its index, or `None` if `name` already exists,
+    // is longer than `FILE_NAME_MAX_LENGTH`, or the entry cannot be written in this operation.
+    fn insert(&mut self, ctx: *mut OpContext, inode: *mut Inode, name: &str, inode_no: usize) -> Option<usize>;
+
+    // Remove the `index`-th entry from a locked directory, return false if it cannot be written in this operation.
+    fn remove(&mut self, ctx: *mut OpContext, inode: *mut Inode, index: usize) -> bool;
+}
+
+struct InodeTable {
+    inodes: Vec<*mut Inode>,
+}
+
+unsafe impl Send for InodeTable {}
+
+impl InodeTable {
+    const fn new() -> Self {
+        Self {
+            inodes: Vec::new(),
+        }
+    }
+
+    fn find(&self, inode_no: usize) -> Option<usize> {
+        self.inodes.iter().position(|i| unsafe { (**i).inode_no } == inode_no)
+    }
+
+    fn clear(&mut self) {
+        for inode in self.inodes.drain(..) {
+            unsafe { drop(Box::from_raw(inode)); }
+        }
+    }
+}
+
+static TABLE: Mutex<InodeTable> = Mutex::new(InodeTable::new());
+
+static mut SBLOCK: MaybeUninit<SuperBlock> = MaybeUninit::uninit();
+static mut CACHE: MaybeUninit<*mut dyn BlockCache> = MaybeUninit::uninit();
+
+fn sblock() -> &'static SuperBlock {
+    unsafe { SBLOCK.assume_init_ref() }
+}
+
+fn cache() -> &'static mut dyn BlockCache {
+    unsafe { &mut **CACHE.assume_init_ref() }
+}
+
+// The on-disk location of an inode: the block holding it and the byte offset inside the block.
+fn locate(inode_no: usize) -> (usize, usize) {
+    (sblock().inode_start as usize + inode_no / INODE_PER_BLOCK,
+     inode_no % INODE_PER_BLOCK * size_of::<InodeEntry>())
+}
+
+// Block data has no alignment guarantee, so entries are always copied in and out.
+fn read_entry(block: *mut Block, offset: usize) -> InodeEntry {
+    unsafe { ptr::read_unaligned((*block).data.as_ptr().add(offset) as *const InodeEntry) }
+}
+
+fn write_entry(block: *mut Block, offset: usize, entry: &InodeEntry) {
+    unsafe { ptr::copy_nonoverlapping(entry as *const _ as *const u8, (*block).data.as_mut_ptr().add(offset), size_of::<InodeEntry>()); }
+}
+
+fn read_addr(block: *mut Block, index: usize) -> u32 {
+    let data = unsafe { &(*block).data };
+    u32::from_ne_bytes(data[index * 4..index * 4 + 4].try_into().unwrap())
+}
+
+fn write_addr(block: *mut Block, index: usize, addr: u32) {
+    let data = unsafe { &mut (*block).data };
+    data[index * 4..index * 4 + 4].copy_from_slice(&addr.to_ne_bytes());
+}
+
+pub struct InodeTreeImpl;
+
+impl InodeTreeImpl {
+    // Return the block number of the `index`-th block of the inode, or 0 if it has not been allocated.
+    fn lookup_block(&self, entry: &InodeEntry, index: usize) -> usize {
+        if index < INODE_NUM_DIRECT {
+            return entry.addrs[index] as usize;
+        }
+        if entry.indirect == 0 {
+            return 0;
+        }
+        let indirect = cache().acquire(entry.indirect as usize);
+        let addr = read_addr(indirect, index - INODE_NUM_DIRECT);
+        cache().release(indirect);
+        addr as usize
+    }
+
+    // The most blocks writing the `index`-th block of `entry` may put into the log: the block itself,
+    // plus a bitmap block and the indirect block if it has to be allocated.
+    fn write_cost(&self, entry: &InodeEntry, index: usize) -> usize {
+        if self.lookup_block(entry, index) != 0 {
+            return 1;
+        }
+        if index < INODE_NUM_DIRECT {
+            2
+        } else if entry.indirect == 0 {
+            4
+        } else {
+            3
+        }
+    }
+
+    // Like `lookup_block`, but allocate the block (and the indirect block) if it does not exist yet.
+    // `modified` is set if `entry` is changed and should be synced.
+    fn map_block(&self, ctx: *mut OpContext, entry: &mut InodeEntry, index: usize, modified: &mut bool) -> usize {
+        assert!(index < INODE_MAX_BLOCKS, "map_block: block index {} out of range", index);
+        if index < INODE_NUM_DIRECT {
+            if entry.addrs[index] == 0 {
+                entry.addrs[index] = cache().alloc(ctx) as u32;
+                *modified = true;
+            }
+            return entry.addrs[index] as usize;
+        }
+        if entry.indirect == 0 {
+            entry.indirect = cache().alloc(ctx) as u32;
+            *modified = true;
+        }
+        let indirect = cache().acquire(entry.indirect as usize);
+        let mut addr = read_addr(indirect, index - INODE_NUM_DIRECT);
+        if addr == 0 {
+            addr = cache().alloc(ctx) as u32;
+            write_addr(indirect, index - INODE_NUM_DIRECT, addr);
+            cache().sync(ctx, indirect);
+        }
+        cache().release(indirect);
+        addr as usize
+    }
+}
+
+impl InodeTree for InodeTreeImpl {
+    fn alloc(&mut self, ctx: *mut OpContext, typ: u16) -> usize {
+        assert_ne!(typ, INODE_INVALID, "alloc: invalid inode type");
+        // Inode 0 is never used, so that a zero `inode_no` can mean "no inode".
+        for inode_no in 1..sblock().num_inodes as usize {
+            let (block_no, offset) = locate(inode_no);
+            let block = cache().acquire(block_no);
+            let mut entry = read_entry(block, offset);
+            if entry.typ == INODE_INVALID {
+                entry = unsafe { MaybeUninit::zeroed().assume_init() };
+                entry.typ = typ;
+                write_entry(block, offset, &entry);
+                cache().sync(ctx, block);
+                cache().release(block);
+                return inode_no;
+            }
+            cache().release(block);
+        }
+        panic!("alloc: no free inode");
+    }
+
+    fn get(&mut self, inode_no: usize) -> *mut Inode {
+        assert!(inode_no > 0 && inode_no < sblock().num_inodes as usize, "get: invalid inode number {}", inode_no);
+        let mut table = TABLE.lock();
+        let inode = match table.find(inode_no) {
+            Some(i) => table.inodes[i],
+            None => {
+                let inode = Box::into_raw(Box::new(Inode::new(inode_no)));
+                table.inodes.push(inode);
+                inode
+            }
+        };
+        unsafe { (*inode).ref_count += 1; }
+        inode
+    }
+
+    fn share(&mut self, inode: *mut Inode) -> *mut Inode {
+        let _table = TABLE.lock();
+        unsafe { (*inode).ref_count += 1; }
+        inode
+    }
+
+    fn put(&mut self, ctx: *mut OpContext, inode: *mut Inode) {
+        let mut table = TABLE.lock();
+        let inode_ref = unsafe { &mut *inode };
+        if inode_ref.ref_count == 1 && inode_ref.valid && inode_ref.entry.num_links == 0 {
+            // No one else can get this inode since it is not linked anywhere,
+            // so it is safe to free it without the table lock.
+            drop(table);
+            self.lock(inode);
+            self.truncate(ctx, inode);
+            inode_ref.entry.typ = INODE_INVALID;
+            self.sync(ctx, inode);
+            inode_ref.valid = false;
+            self.unlock(inode);
+            table = TABLE.lock();
+        }
+        inode_ref.ref_count -= 1;
+        if inode_ref.ref_count == 0 {
+            let i = table.find(inode_ref.inode_no).unwrap();
+            table.inodes.remove(i);
+            unsafe { drop(Box::from_raw(inode)); }
+        }
+    }
+
+    fn lock(&mut self, inode: *mut Inode) {
+        let inode = unsafe { &mut *inode };
+        inode.lock.lock();
+        if !inode.valid {
+            let (block_no, offset) = locate(inode.inode_no);
+            let block = cache().acquire(block_no);
+            inode.entry = read_entry(block, offset);
+            cache().release(block);
+            inode.valid = true;
+            assert_ne!(inode.entry.typ, INODE_INVALID, "lock: inode {} is not allocated", inode.inode_no);
+        }
+    }
+
+    fn unlock(&mut self, inode: *mut Inode) {
+        unsafe { (*inode).lock.unlock(); }
+    }
+
+    fn sync(&mut self, ctx: *mut OpContext, inode: *mut Inode) {
+        let inode = unsafe { &*inode };
+        let (block_no, offset) = locate(inode.inode_no);
+        let block = cache().acquire(block_no);
+        write_entry(block, offset, &inode.entry);
+        cache().sync(ctx, block);
+        cache().release(block);
+    }
+
+    fn truncate(&mut self, ctx: *mut OpContext, inode: *mut Inode) {
+        let entry = unsafe { &mut (*inode).entry };
+        for i in 0..INODE_NUM_DIRECT {
+            if entry.addrs[i] != 0 {
+                cache().free(ctx, entry.addrs[i] as usize);
+                entry.addrs[i] = 0;
+            }
+        }
+        if entry.indirect != 0 {
+            let indirect = cache().acquire(entry.indirect as usize);
+            for i in 0..INODE_MAX_BLOCKS - INODE_NUM_DIRECT {
+                let addr = read_addr(indirect, i);
+                if addr != 0 {
+                    cache().free(ctx, addr as usize);
+                }
+            }
+            cache().release(indirect);
+            cache().free(ctx, entry.indirect as usize);
+            entry.indirect = 0;
+        }
+        entry.num_bytes = 0;
+        self.sync(ctx, inode);
+    }
+
+    fn read(&mut self, inode: *mut Inode, dest: &mut [u8], offset: usize) -> usize {
+        let entry = unsafe { &(*inode).entry };
+        let size = entry.num_bytes as usize;
+        if offset >= size {
+            return 0;
+        }
+        let end = min(size, offset + dest.len());
+        let mut pos = offset;
+        while pos < end {
+            let block_no = self.lookup_block(entry, pos / BLOCK_SIZE);
+            assert_ne!(block_no, 0, "read: block {} of inode {} is missing", pos / BLOCK_SIZE, unsafe { (*inode).inode_no });
+            let start = pos % BLOCK_SIZE;
+            let n = min(end - pos, BLOCK_SIZE - start);
+            let block = cache().acquire(block_no);
+            let data = unsafe { &(*block).data };
+            dest[pos - offset..pos - offset + n].copy_from_slice(&data[start..start + n]);
+            cache().release(block);
+            pos += n;
+        }
+        end - offset
+    }
+
+    fn write(&mut self, ctx: *mut OpContext, inode: *mut Inode, src: &[u8], offset: usize) -> usize {
+        let entry = unsafe { &mut (*inode).entry };
+        if offset > entry.num_bytes as usize || offset >= INODE_MAX_SIZE {
+            return 0;
+        }
+        let end = min(offset + src.len(), INODE_MAX_SIZE);
+        let mut modified = false;
+        let mut pos = offset;
+        while pos < end {
+            let index = pos / BLOCK_SIZE;
+            // Stop early rather than overflow the log, keeping one block for the inode itself.
+            if !ctx.is_null() && unsafe { (*ctx).rm } < self.write_cost(entry, index) + 1 {
+                break;
+            }
+            let block_no = self.map_block(ctx, entry, index, &mut modified);
+            let start = pos % BLOCK_SIZE;
+            let n = min(end - pos, BLOCK_SIZE - start);
+            let block = cache().acquire(block_no);
+            let data = unsafe { &mut (*block).data };
+            data[start..start + n].copy_from_slice(&src[pos - offset..pos - offset + n]);
+            cache().sync(ctx, block);
+            cache().release(block);
+            pos += n;
+        }
+        if pos > entry.num_bytes as usize {
+            entry.num_bytes = pos as u32;
+            modified = true;
+        }
+        if modified {
+            self.sync(ctx, inode);
+        }
+        pos - offset
+    }
+
+    fn read_dir(&mut self, inode: *mut Inode, index: usize) -> Option<DirEntry> {
+        assert_eq!(unsafe { (*inode).entry.typ }, INODE_DIRECTORY, "read_dir: not a directory");
+        let mut buf = [0u8; size_of::<DirEntry>()];
+        if self.read(inode, &mut buf, index * size_of::<DirEntry>()) < buf.len() {
+            return None;
+        }
+        Some(unsafe { ptr::read_unaligned(buf.as_ptr() as *const DirEntry) })
+    }
+
+    fn lookup(&mut self, inode: *mut Inode, name: &str) -> Option<(usize, usize)> {
+        let mut index = 0;
+        while let Some(entry) = self.read_dir(inode, index) {
+            if entry.inode_no != 0 && entry.name() == name.as_bytes() {
+                return Some((entry.inode_no as usize, index));
+            }
+            index += 1;
+        }
+        None
+    }
+
+    fn insert(&mut self, ctx: *mut OpContext, inode: *mut Inode, name: &str, inode_no: usize) -> Option<usize> {
+        if name.len() > FILE_NAME_MAX_LENGTH || self.lookup(inode, name).is_some() {
+            return None;
+        }
+        // Reuse the first empty slot, or append to the end.
+        let mut index = 0;
+        while let Some(entry) = self.read_dir(inode, index) {
+            if entry.inode_no == 0 {
+                break;
+            }
+            index += 1;
+        }
+        let entry = DirEntry::new(inode_no, name);
+        let buf = unsafe { slice::from_raw_parts(&entry as *const _ as *const u8, size_of::<DirEntry>()) };
+        // An entry never crosses a block, so it is either written entirely or not at all.
+        if self.write(ctx, inode, buf, index * size_of::<DirEntry>()) < buf.len() {
+            return None;
+        }
+        Some(index)
+    }
+
+    fn remove(&mut self, ctx: *mut OpContext, inode: *mut Inode, index: usize) -> bool {
+        let buf = [0u8; size_of::<DirEntry>()];
+        assert!(self.read_dir(inode, index).is_some(), "remove: entry {} does not exist", index);
+        self.write(ctx, inode, &buf, index * size_of::<DirEntry>()) == buf.len()
+    }
+}
+
+pub fn init_inodes(sblock: &SuperBlock, cache: &'static mut dyn BlockCache) {
+    unsafe {
+        SBLOCK = MaybeUninit::new(sblock.clone());
+        CACHE = MaybeUninit::new(cache);
+    }
+    TABLE.lock().clear();
+}
+
+pub static mut INODES: InodeTreeImpl = InodeTreeImpl;
@@ -1,11 +1,22 @@
 #![feature(map_try_insert)]
 #![feature(maybe_uninit_uninit_array)]
 #![feature(pointer_byte_offsets)]
+#![cfg_attr(not(any(test, feature = "std_mock")), no_std)]
 
+extern crate alloc;
+
+#[cfg(test)]
 mod tests;
-mod defines;
-mod cache;
-mod block_device;
+pub mod defines;
+pub mod sync;
+pub mod cache;
+pub mod block_device;
+pub mod inode;
+pub mod path;
+#[cfg(any(test, feature = "std_mock"))]
+pub mod file_device;
+#[cfg(any(test, feature = "std_mock"))]
+pub mod fsck;
 
 pub trait Container<T> {
     fn get_child_ptr(&mut self) -> *mut T {
@@ -0,0 +1,78 @@
+use core::cmp::min;
+use crate::cache::OpContext;
+use crate::defines::{FILE_NAME_MAX_LENGTH, INODE_DIRECTORY, ROOT_INODE_NO};
+use crate::inode::{Inode, InodeTree, INODES};
+
+// Split the first element off `path`, return it and the rest of the path.
+// Like xv6, elements longer than `FILE_NAME_MAX_LENGTH` are truncated.
+//
+// skip_elem("a/bb/c") = Some(("a", "/bb/c"))
+// skip_elem("///a//bb") = Some(("a", "//bb"))
+// skip_elem("a") = Some(("a", ""))
+// skip_elem("") = skip_elem("////") = None
+fn skip_elem(path: &str) -> Option<(&str, &str)> {
+    let path = path.trim_start_matches('/');
+    if path.is_empty() {
+        return None;
+    }
+    let end = path.find('/').unwrap_or(path.len());
+    let mut len = min(end, FILE_NAME_MAX_LENGTH);
+    while !path.is_char_boundary(len) {
+        len -= 1;
+    }
+    Some((&path[..len], &path[end..]))
+}
+
+// Walk `path` and return the inode it names. If `parent` is set, stop one level early and
+// return the parent directory together with the last element instead.
+// The returned inode is referenced but not locked.
+unsafe fn namex<'a>(path: &'a str, cwd: *mut Inode, parent: bool, ctx: *mut OpContext) -> Option<(*mut Inode, &'a str)> {
+    let mut inode = if path.starts_with('/') || cwd.is_null() {
+        INODES.get(ROOT_INODE_NO)
+    } else {
+        INODES.share(cwd)
+    };
+    let mut path = path;
+    while let Some((name, rest)) = skip_elem(path) {
+        path = rest;
+        INODES.lock(inode);
+        if (*inode).entry.typ != INODE_DIRECTORY {
+            INODES.unlock(inode);
+            INODES.put(ctx, inode);
+            return None;
+        }
+        if parent && skip_elem(path).is_none() {
+            INODES.unlock(inode);
+            return Some((inode, name));
+        }
+        let next = match INODES.lookup(inode, name) {
+            Some((inode_no, _)) => INODES.get(inode_no),
+            // A directory without `.` and `..` entries still behaves as expected. The parent of root is itself.
+            None if name == "." || (name == ".." && (*inode).inode_no == ROOT_INODE_NO) => INODES.share(inode),
+            None => {
+                INODES.unlock(inode);
+                INODES.put(ctx, inode);
+                return None;
+            }
+        };
+        INODES.unlock(inode);
+        INODES.put(ctx, inode);
+        inode = next;
+    }
+    if parent {
+        // The path does not have a last element, e.g. "/".
+        INODES.put(ctx, inode);
+        return None;
+    }
+    Some((inode, ""))
+}
+
+// Look up the inode of `path`. A relative path starts from `cwd`, or root if `cwd` is null.
+pub fn namei(path: &str, cwd: *mut Inode, ctx: *mut OpContext) -> Option<*mut Inode> {
+    unsafe { namex(path, cwd, false, ctx).map(|(inode, _)| inode) }
+}
+
+// Look up the parent directory of `path`, and return it with the last element of `path`.
+pub fn nameiparent<'a>(path: &'a str, cwd: *mut Inode, ctx: *mut OpContext) -> Option<(*mut Inode, &'a str)> {
+    unsafe { namex(path, cwd, true, ctx) }
+}
@@ -0,0 +1,72 @@
+// Locks used by the filesystem.
+//
+// On host (tests and tools), they are the ones from `parking_lot`. In the kernel, they are spinlocks
+// which give up the CPU through the hook set by `set_yield_hook` while waiting.
+#[cfg(any(test, feature = "std_mock"))]
+pub use parking_lot::{Condvar, Mutex, RawMutex};
+#[cfg(not(any(test, feature = "std_mock")))]
+pub use self::kernel::{Condvar, Mutex, RawMutex, set_yield_hook};
+pub use lock_api::RawMutex as RawMutexTrait;
+
+#[cfg(not(any(test, feature = "std_mock")))]
+mod kernel {
+    use core::hint::spin_loop;
+    use core::sync::atomic::{AtomicBool, Ordering};
+    use lock_api::{GuardSend, MutexGuard};
+
+    static mut YIELD_HOOK: fn() = spin_loop;
+
+    // Set the function called repeatedly while waiting for a lock or a condition, e.g. `yield_` of the scheduler.
+    pub fn set_yield_hook(hook: fn()) {
+        unsafe { YIELD_HOOK = hook; }
+    }
+
+    fn relax() {
+        unsafe { YIELD_HOOK() }
+    }
+
+    pub struct RawMutex {
+        locked: AtomicBool,
+    }
+
+    unsafe impl lock_api::RawMutex for RawMutex {
+        const INIT: Self = Self { locked: AtomicBool::new(false) };
+        type GuardMarker = GuardSend;
+
+        fn lock(&self) {
+            while !self.try_lock() {
+                relax();
+            }
+        }
+
+        fn try_lock(&self) -> bool {
+            self.locked.compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed).is_ok()
+        }
+
+        unsafe fn unlock(&self) {
+            self.locked.store(false, Ordering::Release);
+        }
+    }
+
+    pub type Mutex<T> = lock_api::Mutex<RawMutex, T>;
+
+    // A condition variable which simply re-checks the condition after each `relax`,
+    // so notifying is a no-op.
+    pub struct Condvar;
+
+    impl Condvar {
+        pub const fn new() -> Self {
+            Self
+        }
+
+        pub fn wait_while<T, F: FnMut(&mut T) -> bool>(&self, guard: &mut MutexGuard<'_, RawMutex, T>, mut condition: F) {
+            while condition(&mut **guard) {
+                MutexGuard::unlocked(guard, relax);
+            }
+        }
+
+        pub fn notify_all(&self) -> usize {
+            0
+        }
+    }
+}
@@ -122,10 +122,12 @@ impl MockBlockDevice {
     }
 
     pub fn check_offline(offline: &AtomicBool) {
-        assert!(offline.load(SeqCst));
+        assert!(!offline.load(SeqCst), "the block device is offline");
     }
 }
 
+// Aligned so that `inspect_log_header` can view the data as a `LogHeader`.
+#[repr(C, align(8))]
 pub struct Block {
     pub data: [u8; BLOCK_SIZE],
     mutex: Mutex<()>,
@@ -168,6 +170,7 @@ pub unsafe fn initialize_mock(log_size: usize, num_data_blocks: usize, image_pat
         + 1
         + ((num_data_blocks + BIT_PER_BLOCK - 1) / BIT_PER_BLOCK)
         + num_data_blocks) as u32;
+    mock = MaybeUninit::new(MockBlockDevice::new(sblock_.num_blocks as usize));
     mock.assume_init_mut().init(sblock_);
     if !image_path.is_empty() {
         // todo load image
@@ -176,5 +179,13 @@ pub unsafe fn initialize_mock(log_size: usize, num_data_blocks: usize, image_pat
 
 pub unsafe fn initialize(log_size: usize, num_data_blocks: usize, image_path: &str) {
     initialize_mock(log_size, num_data_blocks, image_path);
-    init_bcache(sblock.assume_init_ref(), mock.assume_init_ref());
+    init_bcache(sblock.assume_init_ref(), mock.assume_init_mut());
+}
+
+// All the tests share the global caches and the mock device, so they must not run concurrently.
+static TEST_LOCK: Mutex<()> = Mutex::new(());
+
+pub fn run(test: unsafe fn()) {
+    let _lock = TEST_LOCK.lock();
+    unsafe { test(); }
 }
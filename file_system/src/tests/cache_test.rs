@@ -1,13 +1,14 @@
 use std::{ptr, thread};
+use std::time::Duration;
 use std::sync::atomic::Ordering::SeqCst;
 use prng_mt::MT19937;
-use crate::cache::{Block, BlockCache, EVICTION_THRESHOLD, OP_MAX_NUM_BLOCKS, OpContext, SCACHE};
+use crate::cache::{Block, BlockCache, EVICTION_THRESHOLD, init_bcache, OP_MAX_NUM_BLOCKS, OpContext, SCACHE};
 
 use crate::defines::{SuperBlock, BLOCK_SIZE};
 use crate::tests::block_device::initialize_mock;
 
 use super::{
-    block_device::{initialize, mock, sblock, MockBlockDevice},
+    block_device::{initialize, mock, run, sblock, MockBlockDevice},
 };
 
 pub unsafe fn mock_() -> &'static mut MockBlockDevice {
@@ -166,10 +167,10 @@ unsafe fn test_atomic_op() {
     let v1 = *d1.add(500);
     let v2 = *d2.add(10);
     assert_eq!((*b1).data[500], v1);
-    assert_eq!((*b1).data[10], v2);
+    assert_eq!((*b2).data[10], v2);
 
     (*b1).data[500] = !v1;
-    (*b1).data[10] = !v2;
+    (*b2).data[10] = !v2;
     SCACHE.sync(&mut ctx, b1);
     SCACHE.release(b1);
     SCACHE.sync(&mut ctx, b2);
@@ -274,6 +275,46 @@ unsafe fn test_global_absorption() {
     }
 }
 
+unsafe fn test_concurrent_ops() {
+    let num_workers = 3;
+    let num_rounds = 10;
+
+    // Two full operations fit in the log, but not three.
+    initialize(2 * OP_MAX_NUM_BLOCKS, 100, "");
+    let t = (sblock_().num_blocks - 1) as usize;
+
+    let mut workers = Vec::new();
+    for i in 0..num_workers {
+        workers.push(thread::spawn(move || {
+            for round in 0..num_rounds {
+                let mut ctx = OpContext::new();
+                SCACHE.begin_op(&mut ctx);
+                // Let the others begin their operations before we fill our share of the log.
+                thread::sleep(Duration::from_millis(1));
+                for j in 0..OP_MAX_NUM_BLOCKS {
+                    let b = SCACHE.acquire(t - i * OP_MAX_NUM_BLOCKS - j);
+                    (*b).data[0] = round as u8;
+                    SCACHE.sync(&mut ctx, b);
+                    SCACHE.release(b);
+                }
+                SCACHE.end_op(&mut ctx);
+            }
+        }));
+    }
+    // A worker overflowing the log panics with its blocks acquired, and may leave the others waiting forever.
+    // So we fail as soon as any of them does, instead of joining them in order.
+    while !workers.is_empty() {
+        if let Some(i) = workers.iter().position(|worker| worker.is_finished()) {
+            workers.swap_remove(i).join().unwrap();
+        }
+        thread::yield_now();
+    }
+    for i in 0..num_workers * OP_MAX_NUM_BLOCKS {
+        let b = mock_().inspect(t - i);
+        assert_eq!(*b.add(0), (num_rounds - 1) as u8);
+    }
+}
+
 unsafe fn test_replay() {
     initialize_mock(50, 1000, "");
 
@@ -287,4 +328,65 @@ unsafe fn test_replay() {
             *b.add(j) = (v & 0xff) as u8;
         }
     }
+
+    init_bcache(sblock_(), mock_());
+
+    assert_eq!((*header).num_blocks, 0);
+    for i in 0..5 {
+        let v = 500 + i;
+        let b = mock_().inspect(v);
+        for j in 0..BLOCK_SIZE {
+            assert_eq!(*b.add(j), (v & 0xff) as u8);
+        }
+    }
+}
+
+#[test]
+fn init() {
+    run(test_init);
+}
+
+#[test]
+fn read_write() {
+    run(test_read_write);
+}
+
+#[test]
+fn loop_read() {
+    run(test_loop_read);
+}
+
+#[test]
+fn reuse() {
+    run(test_reuse);
+}
+
+#[test]
+fn lru() {
+    run(test_lru);
+}
+
+#[test]
+fn atomic_op() {
+    run(test_atomic_op);
+}
+
+#[test]
+fn local_absorption() {
+    run(test_local_absorption);
+}
+
+#[test]
+fn global_absorption() {
+    run(test_global_absorption);
+}
+
+#[test]
+fn concurrent_ops() {
+    run(test_concurrent_ops);
+}
+
+#[test]
+fn replay() {
+    run(test_replay);
 }
\ No newline at end of file
@@ -0,0 +1,141 @@
+use std::ptr;
+use crate::cache::{BlockCache, OpContext, SCACHE};
+use crate::defines::{INODE_DIRECTORY, INODE_REGULAR, ROOT_INODE_NO};
+use crate::inode::{INODES, InodeTree};
+use crate::path::{namei, nameiparent};
+use crate::tests::inode_test::initialize_inodes;
+
+use super::block_device::run;
+
+// Create an inode of `typ` named `name` under `parent`, return its inode number.
+pub unsafe fn create(ctx: &mut OpContext, parent: usize, name: &str, typ: u16) -> usize {
+    let no = INODES.alloc(ctx, typ);
+    let inode = INODES.get(no);
+    INODES.lock(inode);
+    (*inode).entry.num_links = 1;
+    INODES.sync(ctx, inode);
+    if typ == INODE_DIRECTORY {
+        INODES.insert(ctx, inode, ".", no).unwrap();
+        INODES.insert(ctx, inode, "..", parent).unwrap();
+    }
+    INODES.unlock(inode);
+    INODES.put(ctx, inode);
+
+    let dir = INODES.get(parent);
+    INODES.lock(dir);
+    INODES.insert(ctx, dir, name, no).unwrap();
+    INODES.unlock(dir);
+    INODES.put(ctx, dir);
+    no
+}
+
+unsafe fn resolve(path: &str, cwd: usize) -> Option<usize> {
+    let cwd = if cwd == 0 { ptr::null_mut() } else { INODES.get(cwd) };
+    let inode = namei(path, cwd, ptr::null_mut())?;
+    let no = (*inode).inode_no;
+    INODES.put(ptr::null_mut(), inode);
+    if !cwd.is_null() {
+        INODES.put(ptr::null_mut(), cwd);
+    }
+    Some(no)
+}
+
+unsafe fn test_dir_entries() {
+    initialize_inodes(100);
+
+    let mut ctx = OpContext::new();
+    SCACHE.begin_op(&mut ctx);
+    let root = INODES.get(ROOT_INODE_NO);
+    INODES.lock(root);
+    assert_eq!(INODES.insert(&mut ctx, root, "a", 2), Some(0));
+    assert_eq!(INODES.insert(&mut ctx, root, "bb", 3), Some(1));
+    assert_eq!(INODES.insert(&mut ctx, root, "0123456789abcd", 4), Some(2));
+    assert_eq!(INODES.insert(&mut ctx, root, "a", 5), None);
+    assert_eq!(INODES.insert(&mut ctx, root, "0123456789abcde", 5), None);
+    // Nothing is inserted once the operation cannot log any more blocks.
+    let rm = ctx.rm;
+    ctx.rm = 0;
+    assert_eq!(INODES.insert(&mut ctx, root, "d", 5), None);
+    assert!(!INODES.remove(&mut ctx, root, 1));
+    ctx.rm = rm;
+    SCACHE.end_op(&mut ctx);
+
+    assert_eq!(INODES.lookup(root, "a"), Some((2, 0)));
+    assert_eq!(INODES.lookup(root, "bb"), Some((3, 1)));
+    assert_eq!(INODES.lookup(root, "0123456789abcd"), Some((4, 2)));
+    assert_eq!(INODES.lookup(root, "b"), None);
+    assert!(INODES.read_dir(root, 3).is_none());
+
+    SCACHE.begin_op(&mut ctx);
+    assert!(INODES.remove(&mut ctx, root, 1));
+    SCACHE.end_op(&mut ctx);
+    assert_eq!(INODES.lookup(root, "bb"), None);
+    assert_eq!(INODES.read_dir(root, 1).unwrap().inode_no, 0);
+
+    // The empty slot is reused.
+    SCACHE.begin_op(&mut ctx);
+    assert_eq!(INODES.insert(&mut ctx, root, "c", 6), Some(1));
+    SCACHE.end_op(&mut ctx);
+
+    let mut names = Vec::new();
+    let mut index = 0;
+    while let Some(entry) = INODES.read_dir(root, index) {
+        if entry.inode_no != 0 {
+            names.push(entry.name().to_vec());
+        }
+        index += 1;
+    }
+    assert_eq!(names, vec![b"a".to_vec(), b"c".to_vec(), b"0123456789abcd".to_vec()]);
+    INODES.unlock(root);
+    INODES.put(ptr::null_mut(), root);
+}
+
+unsafe fn test_path() {
+    initialize_inodes(100);
+
+    let mut ctx = OpContext::new();
+    SCACHE.begin_op(&mut ctx);
+    let a = create(&mut ctx, ROOT_INODE_NO, "a", INODE_DIRECTORY);
+    let b = create(&mut ctx, a, "b", INODE_DIRECTORY);
+    let f = create(&mut ctx, b, "f", INODE_REGULAR);
+    SCACHE.end_op(&mut ctx);
+
+    assert_eq!(resolve("/", 0), Some(ROOT_INODE_NO));
+    assert_eq!(resolve("/a", 0), Some(a));
+    assert_eq!(resolve("a/b/", 0), Some(b));
+    assert_eq!(resolve("//a///b//f", 0), Some(f));
+    assert_eq!(resolve("/a/./b/../b/f", 0), Some(f));
+    assert_eq!(resolve("/../..", 0), Some(ROOT_INODE_NO));
+    assert_eq!(resolve("/a/c", 0), None);
+    assert_eq!(resolve("/a/b/f/g", 0), None);
+
+    // Relative paths start from the working directory.
+    assert_eq!(resolve("f", b), Some(f));
+    assert_eq!(resolve(".", b), Some(b));
+    assert_eq!(resolve("..", b), Some(a));
+    assert_eq!(resolve("../../a/b", b), Some(b));
+    assert_eq!(resolve("/a", b), Some(a));
+
+    let (parent, name) = nameiparent("/a/b/f", ptr::null_mut(), ptr::null_mut()).unwrap();
+    assert_eq!((*parent).inode_no, b);
+    assert_eq!(name, "f");
+    INODES.put(ptr::null_mut(), parent);
+
+    let (parent, name) = nameiparent("/a/new", ptr::null_mut(), ptr::null_mut()).unwrap();
+    assert_eq!((*parent).inode_no, a);
+    assert_eq!(name, "new");
+    INODES.put(ptr::null_mut(), parent);
+
+    assert!(nameiparent("/", ptr::null_mut(), ptr::null_mut()).is_none());
+    assert!(nameiparent("/c/d", ptr::null_mut(), ptr::null_mut()).is_none());
+}
+
+#[test]
+fn dir_entries() {
+    run(test_dir_entries);
+}
+
+#[test]
+fn path() {
+    run(test_path);
+}
@@ -0,0 +1,112 @@
+use crate::cache::{BlockCache, OpContext, SCACHE};
+use crate::defines::{BIT_PER_BLOCK, BLOCK_SIZE, INODE_DIRECTORY, INODE_MAX_SIZE, INODE_REGULAR, InodeEntry, ROOT_INODE_NO};
+use crate::fsck::{fsck, Problem};
+use crate::inode::{INODES, InodeTree};
+use crate::tests::cache_test::{mock_, sblock_};
+use crate::tests::dir_test::create;
+use crate::tests::inode_test::initialize_inodes;
+
+use super::block_device::run;
+
+unsafe fn inspect_inode(inode_no: usize) -> *mut InodeEntry {
+    (mock_().inspect(sblock_().inode_start as usize) as *mut InodeEntry).add(inode_no)
+}
+
+// Build a small filesystem: /a/f with 3 blocks of data, and /g.
+unsafe fn build() -> (usize, usize, usize) {
+    initialize_inodes(100);
+    let mut ctx = OpContext::new();
+    SCACHE.begin_op(&mut ctx);
+    let a = create(&mut ctx, ROOT_INODE_NO, "a", INODE_DIRECTORY);
+    let f = create(&mut ctx, a, "f", INODE_REGULAR);
+    let g = create(&mut ctx, ROOT_INODE_NO, "g", INODE_REGULAR);
+    SCACHE.end_op(&mut ctx);
+    SCACHE.begin_op(&mut ctx);
+    let inode = INODES.get(f);
+    INODES.lock(inode);
+    assert_eq!(INODES.write(&mut ctx, inode, &[0xab; 3 * BLOCK_SIZE], 0), 3 * BLOCK_SIZE);
+    INODES.unlock(inode);
+    INODES.put(&mut ctx, inode);
+    SCACHE.end_op(&mut ctx);
+    (a, f, g)
+}
+
+unsafe fn test_clean() {
+    build();
+    let problems = fsck(mock_(), sblock_(), false);
+    assert!(problems.is_empty(), "{}", problems[0]);
+}
+
+unsafe fn test_repair() {
+    let (a, f, g) = build();
+
+    let mut ctx = OpContext::new();
+    SCACHE.begin_op(&mut ctx);
+    let root = INODES.get(ROOT_INODE_NO);
+    INODES.lock(root);
+    INODES.insert(&mut ctx, root, "ghost", 6).unwrap();
+    INODES.unlock(root);
+    INODES.put(&mut ctx, root);
+    SCACHE.end_op(&mut ctx);
+
+    let last = sblock_().num_blocks as usize - 1;
+    let bitmap = mock_().inspect(sblock_().bitmap_start as usize);
+    *bitmap.add(last % BIT_PER_BLOCK / 8) |= 1 << (last % 8);
+    (*inspect_inode(a)).num_links = 3;
+    (*inspect_inode(f)).num_bytes = INODE_MAX_SIZE as u32 + 1;
+    (*inspect_inode(g)).addrs[0] = (*inspect_inode(f)).addrs[0];
+    (*inspect_inode(g)).num_bytes = BLOCK_SIZE as u32;
+
+    // A committed transaction writing one block which has not been installed.
+    let target = last - 1;
+    let header = mock_().inspect_log_header();
+    (*header).num_blocks = 1;
+    (*header).block_no[0] = target;
+    mock_().inspect_log(0).write_bytes(0x5a, BLOCK_SIZE);
+
+    let problems = fsck(mock_(), sblock_(), false);
+    for problem in &problems {
+        println!("{}", problem);
+    }
+    assert!(problems.iter().any(|p| matches!(p, Problem::UnreplayedLog { num_blocks: 1 })));
+    assert!(problems.iter().any(|p| matches!(p, Problem::LeakedBlock { block_no } if *block_no == last)));
+    assert!(problems.iter().any(|p| matches!(p, Problem::WrongLinkCount { inode_no, found: 3, expected: 1 } if *inode_no == a)));
+    assert!(problems.iter().any(|p| matches!(p, Problem::SizeTooLarge { inode_no, .. } if *inode_no == f)));
+    assert!(problems.iter().any(|p| matches!(p, Problem::DuplicateBlock { inode_no, owner, .. } if *inode_no == g && *owner == f)));
+    assert!(problems.iter().any(|p| matches!(p, Problem::DanglingEntry { inode_no: 6, name, .. } if name == "ghost")));
+    assert_eq!(problems.len(), 6);
+    // Nothing is changed without repairing.
+    assert_eq!((*inspect_inode(a)).num_links, 3);
+    assert_eq!((*header).num_blocks, 1);
+
+    assert_eq!(fsck(mock_(), sblock_(), true).len(), 6);
+    assert!(fsck(mock_(), sblock_(), false).is_empty());
+    assert_eq!((*header).num_blocks, 0);
+    assert_eq!(*mock_().inspect(target).add(100), 0x5a);
+    assert_eq!((*inspect_inode(a)).num_links, 1);
+    assert_eq!((*inspect_inode(g)).addrs[0], 0);
+}
+
+unsafe fn test_bad_super_block() {
+    build();
+    let mut sblock = sblock_().clone();
+    sblock.inode_start = sblock.log_start;
+    let problems = fsck(mock_(), &sblock, true);
+    assert_eq!(problems.len(), 1);
+    assert!(matches!(problems[0], Problem::BadSuperBlock(_)));
+}
+
+#[test]
+fn clean() {
+    run(test_clean);
+}
+
+#[test]
+fn repair() {
+    run(test_repair);
+}
+
+#[test]
+fn bad_super_block() {
+    run(test_bad_super_block);
+}
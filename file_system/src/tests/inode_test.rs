@@ -0,0 +1,161 @@
+use std::mem::size_of;
+use crate::cache::{BlockCache, init_bcache, OP_MAX_NUM_BLOCKS, OpContext, SCACHE};
+use crate::defines::{BIT_PER_BLOCK, BLOCK_SIZE, INODE_DIRECTORY, INODE_MAX_SIZE, INODE_NUM_DIRECT, INODE_PER_BLOCK, INODE_REGULAR, InodeEntry, ROOT_INODE_NO};
+use crate::inode::{init_inodes, INODES, InodeTree};
+use crate::tests::cache_test::{mock_, sblock_};
+
+use super::block_device::{initialize, run};
+
+pub unsafe fn initialize_inodes(num_data_blocks: usize) {
+    initialize(3 * OP_MAX_NUM_BLOCKS, num_data_blocks, "");
+    // The mock device fills the inode block with junk, so format it here with only the root directory.
+    sblock_().num_inodes = INODE_PER_BLOCK as u32;
+    let inodes = mock_().inspect(sblock_().inode_start as usize);
+    for i in 0..BLOCK_SIZE {
+        *inodes.add(i) = 0;
+    }
+    let root = &mut *(inodes.add(ROOT_INODE_NO * size_of::<InodeEntry>()) as *mut InodeEntry);
+    root.typ = INODE_DIRECTORY;
+    root.num_links = 1;
+    init_inodes(sblock_(), &mut SCACHE);
+}
+
+unsafe fn count_used_blocks() -> usize {
+    let bitmap = mock_().inspect(sblock_().bitmap_start as usize);
+    let mut count = 0;
+    for i in 0..BIT_PER_BLOCK / 8 {
+        count += (*bitmap.add(i)).count_ones() as usize;
+    }
+    count
+}
+
+unsafe fn test_alloc() {
+    initialize_inodes(100);
+
+    let mut ctx = OpContext::new();
+    SCACHE.begin_op(&mut ctx);
+    let no = INODES.alloc(&mut ctx, INODE_REGULAR);
+    SCACHE.end_op(&mut ctx);
+    assert_ne!(no, 0);
+    assert_ne!(no, ROOT_INODE_NO);
+
+    let inode = INODES.get(no);
+    assert_eq!(INODES.get(no), inode);
+    INODES.lock(inode);
+    assert_eq!((*inode).entry.typ, INODE_REGULAR);
+    assert_eq!((*inode).entry.num_bytes, 0);
+    INODES.unlock(inode);
+
+    SCACHE.begin_op(&mut ctx);
+    let other = INODES.alloc(&mut ctx, INODE_REGULAR);
+    SCACHE.end_op(&mut ctx);
+    assert_ne!(other, no);
+
+    // Dropping the last reference to an inode without links frees it.
+    SCACHE.begin_op(&mut ctx);
+    INODES.put(&mut ctx, inode);
+    INODES.put(&mut ctx, inode);
+    SCACHE.end_op(&mut ctx);
+
+    SCACHE.begin_op(&mut ctx);
+    assert_eq!(INODES.alloc(&mut ctx, INODE_REGULAR), no);
+    SCACHE.end_op(&mut ctx);
+}
+
+unsafe fn test_read_write() {
+    initialize_inodes(100);
+
+    let mut ctx = OpContext::new();
+    SCACHE.begin_op(&mut ctx);
+    let no = INODES.alloc(&mut ctx, INODE_REGULAR);
+    let inode = INODES.get(no);
+    INODES.lock(inode);
+    (*inode).entry.num_links = 1;
+    INODES.sync(&mut ctx, inode);
+    let src: Vec<u8> = (0..1000).map(|i| (i % 251) as u8).collect();
+    assert_eq!(INODES.write(&mut ctx, inode, &src, 0), src.len());
+    INODES.unlock(inode);
+    SCACHE.end_op(&mut ctx);
+
+    INODES.lock(inode);
+    assert_eq!((*inode).entry.num_bytes as usize, src.len());
+    let mut dest = vec![0u8; 2000];
+    assert_eq!(INODES.read(inode, &mut dest, 0), src.len());
+    assert_eq!(&dest[..src.len()], &src[..]);
+    assert_eq!(INODES.read(inode, &mut dest[..100], 950), 50);
+    assert_eq!(&dest[..50], &src[950..]);
+    assert_eq!(INODES.read(inode, &mut dest, 1000), 0);
+    INODES.unlock(inode);
+
+    // Everything should have reached the disk.
+    init_bcache(sblock_(), mock_());
+    init_inodes(sblock_(), &mut SCACHE);
+    let inode = INODES.get(no);
+    INODES.lock(inode);
+    let mut dest = vec![0u8; src.len()];
+    assert_eq!(INODES.read(inode, &mut dest, 0), src.len());
+    assert_eq!(dest, src);
+    INODES.unlock(inode);
+}
+
+unsafe fn test_large_file() {
+    initialize_inodes(200);
+    let used = count_used_blocks();
+
+    let mut ctx = OpContext::new();
+    SCACHE.begin_op(&mut ctx);
+    let inode = INODES.get(INODES.alloc(&mut ctx, INODE_REGULAR));
+    SCACHE.end_op(&mut ctx);
+
+    // Each operation can only log a few blocks, so a large write is cut short and has to be continued.
+    INODES.lock(inode);
+    let src: Vec<u8> = (0..INODE_MAX_SIZE + BLOCK_SIZE).map(|i| (i / BLOCK_SIZE & 0xff) as u8).collect();
+    let mut pos = 0;
+    while pos < INODE_MAX_SIZE {
+        SCACHE.begin_op(&mut ctx);
+        let n = INODES.write(&mut ctx, inode, &src[pos..], pos);
+        SCACHE.end_op(&mut ctx);
+        assert!(n > 0 && n < OP_MAX_NUM_BLOCKS * BLOCK_SIZE);
+        pos += n;
+    }
+    assert_eq!(pos, INODE_MAX_SIZE);
+    assert_eq!((*inode).entry.num_bytes as usize, INODE_MAX_SIZE);
+    // Nothing can be written beyond the largest size or the end of file.
+    SCACHE.begin_op(&mut ctx);
+    assert_eq!(INODES.write(&mut ctx, inode, &src[..1], INODE_MAX_SIZE), 0);
+    SCACHE.end_op(&mut ctx);
+    assert_ne!((*inode).entry.indirect, 0);
+    // All the data blocks and the indirect block.
+    assert_eq!(count_used_blocks(), used + INODE_MAX_SIZE / BLOCK_SIZE + 1);
+
+    // Read across the boundary of direct and indirect blocks.
+    let offset = INODE_NUM_DIRECT * BLOCK_SIZE - 10;
+    let mut dest = [0u8; 20];
+    assert_eq!(INODES.read(inode, &mut dest, offset), 20);
+    assert!(dest[..10].iter().all(|&b| b as usize == INODE_NUM_DIRECT - 1));
+    assert!(dest[10..].iter().all(|&b| b as usize == INODE_NUM_DIRECT));
+
+    SCACHE.begin_op(&mut ctx);
+    INODES.truncate(&mut ctx, inode);
+    SCACHE.end_op(&mut ctx);
+    assert_eq!((*inode).entry.num_bytes, 0);
+    assert_eq!((*inode).entry.indirect, 0);
+    assert_eq!(INODES.read(inode, &mut dest, 0), 0);
+    assert_eq!(count_used_blocks(), used);
+    INODES.unlock(inode);
+}
+
+#[test]
+fn alloc() {
+    run(test_alloc);
+}
+
+#[test]
+fn read_write() {
+    run(test_read_write);
+}
+
+#[test]
+fn large_file() {
+    run(test_large_file);
+}
@@ -3,4 +3,7 @@ pub mod block_device;
 pub mod cache;
 pub mod lock;
 pub mod cache_test;
+pub mod inode_test;
+pub mod dir_test;
+pub mod fsck_test;
 pub mod arena;
\ No newline at end of file
@@ -76,6 +76,35 @@ fn _dc_civac(addr: usize) {
     }
 }
 
+// Smallest cache line size among the cores we run on.
+const CACHE_LINE_SIZE: usize = 64;
+
+// Make instructions written through the data cache visible to instruction fetch,
+// e.g. after loading code into a user page.
+pub fn sync_icache(addr: usize, len: usize) {
+    let start = addr & !(CACHE_LINE_SIZE - 1);
+    unsafe {
+        for line in (start..addr + len).step_by(CACHE_LINE_SIZE) {
+            asm!("dc cvau, {}", in(reg) line, options(nostack, preserves_flags));
+        }
+        asm!("dsb ish", options(nostack, preserves_flags));
+        for line in (start..addr + len).step_by(CACHE_LINE_SIZE) {
+            asm!("ic ivau, {}", in(reg) line, options(nostack, preserves_flags));
+        }
+        asm!("dsb ish", options(nostack, preserves_flags));
+    }
+    isb();
+}
+
+// Invalidate all EL1&0 TLB entries on every core, so that a changed page table takes effect.
+#[inline(always)]
+pub fn flush_tlb() {
+    unsafe {
+        asm!("dsb ishst", "tlbi vmalle1is", "dsb ish", options(nostack, preserves_flags));
+    }
+    isb();
+}
+
 #[inline(always)]
 pub fn isb() {
     unsafe {
@@ -105,6 +134,15 @@ pub fn get_esr_el1() -> u64 {
     ret
 }
 
+#[inline(always)]
+pub fn get_far_el1() -> u64 {
+    let mut ret;
+    unsafe {
+        asm!("mrs {}, far_el1", out(reg) ret);
+    }
+    ret
+}
+
 #[inline(always)]
 pub fn reset_esr_el1() {
     unsafe {
@@ -2,10 +2,13 @@
 
 use crate::aarch64::intrinsic::*;
 use crate::driver::interrupt::interrupt_global_handler;
-use crate::kernel::proc::{exit, UserContext};
+use crate::kernel::proc::UserContext;
+use crate::kernel::signal::{do_signal, force_signal, SIGSEGV};
 use crate::kernel::sched::{thisproc, try_thisproc};
 use core::arch::global_asm;
-use crate::kernel::syscall::syscall_entry;
+use crate::kernel::syscall::{syscall_entry, syscall_trace};
+use crate::kernel::vm::{FaultKind, handle_page_fault, PageFault};
+use crate::println;
 
 const ESR_EC_SHIFT: i8 = 26;
 const ESR_ISS_MASK: u64 = 0xFFFFFF;
@@ -18,6 +21,45 @@ const ESR_EC_IABORT_EL1: u64 = 0x21;
 const ESR_EC_DABORT_EL0: u64 = 0x24;
 const ESR_EC_DABORT_EL1: u64 = 0x25;
 
+// Fault status code in the ISS of instruction and data aborts. The low two bits of
+// translation, access flag and permission faults are the level of the page table.
+const ISS_FSC_MASK: u64 = 0x3F;
+const ISS_FSC_TYPE_MASK: u64 = 0x3C;
+const ISS_FSC_TRANSLATION: u64 = 0x04;
+const ISS_FSC_ACCESS_FLAG: u64 = 0x08;
+const ISS_FSC_PERMISSION: u64 = 0x0C;
+// Write not Read, for data aborts only.
+const ISS_WNR: u64 = 1 << 6;
+
+fn decode_fault(esr: u64, instruction: bool) -> PageFault {
+    let iss = esr & ESR_ISS_MASK;
+    let fsc = iss & ISS_FSC_MASK;
+    let kind = match fsc & ISS_FSC_TYPE_MASK {
+        ISS_FSC_TRANSLATION => FaultKind::Translation,
+        ISS_FSC_ACCESS_FLAG => FaultKind::AccessFlag,
+        ISS_FSC_PERMISSION => FaultKind::Permission,
+        _ => FaultKind::Other,
+    };
+    PageFault {
+        addr: get_far_el1() as usize,
+        kind,
+        write: !instruction && iss & ISS_WNR != 0,
+        exec: instruction,
+    }
+}
+
+// Handle an abort from user space. Send `SIGSEGV` to the process if the access is invalid,
+// and tell why if syscalls are traced.
+fn user_fault(esr: u64, instruction: bool, context: &UserContext) {
+    let fault = decode_fault(esr, instruction);
+    if !handle_page_fault(&fault) {
+        if syscall_trace() {
+            println!("pid {}: segmentation fault, {:?} at {:#x}", thisproc().pid, fault, context.elr_el1);
+        }
+        force_signal(SIGSEGV);
+    }
+}
+
 global_asm!(include_str!("trap.asm"));
 global_asm!(include_str!("exception_vector.asm"));
 
@@ -44,13 +86,13 @@ pub extern "C" fn trap_global_handler(context: *mut UserContext) {
             syscall_entry(context);
         }
         ESR_EC_IABORT_EL0 => {
-            panic!("IABORT_EL0 exception, at {:x}", context.elr_el1);
+            user_fault(esr, true, context);
         }
         ESR_EC_IABORT_EL1 => {
             panic!("IABORT_EL1 exception, at {:x}", context.elr_el1);
         }
         ESR_EC_DABORT_EL0 => {
-            panic!("DABORT_EL0 exception, at {:x}", context.elr_el1);
+            user_fault(esr, false, context);
         }
         ESR_EC_DABORT_EL1 => {
             panic!("DABORT_EL1 exception, at {:x}", context.elr_el1);
@@ -59,9 +101,7 @@ pub extern "C" fn trap_global_handler(context: *mut UserContext) {
             panic!("Unknown exception");
         }
     }
-    if thisproc().killed {
-        exit(-1);
-    }
+    do_signal(context);
 }
 
 #[no_mangle]
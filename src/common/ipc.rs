@@ -1,55 +1,149 @@
 use alloc::boxed::Box;
+use alloc::vec;
+use alloc::vec::Vec;
 use core::cmp::min;
-use core::mem::MaybeUninit;
-use core::ptr;
+use core::mem::{MaybeUninit, size_of};
+use core::{ptr, slice};
 use field_offset::offset_of;
 use spin::Mutex;
+use crate::aarch64::intrinsic::get_time_us;
 use crate::aarch64::mmu::PAGE_SIZE;
 use crate::common::list::{InplaceFilter, ListLink, ListNode};
-use crate::define_early_init;
+use crate::{define_early_init, define_syscall};
+use crate::kernel::errno::Errno::{self, E2BIG, EACCES, EAGAIN, EEXIST, EIDRM, EINTR, EINVAL, ENOENT, ENOMEM, ENOMSG, ENOSPC, EPERM};
+use crate::kernel::errno::SyscallResult;
 use crate::kernel::mem::{kalloc_page, kfree_page};
 use crate::kernel::proc::Process;
 use crate::kernel::proc::ProcessState::Sleeping;
-use crate::kernel::sched::{acquire_sched_lock, activate, sched, thisproc};
+use crate::kernel::sched::{acquire_proc_lock, activate, sched, thisproc};
+use crate::kernel::syscall::{SYS_MSGCTL, SYS_MSGGET, SYS_MSGRCV, SYS_MSGSND};
+use crate::kernel::uaccess::{UserPtr, UserSlice};
 
-const SEQ_MULTIPLIER: i32 = 16;
-const MAX_MSGNUM: i32 = 256;
+// An id is `seq * IPCMNI + index`, as on Linux. Every new queue takes the next `seq`, so a stale id of
+// a removed queue does not match a new queue in the same slot until `seq` wraps around.
+const IPCMNI: i32 = 32768;
+const SEQ_MAX: i32 = i32::MAX / IPCMNI;
 
 pub const IPC_RMID: i32 = 0;
+pub const IPC_SET: i32 = 1;
+pub const IPC_STAT: i32 = 2;
+pub const IPC_INFO: i32 = 3;
 pub const IPC_PRIVATE: i32 = 0;
-pub const IPC_CREATE: i32 = 2;
-pub const IPC_EXCL: i32 = 1;
-pub const IPC_NOWAIT: i32 = 1;
-
-const ENOMEM: i32 = -1;
-const ENOSEQ: i32 = -2;
-const ENOENT: i32 = -3;
-const EEXIST: i32 = -4;
-const EINVAL: i32 = -5;
-const EAGAIN: i32 = -6;
-const EIDRM: i32 = -7;
-const E2BIG: i32 = -8;
-const ENOMSG: i32 = -9;
+// The flags have the same values as on Linux, so that the low 9 bits of `msgflg` of `msgget` are free
+// for the permissions of the new queue.
+pub const IPC_CREATE: i32 = 0o1000;
+pub const IPC_EXCL: i32 = 0o2000;
+pub const IPC_NOWAIT: i32 = 0o4000;
+
+// The default limits, as on Linux. See `MsgLimits`.
+pub const MSGMNI: usize = 32000;
+pub const MSGMAX: usize = 8192;
+pub const MSGMNB: usize = 16384;
+
+const S_IRUGO: u32 = 0o444;
+const S_IWUGO: u32 = 0o222;
 
 const MSG_SIZE: usize = PAGE_SIZE - core::mem::size_of::<Message>();
 const MSG_SEG_SIZE: usize = PAGE_SIZE - core::mem::size_of::<MessageSegment>();
 
+/// The ownership and permissions of an IPC object, laid out as `struct ipc64_perm` of Linux.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct IpcPerm {
+    pub key: i32,
+    pub uid: u32,
+    pub gid: u32,
+    pub cuid: u32,
+    pub cgid: u32,
+    pub mode: u32,
+    pub seq: u16,
+    _pad: u16,
+    _unused: [u64; 2],
+}
+
+/// The status of a message queue, laid out as `struct msqid64_ds` of Linux.
+///
+/// `IPC_STAT` fills all of it, and `IPC_SET` takes `msg_perm.uid`, `msg_perm.gid`, `msg_perm.mode`
+/// and `msg_qbytes` from it.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct MsqidDs {
+    pub msg_perm: IpcPerm,
+    pub msg_stime: i64,
+    pub msg_rtime: i64,
+    pub msg_ctime: i64,
+    pub msg_cbytes: u64,
+    pub msg_qnum: u64,
+    pub msg_qbytes: u64,
+    pub msg_lspid: i32,
+    pub msg_lrpid: i32,
+    _unused: [u64; 2],
+}
+
+/// The limits of message queues, laid out as `struct msginfo` of Linux.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct MsgInfo {
+    pub msgpool: i32,
+    pub msgmap: i32,
+    pub msgmax: i32,
+    pub msgmnb: i32,
+    pub msgmni: i32,
+    pub msgssz: i32,
+    pub msgtql: i32,
+    pub msgseg: u16,
+}
+
 struct MessageQueue {
-    key: i32,
+    perm: IpcPerm,
     seq: i32,
-    max_msg: i32,
-    sum_msg: i32,
+    // The time of the last send, receive and change, in seconds since boot.
+    stime: i64,
+    rtime: i64,
+    ctime: i64,
+    // The total size and the number of the messages in the queue.
+    cbytes: usize,
+    qnum: usize,
+    // The queue is full when it would hold more than `qbytes` bytes or messages.
+    qbytes: usize,
+    // The last process that sent and received a message.
+    lspid: usize,
+    lrpid: usize,
     q_message: ListLink,
     q_sender: ListLink,
     q_receiver: ListLink,
 }
 
+/// The limits of message queues in an IPC namespace.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MsgLimits {
+    /// The maximum number of queues, at most `IPCMNI`.
+    pub msgmni: usize,
+    /// The size of the largest message user space can send.
+    pub msgmax: usize,
+    /// The default size limit in bytes of a queue. Only root can raise a queue's limit above it.
+    pub msgmnb: usize,
+}
+
+impl Default for MsgLimits {
+    fn default() -> Self {
+        Self {
+            msgmni: MSGMNI,
+            msgmax: MSGMAX,
+            msgmnb: MSGMNB,
+        }
+    }
+}
+
+/// The message queues of an IPC namespace.
 struct IPCIds {
-    size: i32,
-    in_use: i32,
-    seq: u8,
+    in_use: usize,
+    seq: i32,
+    limits: MsgLimits,
     lock: Mutex<()>,
-    entries: [*mut MessageQueue; SEQ_MULTIPLIER as usize],
+    /// The queue of each index. It grows when all slots are in use, and a removed queue leaves
+    /// a null slot to be reused.
+    entries: Vec<*mut MessageQueue>,
 }
 
 trait MessageHeader<T> {
@@ -157,11 +251,11 @@ fn msg_ids() -> &'static mut IPCIds {
 extern "C" fn init_ipc() {
     unsafe {
         MSG_IDS = MaybeUninit::new(IPCIds {
-            size: SEQ_MULTIPLIER,
             in_use: 0,
             seq: 0,
+            limits: MsgLimits::default(),
             lock: Mutex::new(()),
-            entries: [ptr::null_mut(); SEQ_MULTIPLIER as usize],
+            entries: Vec::new(),
         });
     }
 }
@@ -170,32 +264,107 @@ define_early_init!(init_ipc);
 /// Add a message `queue` to the IPC ids.
 ///
 /// It will set the `seq` field of `queue` to the next available sequence number.
+/// Return None if there are already `msgmni` queues.
 fn ipc_add_id(queue: &mut MessageQueue) -> Option<i32> {
     let msg_ids = msg_ids();
-    msg_ids.entries.iter_mut().enumerate()
-        .find(|(_, e)| e.is_null())
-        .map(|(i, e)| {
-            msg_ids.in_use += 1;
-            queue.seq = msg_ids.seq as i32;
-            msg_ids.seq += 1;
-            *e = queue;
-            i as i32
-        })
+    if msg_ids.in_use >= msg_ids.limits.msgmni {
+        return None;
+    }
+    let id = match msg_ids.entries.iter().position(|e| e.is_null()) {
+        Some(id) => id,
+        None => {
+            msg_ids.entries.push(ptr::null_mut());
+            msg_ids.entries.len() - 1
+        }
+    };
+    msg_ids.entries[id] = queue;
+    msg_ids.in_use += 1;
+    queue.seq = msg_ids.seq;
+    msg_ids.seq = if msg_ids.seq == SEQ_MAX { 0 } else { msg_ids.seq + 1 };
+    Some(id as i32)
 }
 
 const fn ipc_buildin(id: i32, seq: i32) -> i32 {
-    seq * SEQ_MULTIPLIER + id
+    seq * IPCMNI + id
+}
+
+/// Get the limits of message queues.
+pub fn msg_limits() -> MsgLimits {
+    let msg_ids = msg_ids();
+    let _lock = msg_ids.lock.lock();
+    msg_ids.limits
+}
+
+/// Change the limits of message queues.
+///
+/// Existing queues are kept even if there are more than `msgmni` of them, but no more can be created.
+pub fn set_msg_limits(limits: MsgLimits) -> Result<(), Errno> {
+    if limits.msgmni > IPCMNI as usize || limits.msgmax == 0 || limits.msgmnb == 0 {
+        return Err(EINVAL);
+    }
+    let msg_ids = msg_ids();
+    let _lock = msg_ids.lock.lock();
+    msg_ids.limits = limits;
+    Ok(())
+}
+
+fn now() -> i64 {
+    (get_time_us() / 1_000_000) as i64
+}
+
+/// Check that the current process has the permissions `flag` to the IPC object `perm`.
+///
+/// `flag` is in the form of a mode, e.g. `S_IRUGO` for reading. The owner bits of the mode apply to
+/// the owner and the creator, the group bits to their groups, and the other bits to everyone else.
+/// Root passes every check.
+fn ipc_perms(perm: &IpcPerm, flag: u32) -> Result<(), Errno> {
+    let proc = thisproc();
+    let requested = (flag | flag >> 3 | flag >> 6) & 0o7;
+    let granted = if proc.uid == perm.uid || proc.uid == perm.cuid {
+        perm.mode >> 6
+    } else if proc.gid == perm.gid || proc.gid == perm.cgid {
+        perm.mode >> 3
+    } else {
+        perm.mode
+    } & 0o7;
+    if proc.uid != 0 && requested & !granted != 0 {
+        Err(EACCES)
+    } else {
+        Ok(())
+    }
+}
+
+/// Determine whether the current process may change or remove the IPC object `perm`.
+fn ipc_owner(perm: &IpcPerm) -> bool {
+    let uid = thisproc().uid;
+    uid == 0 || uid == perm.uid || uid == perm.cuid
 }
 
 /// Create a message queue with the given `key` and add it to the IPC ids.
 ///
+/// The queue is owned by the current process, with the permissions in the low 9 bits of `msgflg`.
 /// Returns the allocated id for the message queue.
-fn new_queue(key: i32) -> Result<i32, i32> {
+fn new_queue(key: i32, msgflg: i32) -> Result<i32, Errno> {
+    let proc = thisproc();
     let mut queue = Box::new(MessageQueue {
-        key,
+        perm: IpcPerm {
+            key,
+            uid: proc.uid,
+            gid: proc.gid,
+            cuid: proc.uid,
+            cgid: proc.gid,
+            mode: msgflg as u32 & 0o777,
+            ..Default::default()
+        },
         seq: 0,
-        max_msg: MAX_MSGNUM,
-        sum_msg: 0,
+        stime: 0,
+        rtime: 0,
+        ctime: now(),
+        cbytes: 0,
+        qnum: 0,
+        qbytes: msg_ids().limits.msgmnb,
+        lspid: 0,
+        lrpid: 0,
         q_message: ListLink::uninit(),
         q_sender: ListLink::uninit(),
         q_receiver: ListLink::uninit(),
@@ -204,7 +373,7 @@ fn new_queue(key: i32) -> Result<i32, i32> {
     queue.q_sender.init();
     queue.q_receiver.init();
 
-    let id = ipc_add_id(queue.as_mut()).ok_or(ENOSEQ)?;
+    let id = ipc_add_id(queue.as_mut()).ok_or(ENOSPC)?;
 
     let ret = ipc_buildin(id, queue.seq);
     let _ = Box::into_raw(queue);
@@ -216,19 +385,19 @@ fn new_queue(key: i32) -> Result<i32, i32> {
 fn ipc_findkey(key: i32) -> Option<i32> {
     msg_ids().entries.iter_mut()
         .enumerate()
-        .find(|(_, e)| !e.is_null() && unsafe { e.read().key } == key)
+        .find(|(_, e)| !e.is_null() && unsafe { e.read().perm.key } == key)
         .map(|(i, _)| i as i32)
 }
 
 /// Get the message queue with the given `key`.
 ///
 /// Returns the message queue's id.
-pub fn sys_msgget(key: i32, msgflg: i32) -> Result<i32, i32> {
+pub fn msgget(key: i32, msgflg: i32) -> Result<i32, Errno> {
     let ipc_ids = msg_ids();
     let _lock = ipc_ids.lock.lock();
 
     if key == IPC_PRIVATE {
-        return new_queue(key);
+        return new_queue(key, msgflg);
     }
 
 
@@ -238,13 +407,15 @@ pub fn sys_msgget(key: i32, msgflg: i32) -> Result<i32, i32> {
             Err(EEXIST)
         } else {
             let queue = unsafe { &mut *ipc_ids.entries[id as usize] };
+            // The caller must have the permissions it asks for
+            ipc_perms(&queue.perm, msgflg as u32 & 0o777)?;
             Ok(ipc_buildin(id, queue.seq))
         };
     }
 
     // Or, if a message queue with the given `key` does not exist
     return if msgflg & IPC_CREATE != 0 {
-        new_queue(key)
+        new_queue(key, msgflg)
     } else {
         Err(ENOENT)
     };
@@ -296,19 +467,24 @@ fn load_msg(mut src: &[u8]) -> *mut Message {
 
 /// Get the corresponding message queue with the given `msg_id`.
 ///
+/// Return EINVAL if `msg_id` has never been an id, or EIDRM if its queue has been removed.
+///
 /// Note: you should hold the lock of `MSG_IDS` before calling this function.
-fn get_msg_queue(msg_id: i32) -> Option<&'static mut MessageQueue> {
+fn get_msg_queue(msg_id: i32) -> Result<&'static mut MessageQueue, Errno> {
     let ipc_ids = msg_ids();
-    let id = msg_id % SEQ_MULTIPLIER;
-    if id < 0 || id >= ipc_ids.size || ipc_ids.entries[id as usize].is_null() {
-        return None;
+    if msg_id < 0 {
+        return Err(EINVAL);
+    }
+    let id = (msg_id % IPCMNI) as usize;
+    let queue = *ipc_ids.entries.get(id).ok_or(EINVAL)?;
+    if queue.is_null() {
+        return Err(EIDRM);
     }
-    let queue = ipc_ids.entries[id as usize];
     let queue = unsafe { &mut *queue };
-    if queue.seq != msg_id / SEQ_MULTIPLIER {
-        return None;
+    if queue.seq != msg_id / IPCMNI {
+        return Err(EIDRM);
     }
-    Some(queue)
+    Ok(queue)
 }
 
 /// Determine whether the receiver can receive the message type.
@@ -324,12 +500,11 @@ fn test_msg(receive_type: i32, msg_type: i32) -> bool {
 
 /// Send `msg` to `queue`.
 ///
-/// Return true if it finds the first receiver which can receive the message.
-/// Otherwise, return false.
+/// Return the pid of the first receiver which can receive the message, if any.
 ///
 /// It will wake up all potential receivers before the first possible receiver.
-fn pipeline_send(queue: &mut MessageQueue, msg: &mut Message) -> bool {
-    let mut ret = false;
+fn pipeline_send(queue: &mut MessageQueue, msg: &mut Message) -> Option<usize> {
+    let mut ret = None;
     let mtype = msg.mtype;
     queue.q_receiver.iter::<MessageReceiver>(true).filter_inplace(|recv: &mut MessageReceiver| {
         // Only keep the receivers that can not receive this message
@@ -344,8 +519,8 @@ fn pipeline_send(queue: &mut MessageQueue, msg: &mut Message) -> bool {
         } else {
             // Give the message to the receiver
             recv.r_msg = msg;
+            ret = Some(proc.pid);
             activate(proc);
-            ret = true;
             // Break the iteration
             true
         }
@@ -356,29 +531,44 @@ fn pipeline_send(queue: &mut MessageQueue, msg: &mut Message) -> bool {
 /// Create a new message with the given buffer `msgp` and `msg_size`.
 /// Then, send the message to the message queue with the given `msg_id`.
 ///
-/// Return 0 on success, or a negative error code on failure.
-pub fn sys_msgsend(msg_id: i32, msgp: &mut MessageBuffer, msg_size: usize, msgflg: i32) -> Result<i32, i32> {
-    if msgp.mtype < 1 {
+/// Return 0 on success, or the error on failure.
+pub fn msgsnd(msg_id: i32, msgp: &mut MessageBuffer, msg_size: usize, msgflg: i32) -> Result<i32, Errno> {
+    send_msg(msg_id, msgp.mtype, msgp.get_data_slice(msg_size), msgflg).map(|_| 0)
+}
+
+/// Send a message of `mtype` with the content `data` to the message queue with the given `msg_id`.
+fn send_msg(msg_id: i32, mtype: i32, data: &[u8], msgflg: i32) -> Result<(), Errno> {
+    if mtype < 1 {
         return Err(EINVAL);
     }
     // Create a new message in new pages
-    let msg = load_msg(msgp.get_data_slice(msg_size));
+    let msg = load_msg(data);
     if msg.is_null() {
         return Err(ENOMEM);
     }
     let msg = unsafe { &mut *msg };
-    msg.mtype = msgp.mtype;
-    msg.size = msg_size;
+    msg.mtype = mtype;
+    msg.size = data.len();
 
     loop {
         let lock = msg_ids().lock.lock();
-        let queue = get_msg_queue(msg_id);
-        if queue.is_none() {
+        let queue = match get_msg_queue(msg_id) {
+            Ok(queue) => queue,
+            Err(err) => {
+                drop_msg(msg);
+                return Err(err);
+            }
+        };
+        if let Err(err) = ipc_perms(&queue.perm, S_IWUGO) {
+            drop_msg(msg);
+            return Err(err);
+        }
+        // A message larger than the whole queue would wait forever.
+        if data.len() > queue.qbytes {
             drop_msg(msg);
-            return Err(EIDRM);
+            return Err(EINVAL);
         }
-        let queue = queue.unwrap();
-        if queue.sum_msg + 1 > queue.max_msg {
+        if data.len() + queue.cbytes > queue.qbytes || queue.qnum + 1 > queue.qbytes {
             // The queue is full, we need to wait!
             if msgflg & IPC_NOWAIT != 0 {
                 // But we cannot wait, so we return EAGAIN
@@ -389,19 +579,35 @@ pub fn sys_msgsend(msg_id: i32, msgp: &mut MessageBuffer, msg_size: usize, msgfl
                 let mut sender = MessageSender::uninit(thisproc());
                 sender.init();
                 queue.q_sender.insert_at_last(&mut sender);
-                let sched_lock = acquire_sched_lock();
+                let proc_lock = acquire_proc_lock(thisproc());
                 drop(lock);
-                sched(sched_lock, Sleeping);
+                sched(proc_lock, Sleeping);
+
+                // Receivers take us off the queue when waking us up, but `kill` does not.
+                let _lock = msg_ids().lock.lock();
+                if !sender.link.is_single() {
+                    sender.link.detach();
+                }
+                if thisproc().killed || thisproc().signal.has_deliverable() {
+                    drop_msg(msg);
+                    return Err(EINTR);
+                }
             }
         } else {
             // The queue is not full, we can send the message
-            if !pipeline_send(queue, msg) {
+            if let Some(pid) = pipeline_send(queue, msg) {
+                queue.lrpid = pid;
+                queue.rtime = now();
+            } else {
                 // If no receiver is waiting, we push the message into the queue
                 msg.link.init();
                 queue.q_message.insert_at_last(msg);
-                queue.sum_msg += 1;
+                queue.cbytes += msg.size;
+                queue.qnum += 1;
             }
-            return Ok(0);
+            queue.lspid = thisproc().pid;
+            queue.stime = now();
+            return Ok(());
         }
     }
 }
@@ -452,11 +658,23 @@ fn store_msg(dst: *mut u8, msg: &mut Message, msg_size: usize) {
 
 /// Receive a message from the message queue with the given `msg_id`.
 ///
-/// Return the message size on success, or a negative error code on failure.
-pub fn sys_msgrcv(msg_id: i32, msgp: &mut MessageBuffer, mut msg_size: usize, mut mtype: i32, msgflg: i32) -> Result<i32, i32> {
+/// Return the message size on success, or the error on failure.
+pub fn msgrcv(msg_id: i32, msgp: &mut MessageBuffer, msg_size: usize, mtype: i32, msgflg: i32) -> Result<i32, Errno> {
+    let buf = unsafe { slice::from_raw_parts_mut(msgp.get_data(), msg_size) };
+    let (mtype, size) = recv_msg(msg_id, buf, mtype, msgflg)?;
+    msgp.mtype = mtype;
+    Ok(size as i32)
+}
+
+/// Receive a message into `buf` from the message queue with the given `msg_id`.
+///
+/// Return the type and the size of the message.
+fn recv_msg(msg_id: i32, buf: &mut [u8], mut mtype: i32, msgflg: i32) -> Result<(i32, usize), Errno> {
+    let mut msg_size = buf.len();
     let lock = msg_ids().lock.lock();
 
-    let queue = get_msg_queue(msg_id).ok_or(EIDRM)?;
+    let queue = get_msg_queue(msg_id)?;
+    ipc_perms(&queue.perm, S_IRUGO)?;
 
     let mut found_msg: *mut Message = ptr::null_mut();
 
@@ -480,7 +698,10 @@ pub fn sys_msgrcv(msg_id: i32, msgp: &mut MessageBuffer, mut msg_size: usize, mu
         }
         // This message is the one we want, so we detach it from the queue
         found_msg.link.detach();
-        queue.sum_msg -= 1;
+        queue.cbytes -= found_msg.size;
+        queue.qnum -= 1;
+        queue.lrpid = thisproc().pid;
+        queue.rtime = now();
         // The queue has space now, so we wake up all the senders
         wakeup_senders(&mut queue.q_sender);
         drop(lock);
@@ -494,13 +715,21 @@ pub fn sys_msgrcv(msg_id: i32, msgp: &mut MessageBuffer, mut msg_size: usize, mu
             let mut receiver = MessageReceiver::uninit(thisproc(), mtype, msg_size);
             receiver.init();
             queue.q_receiver.insert_at_last(&mut receiver);
-            let sched_lock = acquire_sched_lock();
+            let proc_lock = acquire_proc_lock(thisproc());
             drop(lock);
-            sched(sched_lock, Sleeping);
+            sched(proc_lock, Sleeping);
 
             // After waking up, we check the message again
+            let _lock = msg_ids().lock.lock();
             found_msg = receiver.r_msg;
             if found_msg.is_null() {
+                // Senders take us off the queue when waking us up, but `kill` does not.
+                if !receiver.link.is_single() {
+                    receiver.link.detach();
+                    return Err(EINTR);
+                }
+                // `drop_queue` wakes us up without a message, too
+                get_msg_queue(msg_id)?;
                 // In `ss_wakeup`, if the message is too large, we set `r_msg` to null
                 return Err(E2BIG);
             }
@@ -511,12 +740,12 @@ pub fn sys_msgrcv(msg_id: i32, msgp: &mut MessageBuffer, mut msg_size: usize, mu
     let found_msg = unsafe { &mut *found_msg };
     msg_size = min(msg_size, found_msg.size);
     // Store the message into the buffer
-    store_msg(msgp.get_data(), found_msg, msg_size);
-    msgp.mtype = found_msg.mtype;
+    store_msg(buf.as_mut_ptr(), found_msg, msg_size);
+    let mtype = found_msg.mtype;
     // Drop the message
     drop_msg(found_msg);
     // Return the size of the message
-    Ok(msg_size as i32)
+    Ok((mtype, msg_size))
 }
 
 /// Empty out the message `queue`.
@@ -531,36 +760,156 @@ fn expunge_all(queue: &mut MessageQueue) {
 }
 
 /// Drop the message queue with the given `msg_id`.
-fn drop_queue(msg_id: i32) {
+///
+/// Only the owner, the creator or root can drop a queue.
+fn drop_queue(msg_id: i32) -> Result<(), Errno> {
     let msg_ids = msg_ids();
     let _lock = msg_ids.lock.lock();
-    let queue_ptr = get_msg_queue(msg_id);
-    if let Some(queue) = queue_ptr {
-        // Remove the queue from `MSG_IDS`
-        msg_ids.entries[(msg_id % SEQ_MULTIPLIER) as usize] = ptr::null_mut();
-        // Wake up all the senders and receivers
-        wakeup_receivers(&mut queue.q_receiver);
-        expunge_all(queue);
-        // Drop all the messages in the queue
-        queue.q_message.iter::<Message>(true).filter_inplace(|_| false, |msg: &mut Message| {
-            drop_msg(msg);
-            false
-        });
-        msg_ids.in_use -= 1;
-        // Drop the queue
-        unsafe { let _ = Box::from_raw(queue); }
+    let queue = get_msg_queue(msg_id)?;
+    if !ipc_owner(&queue.perm) {
+        return Err(EPERM);
+    }
+    // Remove the queue from `MSG_IDS`
+    msg_ids.entries[(msg_id % IPCMNI) as usize] = ptr::null_mut();
+    // Wake up all the senders and receivers, who will find the queue removed
+    wakeup_senders(&mut queue.q_sender);
+    wakeup_receivers(&mut queue.q_receiver);
+    expunge_all(queue);
+    // Drop all the messages in the queue
+    queue.q_message.iter::<Message>(true).filter_inplace(|_| false, |msg: &mut Message| {
+        drop_msg(msg);
+        false
+    });
+    msg_ids.in_use -= 1;
+    // Drop the queue
+    unsafe { let _ = Box::from_raw(queue); }
+    Ok(())
+}
+
+/// Get the status of the message queue with the given `msg_id`.
+///
+/// The caller needs the permission to read the queue.
+pub fn msg_stat(msg_id: i32) -> Result<MsqidDs, Errno> {
+    let _lock = msg_ids().lock.lock();
+    let queue = get_msg_queue(msg_id)?;
+    ipc_perms(&queue.perm, S_IRUGO)?;
+    Ok(MsqidDs {
+        msg_perm: IpcPerm { seq: queue.seq as u16, ..queue.perm },
+        msg_stime: queue.stime,
+        msg_rtime: queue.rtime,
+        msg_ctime: queue.ctime,
+        msg_cbytes: queue.cbytes as u64,
+        msg_qnum: queue.qnum as u64,
+        msg_qbytes: queue.qbytes as u64,
+        msg_lspid: queue.lspid as i32,
+        msg_lrpid: queue.lrpid as i32,
+        ..Default::default()
+    })
+}
+
+/// Change the owner, the permissions and the size limit of the message queue with the given `msg_id`
+/// to those in `ds`.
+///
+/// Only the owner, the creator or root can change a queue, and only root can raise its limit above `msgmnb`.
+pub fn msg_set(msg_id: i32, ds: &MsqidDs) -> Result<(), Errno> {
+    let _lock = msg_ids().lock.lock();
+    let queue = get_msg_queue(msg_id)?;
+    if !ipc_owner(&queue.perm) {
+        return Err(EPERM);
+    }
+    if ds.msg_qbytes > msg_ids().limits.msgmnb as u64 && thisproc().uid != 0 {
+        return Err(EPERM);
     }
+    queue.perm.uid = ds.msg_perm.uid;
+    queue.perm.gid = ds.msg_perm.gid;
+    queue.perm.mode = ds.msg_perm.mode & 0o777;
+    queue.qbytes = ds.msg_qbytes as usize;
+    queue.ctime = now();
+    // The queue may have space now
+    wakeup_senders(&mut queue.q_sender);
+    Ok(())
+}
+
+/// Get the limits of message queues.
+///
+/// Also return the highest index in use of the IPC ids, as `IPC_INFO` does on Linux.
+pub fn msg_info() -> (MsgInfo, i32) {
+    let msg_ids = msg_ids();
+    let _lock = msg_ids.lock.lock();
+    let info = MsgInfo {
+        msgmax: msg_ids.limits.msgmax as i32,
+        msgmnb: msg_ids.limits.msgmnb as i32,
+        msgmni: msg_ids.limits.msgmni as i32,
+        msgssz: MSG_SEG_SIZE as i32,
+        // Messages are allocated from pages on demand, so there is no pool of segments to report.
+        ..Default::default()
+    };
+    let max_id = msg_ids.entries.iter().rposition(|e| !e.is_null()).unwrap_or(0);
+    (info, max_id as i32)
 }
 
 /// Control the message queue with the given `msg_id`.
 ///
-/// Return 0 on success, or a negative error code on failure.
-pub fn sys_msgctl(msg_id: i32, cmd: i32) -> Result<i32, i32> {
+/// Return 0 on success, or the error on failure. Use `msg_stat`, `msg_set` and `msg_info` for the
+/// commands with a buffer.
+pub fn msgctl(msg_id: i32, cmd: i32) -> Result<i32, Errno> {
     match cmd {
         IPC_RMID => {
-            drop_queue(msg_id);
+            drop_queue(msg_id)?;
             Ok(0)
         }
         _ => Err(EINVAL)
     }
-}
\ No newline at end of file
+}
+define_syscall!(SYS_MSGGET, fn sys_msgget(key: i32, msgflg: i32) -> SyscallResult {
+    msgget(key, msgflg).map(|id| id as u64)
+});
+
+// `msgp` points to a `struct msgbuf { long mtype; char mtext[msgsz]; }` in user space.
+define_syscall!(SYS_MSGSND, fn sys_msgsnd(msg_id: i32, msgp: UserPtr<i64>, msgsz: usize, msgflg: i32) -> SyscallResult {
+    if msgsz > msg_limits().msgmax {
+        return Err(EINVAL);
+    }
+    let mtype = msgp.read()?;
+    if mtype < 1 || mtype > i32::MAX as i64 {
+        return Err(EINVAL);
+    }
+    let mut data = vec![0u8; msgsz];
+    UserSlice::new(msgp.add(1).addr(), msgsz).read(&mut data)?;
+    send_msg(msg_id, mtype as i32, &data, msgflg)?;
+    Ok(0)
+});
+
+define_syscall!(SYS_MSGRCV, fn sys_msgrcv(msg_id: i32, msgp: UserPtr<i64>, msgsz: usize, msgtyp: i64, msgflg: i32) -> SyscallResult {
+    let mtext = UserSlice::new(msgp.add(1).addr(), msgsz);
+    let mut data = vec![0u8; min(msgsz, msg_limits().msgmax)];
+    // Check the buffer first, so that a message is not lost for a bad one.
+    // It is left as it is, since we may receive nothing.
+    UserSlice::new(msgp.addr(), size_of::<i64>() + data.len()).check_writable()?;
+    let mtype = msgtyp.clamp(-(i32::MAX as i64), i32::MAX as i64) as i32;
+    let (mtype, size) = recv_msg(msg_id, &mut data, mtype, msgflg)?;
+    msgp.write(mtype as i64)?;
+    mtext.write(&data[..size])?;
+    Ok(size as u64)
+});
+
+// `buf` points to a `MsqidDs` for `IPC_STAT` and `IPC_SET`, or a `MsgInfo` for `IPC_INFO`.
+define_syscall!(SYS_MSGCTL, fn sys_msgctl(msg_id: i32, cmd: i32, buf: usize) -> SyscallResult {
+    match cmd {
+        IPC_STAT => {
+            UserPtr::<MsqidDs>::new(buf).write(msg_stat(msg_id)?)?;
+            Ok(0)
+        }
+        IPC_SET => {
+            let ds = UserPtr::<MsqidDs>::new(buf).read()?;
+            msg_set(msg_id, &ds)?;
+            Ok(0)
+        }
+        IPC_INFO => {
+            let (info, max_id) = msg_info();
+            UserPtr::<MsgInfo>::new(buf).write(info)?;
+            Ok(max_id as u64)
+        }
+        _ => msgctl(msg_id, cmd).map(|ret| ret as u64),
+    }
+});
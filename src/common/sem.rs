@@ -4,7 +4,7 @@ use spin::Mutex;
 use crate::common::list::{ListLink, ListNode};
 use crate::kernel::proc::Process;
 use crate::kernel::proc::ProcessState::Sleeping;
-use crate::kernel::sched::{acquire_sched_lock, activate, activate_no_lock, sched, thisproc};
+use crate::kernel::sched::{acquire_proc_lock, activate, activate_no_lock, sched, thisproc};
 
 pub struct Semaphore {
     lock: Mutex<()>,
@@ -58,10 +58,10 @@ impl Semaphore {
         let mut wait_data = Box::new(WaitData::uninit());
         wait_data.sibling.init();
         self.sleep_list.insert_at_first(wait_data.as_mut());
-        // Lock for the scheduler, and tell it that the process is going to sleep.
-        let sched_lock = acquire_sched_lock();
+        // Lock the process, and tell the scheduler that it is going to sleep.
+        let proc_lock = acquire_proc_lock(thisproc());
         drop(lock);
-        sched(sched_lock, Sleeping);
+        sched(proc_lock, Sleeping);
 
         // Now back from the scheduler...
         // ... lock self again, since we are going to modify this semaphore.
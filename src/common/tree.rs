@@ -468,4 +468,52 @@ impl<T: ListNode<RbTreeLink> + 'static> RbTree<T> {
             Some(ret)
         }
     }
+
+    pub fn len(&self) -> usize {
+        self.size
+    }
+
+    // Return the smallest node for which `before` is false.
+    //
+    // `before` must be monotonic in the order of the tree, i.e. true for all nodes smaller than some point,
+    // and false for the others.
+    pub fn first_after<F: FnMut(&mut T) -> bool>(&mut self, mut before: F) -> Option<&'static mut T> {
+        let mut node = self.root;
+        let mut ret = ptr::null_mut();
+        while !node.is_null() {
+            if before(T::container(node)) {
+                node = right_of(node);
+            } else {
+                ret = node;
+                node = left_of(node);
+            }
+        }
+        if ret.is_null() {
+            None
+        } else {
+            Some(T::container(ret))
+        }
+    }
+
+    // Return the next node of `node` in order.
+    pub fn next(&self, node: &mut T) -> Option<&'static mut T> {
+        let mut link = node.link_ptr();
+        if !right_of(link).is_null() {
+            link = right_of(link);
+            while !left_of(link).is_null() {
+                link = left_of(link);
+            }
+            return Some(T::container(link));
+        }
+        // Go up until we come from a left subtree.
+        while is_right_child(link, parent_of(link)) {
+            link = parent_of(link);
+        }
+        let parent = parent_of(link);
+        if parent.is_null() {
+            None
+        } else {
+            Some(T::container(parent))
+        }
+    }
 }
\ No newline at end of file
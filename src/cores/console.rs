@@ -3,6 +3,9 @@ use core::fmt;
 use spin::{Mutex, RwLock};
 
 use crate::driver::CharDevice;
+use crate::driver::interrupt::{set_interrupt_handler, InterruptType};
+use crate::kernel::procinfo::ps;
+use crate::kernel::syscall::{set_syscall_trace, syscall_trace};
 use crate::{define_early_init, UartDevice};
 
 pub static CONSOLE: RwLock<Option<ConsoleContext<UartDevice>>> = RwLock::new(None);
@@ -21,6 +24,34 @@ pub extern "C" fn init_console() {
 }
 define_early_init!(init_console);
 
+const fn ctrl(c: u8) -> u8 {
+    c - b'@'
+}
+
+// Handle the input on the console. There is nothing to read it yet, so only control keys do something:
+// Ctrl-P prints the processes, as in xv6, and Ctrl-T turns the tracing of syscalls on or off.
+fn console_intr() {
+    loop {
+        // The read lock must be released before printing.
+        let c = match CONSOLE.read().as_ref() {
+            Some(console) => console.device.get_char(),
+            None => return,
+        };
+        match c {
+            u8::MAX => return,
+            c if c == ctrl(b'P') => ps(),
+            c if c == ctrl(b'T') => set_syscall_trace(!syscall_trace()),
+            _ => {}
+        }
+    }
+}
+
+// Take input from the console from now on.
+// Must be called once the root process is set up, since Ctrl-P walks the process tree.
+pub fn init_console_input() {
+    set_interrupt_handler(InterruptType::IRQ_AUX, console_intr);
+}
+
 pub struct ConsoleContext<T>
     where
         T: CharDevice,
@@ -1,4 +1,5 @@
 use core::mem::{MaybeUninit, size_of};
+use core::sync::atomic::{AtomicU32, Ordering};
 use field_offset::offset_of;
 use crate::{aarch64::mmu::PAGE_SIZE, common::round_down};
 use crate::aarch64::mmu::{_kernel2physical_mut, _physical2kernel_mut};
@@ -11,6 +12,7 @@ pub struct PhysicalMemory<T>
         T: PhysicalMemoryTable,
 {
     pub table: T,
+    pub refs: PageRefs,
 }
 
 pub trait PhysicalMemoryTable {
@@ -30,6 +32,65 @@ impl<T: PhysicalMemoryTable> PhysicalMemoryTable for PhysicalMemory<T> {
     }
 }
 
+// Reference counts of physical pages, indexed by page frame number.
+// A page is shared by several page tables (e.g. copy-on-write pages after fork) when its count is above 1.
+pub struct PageRefs {
+    counts: *mut AtomicU32,
+    len: usize,
+}
+
+unsafe impl Send for PageRefs {}
+
+unsafe impl Sync for PageRefs {}
+
+impl PageRefs {
+    pub const fn uninitialized() -> Self {
+        Self {
+            counts: core::ptr::null_mut(),
+            len: 0,
+        }
+    }
+
+    // Count the pages below physical address `len * PAGE_SIZE`, using the zeroed `counts` of `len` entries.
+    pub fn init(&mut self, counts: *mut AtomicU32, len: usize) {
+        self.counts = counts;
+        self.len = len;
+    }
+
+    // The space needed to count `len` pages.
+    pub const fn size_for(len: usize) -> usize {
+        len * size_of::<AtomicU32>()
+    }
+
+    fn get(&self, page_addr: *mut u8) -> &AtomicU32 {
+        let index = page_addr as usize / PAGE_SIZE;
+        assert!(index < self.len, "PageRefs: page {:p} out of range", page_addr);
+        unsafe { &*self.counts.add(index) }
+    }
+
+    pub fn count(&self, page_addr: *mut u8) -> u32 {
+        self.get(page_addr).load(Ordering::Acquire)
+    }
+
+    pub fn set(&self, page_addr: *mut u8, count: u32) {
+        self.get(page_addr).store(count, Ordering::Release)
+    }
+
+    // Return the new count.
+    pub fn inc(&self, page_addr: *mut u8) -> u32 {
+        let old = self.get(page_addr).fetch_add(1, Ordering::AcqRel);
+        assert_ne!(old, 0, "PageRefs: sharing free page {:p}", page_addr);
+        old + 1
+    }
+
+    // Return the new count.
+    pub fn dec(&self, page_addr: *mut u8) -> u32 {
+        let old = self.get(page_addr).fetch_sub(1, Ordering::AcqRel);
+        assert_ne!(old, 0, "PageRefs: dropping free page {:p}", page_addr);
+        old - 1
+    }
+}
+
 #[repr(C)]
 struct Page {
     buddy_link: ListLink,
@@ -1,11 +1,12 @@
 #![allow(unused_variables)]
 
+use core::cmp::{max, min};
 use core::ptr;
-use crate::aarch64::intrinsic::set_ttbr0_el1;
+use crate::aarch64::intrinsic::{flush_tlb, set_ttbr0_el1};
 use crate::aarch64::kernel_pt::invalid_pt;
 use crate::aarch64::mmu::{kernel2physical, N_PTE_PER_TABLE, physical2kernel};
 use crate::common::{get_bits, set_bits};
-use crate::kernel::mem::{kalloc_page, kfree_page};
+use crate::kernel::mem::{kalloc_page, kfree_page, kput_page, kshare_page};
 
 pub mod pte_flags {
     use crate::cores::virtual_memory::{AccessPermission, PageTableEntry, PageTableEntryType, Shareability};
@@ -25,6 +26,13 @@ pub mod pte_flags {
         normal(pte);
         pte.set_type(PageTableEntryType::TableOrPage);
     }
+
+    // User pages are always readable, and never executable by the kernel.
+    pub fn user_prot(pte: &mut PageTableEntry, writable: bool, executable: bool) {
+        pte.set_access_permission(if writable { AccessPermission::El1rwEl0rw } else { AccessPermission::EL1rEL0r });
+        pte.set_execute_never(!executable);
+        pte.set_privileged_execute_never(true);
+    }
 }
 
 #[repr(transparent)]
@@ -184,6 +192,26 @@ impl PageTableEntry {
         set_bits(&mut self.0, attr_index as u64, 2, 5);
     }
 
+    /// Software-defined bit: the page is allocated for this page table, and is freed together with it.
+    pub const fn owned(&self) -> bool {
+        get_bits(self.0, 55, 56) == 1
+    }
+
+    pub const fn set_owned(&mut self, owned: bool) {
+        let owned = if owned { 1 } else { 0 };
+        set_bits(&mut self.0, owned, 55, 56);
+    }
+
+    /// Software-defined bit: the page is shared copy-on-write, so it is mapped read-only and must be copied before written.
+    pub const fn cow(&self) -> bool {
+        get_bits(self.0, 56, 57) == 1
+    }
+
+    pub const fn set_cow(&mut self, cow: bool) {
+        let cow = if cow { 1 } else { 0 };
+        set_bits(&mut self.0, cow, 56, 57);
+    }
+
     pub fn free(&mut self, level: u8) {
         if self.valid() {
             if level < 3 && matches!(self.type_(), PageTableEntryType::TableOrPage) {
@@ -193,14 +221,88 @@ impl PageTableEntry {
                     entry.free(level + 1);
                 }
                 kfree_page(self.kernel_addr(level) as *mut u8, 1);
+            } else if level == 3 && self.owned() {
+                // The page may still be shared with other page tables.
+                kput_page(self.kernel_addr(level) as *mut u8);
             }
             self.set_valid(false);
         }
     }
+
+    /// The number of pages mapped by this entry.
+    pub fn count_pages(&self, level: u8) -> usize {
+        if !self.valid() {
+            return 0;
+        }
+        if level == 3 || !matches!(self.type_(), PageTableEntryType::TableOrPage) {
+            // A page, or a block of pages.
+            return 1 << (9 * (3 - level));
+        }
+        let table = unsafe { &*(self.kernel_addr(level) as *const PageTable) };
+        table.iter().map(|entry| entry.count_pages(level + 1)).sum()
+    }
+
+    /// Copy the mapping into `dst`, duplicating sub page tables on the way.
+    /// Pages owned by `self` become shared with `dst` copy-on-write, and both are mapped read-only.
+    /// Other pages are simply mapped in `dst` too.
+    pub fn copy_to(&mut self, dst: &mut PageTableEntry, level: u8) {
+        if level == 3 && self.valid() && self.owned() {
+            if matches!(self.access_permission(), AccessPermission::El1rwEl0rw) {
+                self.set_access_permission(AccessPermission::EL1rEL0r);
+            }
+            // Even a read-only page must be copied, if it is made writable later.
+            self.set_cow(true);
+            kshare_page(self.kernel_addr(level) as *mut u8);
+        }
+        dst.0 = self.0;
+        if level == 3 || !self.valid() || !matches!(self.type_(), PageTableEntryType::TableOrPage) {
+            return;
+        }
+        let page = kalloc_page(1);
+        let src_table = unsafe { &mut *(self.kernel_addr(level) as *mut PageTable) };
+        let dst_table = unsafe { &mut *(page as *mut PageTable) };
+        for (src, dst) in src_table.iter_mut().zip(dst_table.iter_mut()) {
+            src.copy_to(dst, level + 1);
+        }
+        dst.set_addr(kernel2physical(page as u64) as usize, level);
+    }
 }
 
 pub type PageTable = [PageTableEntry; N_PTE_PER_TABLE];
 
+/// The number of bytes mapped by an entry of a page table at `level`.
+const fn entry_span(level: u8) -> usize {
+    1 << (12 + 9 * (3 - level))
+}
+
+/// Call `f` on every valid last-level entry of `table`, a page table at `level` mapping from `base`,
+/// that maps an address in [start, end). Entries without a sub page table are skipped at once, with all
+/// the addresses they would map. If `free_empty`, the sub page tables left empty by `f` are freed.
+fn for_each_page_in(table: &mut PageTable, level: u8, base: usize, start: usize, end: usize, free_empty: bool,
+                    f: &mut impl FnMut(&mut PageTableEntry)) {
+    let span = entry_span(level);
+    for index in (start - base) / span..=(end - 1 - base) / span {
+        let entry = &mut table[index];
+        if !entry.valid() {
+            continue;
+        }
+        if level == 3 {
+            f(entry);
+            continue;
+        }
+        if !matches!(entry.type_(), PageTableEntryType::TableOrPage) {
+            continue;
+        }
+        let entry_base = base + index * span;
+        let sub_table = unsafe { &mut *(entry.kernel_addr(level) as *mut PageTable) };
+        for_each_page_in(sub_table, level + 1, entry_base, max(start, entry_base), min(end, entry_base + span),
+                         free_empty, f);
+        if free_empty && sub_table.iter().all(|entry| !entry.valid()) {
+            entry.free(level);
+        }
+    }
+}
+
 #[repr(transparent)]
 pub struct FourLevelVirtualAddress(pub u64);
 
@@ -245,6 +347,36 @@ impl PageTableDirectory {
     pub fn get_page_table(&self) -> &mut PageTable {
         unsafe { &mut *self.page_table }
     }
+
+    /// The number of user pages mapped, shared or not.
+    pub fn count_pages(&self) -> usize {
+        if self.page_table.is_null() {
+            return 0;
+        }
+        self.get_page_table().iter().map(|entry| entry.count_pages(0)).sum()
+    }
+
+    /// Call `f` on the entry of every page mapped in [start, end), skipping the parts with no page table.
+    pub fn for_each_page(&mut self, start: usize, end: usize, mut f: impl FnMut(&mut PageTableEntry)) {
+        if start < end {
+            for_each_page_in(self.get_page_table(), 0, 0, start, end, false, &mut f);
+        }
+    }
+
+    /// Unmap every page in [start, end), put the pages owned, and free the sub page tables left empty.
+    pub fn unmap(&mut self, start: usize, end: usize) {
+        if start < end {
+            for_each_page_in(self.get_page_table(), 0, 0, start, end, true, &mut |entry| entry.free(3));
+        }
+    }
+
+    /// Make this (empty) directory a copy of `src`, sharing its pages copy-on-write.
+    /// `src` is modified too, so it must be re-attached if it is in use.
+    pub fn copy_from(&mut self, src: &PageTableDirectory) {
+        for (src, dst) in src.get_page_table().iter_mut().zip(self.get_page_table().iter_mut()) {
+            src.copy_to(dst, 0);
+        }
+    }
 }
 
 impl VirtualMemoryPageTable for PageTableDirectory {
@@ -292,6 +424,9 @@ impl VirtualMemoryPageTable for PageTableDirectory {
     /// Free the page table and its sub page tables.
     /// The page pointed by the page table directory will NOT be freed.
     ///
+    /// The user mappings should be removed by `VmaTree::clear` first, which frees the sub page tables of
+    /// each VMA as it unmaps it, so only the root table and what was mapped outside any VMA are left here.
+    ///
     /// Note: This function should be called when the page table is not used anymore,
     /// and the page table should be detached before calling this function.
     ///
@@ -309,5 +444,7 @@ impl VirtualMemoryPageTable for PageTableDirectory {
         } else {
             set_ttbr0_el1(kernel2physical(self.page_table as u64));
         }
+        // We do not use ASIDs, so entries of the previous page table must be dropped.
+        flush_tlb();
     }
 }
\ No newline at end of file
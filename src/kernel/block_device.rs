@@ -0,0 +1,109 @@
+use core::mem::MaybeUninit;
+use core::ptr;
+use file_system::block_device::BlockDevice;
+use file_system::cache::{init_bcache, SCACHE};
+use file_system::defines::{BLOCK_SIZE, SuperBlock};
+use file_system::inode::init_inodes;
+use file_system::sync::set_yield_hook;
+use crate::kernel::errno::Errno::{self, EINVAL, ENODEV};
+use crate::kernel::sched::yield_;
+use crate::kernel::sd::{Buffer, get_partitions, sd_rw};
+use crate::println;
+
+// A partition of SD card. Block numbers are relative to the start of the partition,
+// and accessing blocks out of it panics.
+pub struct SdBlockDevice {
+    start: usize,
+    size: usize,
+}
+
+impl SdBlockDevice {
+    pub const fn new(start: usize, size: usize) -> Self {
+        Self { start, size }
+    }
+
+    fn translate(&self, block_no: usize) -> u32 {
+        assert!(block_no < self.size, "SdBlockDevice: block {} out of partition of {} blocks", block_no, self.size);
+        (self.start + block_no) as u32
+    }
+}
+
+// How many times a request is submitted before we give up on the block. The driver restarts a failed transfer a
+// few times by itself, but a request may still fail while the card is being reset.
+const SD_TRIES: usize = 3;
+
+// Make the request `rw` until it succeeds, logging each failure.
+// The block cache cannot report an error to its callers, so we panic if the last try fails as well.
+fn retry(block_no: usize, what: &str, mut rw: impl FnMut() -> Result<(), u32>) {
+    for tries in 1..=SD_TRIES {
+        match rw() {
+            Ok(()) => return,
+            Err(code) => println!("SdBlockDevice: failed to {} block {} ({}/{}): {}", what, block_no, tries, SD_TRIES, code),
+        }
+    }
+    panic!("SdBlockDevice: cannot {} block {}", what, block_no);
+}
+
+impl BlockDevice for SdBlockDevice {
+    fn write(&mut self, block_no: usize, buf: &[u8]) {
+        let sector = self.translate(block_no);
+        retry(block_no, "write", || {
+            let mut b = Buffer::write_uninit(sector);
+            b.init();
+            b.data.copy_from_slice(&buf[..BLOCK_SIZE]);
+            sd_rw(&mut b)
+        });
+    }
+
+    fn read(&mut self, block_no: usize, buf: &mut [u8]) {
+        let sector = self.translate(block_no);
+        retry(block_no, "read", || {
+            let mut b = Buffer::read_uninit(sector);
+            b.init();
+            sd_rw(&mut b)?;
+            buf[..BLOCK_SIZE].copy_from_slice(&b.data);
+            Ok(())
+        });
+    }
+}
+
+static mut DEVICE: MaybeUninit<SdBlockDevice> = MaybeUninit::uninit();
+// Set once the file system is mounted.
+static mut SBLOCK: Option<SuperBlock> = None;
+
+// Mount the file system on the first Linux data partition, i.e. read its super block and
+// set up the block cache (replaying the log if needed) and the inode layer.
+//
+// Fail with ENODEV if there is no such partition, or with EINVAL if its super block does not describe a valid
+// layout fitting in it.
+// Nothing is set up then, so the kernel runs on without a file system.
+//
+// Must be called after `init_sd`, in a context which can sleep.
+pub fn init_block_device() -> Result<(), Errno> {
+    let partition = get_partitions().iter().find(|p| p.is_linux_data()).ok_or(ENODEV)?;
+    let mut device = partition.device();
+    let mut buf = [0u8; BLOCK_SIZE];
+    device.read(1, &mut buf);
+    let sblock: SuperBlock = unsafe { ptr::read_unaligned(buf.as_ptr() as *const SuperBlock) };
+    if sblock.num_blocks as usize > device.size {
+        return Err(EINVAL);
+    }
+    if let Err(reason) = sblock.check_layout() {
+        println!("init_block_device: bad super block: {}", reason);
+        return Err(EINVAL);
+    }
+
+    set_yield_hook(yield_);
+    unsafe {
+        DEVICE = MaybeUninit::new(device);
+        init_bcache(&sblock, DEVICE.assume_init_mut());
+        init_inodes(&sblock, &mut SCACHE);
+        SBLOCK = Some(sblock);
+    }
+    Ok(())
+}
+
+// The super block of the mounted file system, or `None` if `init_block_device` failed.
+pub fn get_super_block() -> Option<&'static SuperBlock> {
+    unsafe { SBLOCK.as_ref() }
+}
@@ -133,7 +133,8 @@ pub fn get_cpu_info() -> &'static mut CPU {
     unsafe { &mut CPUS[get_cpu_id()] }
 }
 
-pub unsafe fn get_cpu_info_ref(cpu_id: usize) -> &'static CPU {
+// Get another CPU's Info for modification, which is only safe when both CPUs agree on a lock, e.g. the lock of its run queue.
+pub unsafe fn get_cpu_info_mut(cpu_id: usize) -> &'static mut CPU {
     &mut CPUS[cpu_id]
 }
 
@@ -0,0 +1,103 @@
+/*
+ * ELF64 executables.
+ *
+ * See https://refspecs.linuxfoundation.org/elf/gabi4+/ch4.eheader.html
+ * and https://refspecs.linuxfoundation.org/elf/gabi4+/ch5.pheader.html
+ */
+pub const ELF_MAGIC: [u8; 4] = *b"\x7fELF";
+pub const ELF_CLASS_64: u8 = 2;
+pub const ELF_DATA_LSB: u8 = 1;
+pub const ELF_VERSION_CURRENT: u8 = 1;
+pub const ELF_TYPE_EXEC: u16 = 2;
+pub const ELF_MACHINE_AARCH64: u16 = 183;
+
+pub const ELF_HEADER_SIZE: usize = 64;
+pub const PROGRAM_HEADER_SIZE: usize = 56;
+// Refuse to read unreasonably many program headers from a corrupted header.
+pub const PROGRAM_HEADER_MAX_NUM: usize = 64;
+
+pub const PT_LOAD: u32 = 1;
+
+pub const PF_X: u32 = 1;
+pub const PF_W: u32 = 2;
+pub const PF_R: u32 = 4;
+
+fn read_u16(buf: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes(buf[offset..offset + 2].try_into().unwrap())
+}
+
+fn read_u32(buf: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(buf[offset..offset + 4].try_into().unwrap())
+}
+
+fn read_u64(buf: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(buf[offset..offset + 8].try_into().unwrap())
+}
+
+#[derive(Debug)]
+pub struct ElfHeader {
+    pub entry: u64,
+    pub ph_offset: u64,
+    pub ph_num: u16,
+}
+
+impl ElfHeader {
+    // Parse a header and check that it describes a little-endian AArch64 executable we can load.
+    // Return `None` if it does not.
+    pub fn parse(buf: &[u8]) -> Option<Self> {
+        if buf.len() < ELF_HEADER_SIZE || buf[0..4] != ELF_MAGIC {
+            return None;
+        }
+        if buf[4] != ELF_CLASS_64 || buf[5] != ELF_DATA_LSB || buf[6] != ELF_VERSION_CURRENT {
+            return None;
+        }
+        if read_u16(buf, 16) != ELF_TYPE_EXEC || read_u16(buf, 18) != ELF_MACHINE_AARCH64 {
+            return None;
+        }
+        let this = Self {
+            entry: read_u64(buf, 24),
+            ph_offset: read_u64(buf, 32),
+            ph_num: read_u16(buf, 56),
+        };
+        let ph_entry_size = read_u16(buf, 54) as usize;
+        if ph_entry_size != PROGRAM_HEADER_SIZE || this.ph_num == 0 || this.ph_num as usize > PROGRAM_HEADER_MAX_NUM {
+            return None;
+        }
+        Some(this)
+    }
+
+    pub fn ph_size(&self) -> usize {
+        self.ph_num as usize * PROGRAM_HEADER_SIZE
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ProgramHeader {
+    pub typ: u32,
+    pub flags: u32,
+    pub offset: u64,
+    pub vaddr: u64,
+    pub file_size: u64,
+    pub mem_size: u64,
+}
+
+impl ProgramHeader {
+    pub fn parse(buf: &[u8]) -> Self {
+        Self {
+            typ: read_u32(buf, 0),
+            flags: read_u32(buf, 4),
+            offset: read_u64(buf, 8),
+            vaddr: read_u64(buf, 16),
+            file_size: read_u64(buf, 32),
+            mem_size: read_u64(buf, 40),
+        }
+    }
+
+    pub fn writable(&self) -> bool {
+        self.flags & PF_W != 0
+    }
+
+    pub fn executable(&self) -> bool {
+        self.flags & PF_X != 0
+    }
+}
@@ -0,0 +1,46 @@
+/*
+ * Error numbers, the same as Linux.
+ *
+ * A syscall returns `SyscallResult`. On failure, user space gets the negated number in x0,
+ * i.e. a value in [-4095, -1], as on Linux.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(i32)]
+pub enum Errno {
+    EPERM = 1,
+    ENOENT = 2,
+    ESRCH = 3,
+    EINTR = 4,
+    E2BIG = 7,
+    ENOEXEC = 8,
+    EBADF = 9,
+    ECHILD = 10,
+    EAGAIN = 11,
+    ENOMEM = 12,
+    EACCES = 13,
+    EFAULT = 14,
+    EEXIST = 17,
+    ENODEV = 19,
+    EINVAL = 22,
+    ENOTTY = 25,
+    ENOSPC = 28,
+    ENOSYS = 38,
+    ENOMSG = 42,
+    EIDRM = 43,
+}
+
+pub type SyscallResult = Result<u64, Errno>;
+
+impl Errno {
+    // The value of x0 returned to user space.
+    pub fn as_return_value(self) -> u64 {
+        -(self as i64) as u64
+    }
+}
+
+pub fn encode_result(result: SyscallResult) -> u64 {
+    match result {
+        Ok(ret) => ret,
+        Err(err) => err.as_return_value(),
+    }
+}
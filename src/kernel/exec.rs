@@ -0,0 +1,275 @@
+use alloc::vec;
+use alloc::vec::Vec;
+use core::cmp::max;
+use core::{ptr, slice};
+use file_system::defines::INODE_REGULAR;
+use file_system::inode::{Inode, InodeTree, INODES};
+use file_system::path::namei;
+use crate::aarch64::mmu::PAGE_SIZE;
+use crate::cores::virtual_memory::{PageTableDirectory, VirtualMemoryPageTable};
+use crate::define_syscall;
+use crate::kernel::elf::{ElfHeader, PROGRAM_HEADER_SIZE, ProgramHeader, PT_LOAD, ELF_HEADER_SIZE};
+use crate::kernel::proc::Process;
+use crate::kernel::sched::thisproc;
+use crate::kernel::syscall::SYS_EXECVE;
+use crate::kernel::errno::Errno::{self, E2BIG, EACCES, EFAULT, ENOENT, ENOEXEC};
+use crate::kernel::errno::SyscallResult;
+use crate::kernel::uaccess::{copy_out, user_page, UserPtr};
+use crate::kernel::vm::{align_up, Backing, PROT_EXEC, PROT_READ, PROT_WRITE, Vma, VmaTree};
+
+// Layout of the user address space. The stack grows down from `USER_STACK_TOP`,
+// and program segments must stay below the stack.
+pub const USER_STACK_TOP: usize = 1 << 47;
+pub const USER_STACK_SIZE: usize = 16 * PAGE_SIZE;
+
+// Limits of the arguments and environment passed to a new program.
+pub const MAX_ARG_NUM: usize = 64;
+pub const MAX_ARG_STRLEN: usize = PAGE_SIZE;
+
+// Types of the auxiliary vector entries.
+const AT_NULL: u64 = 0;
+const AT_PHDR: u64 = 3;
+const AT_PHENT: u64 = 4;
+const AT_PHNUM: u64 = 5;
+const AT_PAGESZ: u64 = 6;
+const AT_ENTRY: u64 = 9;
+
+// Read the NUL-terminated string at the user address `addr` of `pgdir`, without the NUL.
+fn fetch_str(pgdir: &mut PageTableDirectory, vmas: &mut VmaTree, addr: usize) -> Result<Vec<u8>, Errno> {
+    let mut s = Vec::new();
+    loop {
+        let addr = addr + s.len();
+        let offset = addr % PAGE_SIZE;
+        let page = user_page(pgdir, vmas, addr, false)?;
+        for &c in unsafe { slice::from_raw_parts(page.add(offset), PAGE_SIZE - offset) } {
+            if c == 0 {
+                return Ok(s);
+            }
+            if s.len() == MAX_ARG_STRLEN {
+                return Err(E2BIG);
+            }
+            s.push(c);
+        }
+    }
+}
+
+// Read the NULL-terminated array of strings at the user address `addr` of `pgdir`.
+// A NULL `addr` is taken as an empty array.
+fn fetch_str_array(pgdir: &mut PageTableDirectory, vmas: &mut VmaTree, addr: usize) -> Result<Vec<Vec<u8>>, Errno> {
+    let mut strs = Vec::new();
+    if addr == 0 {
+        return Ok(strs);
+    }
+    if addr % 8 != 0 {
+        return Err(EFAULT);
+    }
+    loop {
+        let addr = addr + strs.len() * 8;
+        let page = user_page(pgdir, vmas, addr, false)?;
+        let s = unsafe { (page.add(addr % PAGE_SIZE) as *const u64).read() };
+        if s == 0 {
+            return Ok(strs);
+        }
+        if strs.len() == MAX_ARG_NUM {
+            return Err(E2BIG);
+        }
+        strs.push(fetch_str(pgdir, vmas, s as usize)?);
+    }
+}
+
+// Map the segment described by `ph` into `vmas`. Its pages are read from the file on the first access.
+unsafe fn load_segment(vmas: &mut VmaTree, inode: *mut Inode, ph: &ProgramHeader) -> Result<(), Errno> {
+    let file_end = ph.offset.checked_add(ph.file_size).ok_or(ENOEXEC)?;
+    let mem_end = ph.vaddr.checked_add(ph.mem_size).ok_or(ENOEXEC)?;
+    if ph.file_size > ph.mem_size
+        || file_end > (*inode).entry.num_bytes as u64
+        || mem_end > (USER_STACK_TOP - USER_STACK_SIZE) as u64 {
+        return Err(ENOEXEC);
+    }
+    if ph.mem_size == 0 {
+        return Ok(());
+    }
+    let (vaddr, file_size) = (ph.vaddr as usize, ph.file_size as usize);
+    let (start, end) = (vaddr & !(PAGE_SIZE - 1), align_up(mem_end as usize));
+    // Segments sharing a page would need the union of their permissions, which we do not support.
+    if !vmas.is_free(start, end) {
+        return Err(ENOEXEC);
+    }
+    let mut prot = PROT_READ;
+    if ph.writable() {
+        prot |= PROT_WRITE;
+    }
+    if ph.executable() {
+        prot |= PROT_EXEC;
+    }
+    vmas.insert(Vma::new(start, end, prot, Backing::File {
+        inode: INODES.share(inode),
+        offset: ph.offset as usize,
+        data_start: vaddr,
+        data_end: vaddr + file_size,
+    }));
+    Ok(())
+}
+
+// Set up the user stack region, and push the strings, argc, argv, envp and auxv on it like Linux does:
+//
+// sp -> argc, argv[0], ..., NULL, envp[0], ..., NULL, auxv pairs, AT_NULL pair, padding, strings <- USER_STACK_TOP
+//
+// Return the initial stack pointer.
+fn setup_stack(pgdir: &mut PageTableDirectory, vmas: &mut VmaTree, argv: &[&[u8]], envp: &[&[u8]], auxv: &[(u64, u64)]) -> Result<usize, Errno> {
+    let strs_size: usize = argv.iter().chain(envp).map(|s| s.len() + 1).sum();
+    let num_words = 1 + argv.len() + 1 + envp.len() + 1 + 2 * (auxv.len() + 1);
+    let size = strs_size + num_words * 8 + 16;
+    // Leave at least one page for the program itself.
+    if size > USER_STACK_SIZE - PAGE_SIZE {
+        return Err(E2BIG);
+    }
+    // Only the pages written below are allocated now. The rest is mapped on demand.
+    vmas.insert(Vma::new(USER_STACK_TOP - USER_STACK_SIZE, USER_STACK_TOP, PROT_READ | PROT_WRITE, Backing::Anonymous));
+
+    let mut sp = USER_STACK_TOP;
+    let mut addrs = Vec::with_capacity(argv.len() + envp.len());
+    for s in argv.iter().chain(envp) {
+        sp -= s.len() + 1;
+        copy_out(pgdir, vmas, sp, s)?;
+        copy_out(pgdir, vmas, sp + s.len(), &[0])?;
+        addrs.push(sp as u64);
+    }
+    let mut words = Vec::with_capacity(num_words);
+    words.push(argv.len() as u64);
+    words.extend_from_slice(&addrs[..argv.len()]);
+    words.push(0);
+    words.extend_from_slice(&addrs[argv.len()..]);
+    words.push(0);
+    for &(typ, value) in auxv.iter().chain(&[(AT_NULL, 0)]) {
+        words.push(typ);
+        words.push(value);
+    }
+
+    sp = (sp - words.len() * 8) & !15;
+    let bytes = unsafe { slice::from_raw_parts(words.as_ptr() as *const u8, words.len() * 8) };
+    copy_out(pgdir, vmas, sp, bytes)?;
+    Ok(sp)
+}
+
+// Load the executable `inode` (locked) into the empty `pgdir` and `vmas`.
+// Return the entry point, the stack pointer and the program break, which is right after the last segment.
+unsafe fn load(pgdir: &mut PageTableDirectory, vmas: &mut VmaTree, inode: *mut Inode, argv: &[&[u8]], envp: &[&[u8]]) -> Result<(usize, usize, usize), Errno> {
+    if (*inode).entry.typ != INODE_REGULAR {
+        return Err(EACCES);
+    }
+    let file_size = (*inode).entry.num_bytes as u64;
+    let mut buf = [0u8; ELF_HEADER_SIZE];
+    if INODES.read(inode, &mut buf, 0) != buf.len() {
+        return Err(ENOEXEC);
+    }
+    let header = ElfHeader::parse(&buf).ok_or(ENOEXEC)?;
+    let ph_end = header.ph_offset.checked_add(header.ph_size() as u64).ok_or(ENOEXEC)?;
+    if ph_end > file_size {
+        return Err(ENOEXEC);
+    }
+    let mut buf = vec![0u8; header.ph_size()];
+    if INODES.read(inode, &mut buf, header.ph_offset as usize) != buf.len() {
+        return Err(ENOEXEC);
+    }
+
+    let mut brk = 0;
+    let mut phdr_addr = None;
+    for ph in buf.chunks_exact(PROGRAM_HEADER_SIZE).map(ProgramHeader::parse).filter(|ph| ph.typ == PT_LOAD) {
+        load_segment(vmas, inode, &ph)?;
+        brk = max(brk, align_up((ph.vaddr + ph.mem_size) as usize));
+        // Tell the program where its program headers are, if they are loaded.
+        if ph.offset <= header.ph_offset && ph_end <= ph.offset + ph.file_size {
+            phdr_addr = Some(ph.vaddr + header.ph_offset - ph.offset);
+        }
+    }
+    // The entry point must be in an executable segment.
+    let entry = header.entry as usize;
+    if brk == 0 || !vmas.find(entry).map_or(false, |vma| vma.executable()) {
+        return Err(ENOEXEC);
+    }
+
+    let mut auxv = Vec::new();
+    if let Some(addr) = phdr_addr {
+        auxv.push((AT_PHDR, addr));
+    }
+    auxv.push((AT_PHENT, PROGRAM_HEADER_SIZE as u64));
+    auxv.push((AT_PHNUM, header.ph_num as u64));
+    auxv.push((AT_PAGESZ, PAGE_SIZE as u64));
+    auxv.push((AT_ENTRY, header.entry));
+    let sp = setup_stack(pgdir, vmas, argv, envp, &auxv)?;
+    Ok((entry, sp, brk))
+}
+
+// Replace the address space of `proc` with the executable `inode`, and reset its user context
+// to start the program. `proc` is either the current process or one not started yet.
+//
+// The new address space is built aside, so `proc` is left untouched if it fails.
+pub fn exec_inode(proc: &mut Process, inode: *mut Inode, argv: &[&[u8]], envp: &[&[u8]]) -> Result<(), Errno> {
+    if argv.len() + envp.len() > MAX_ARG_NUM {
+        return Err(E2BIG);
+    }
+    let mut pgdir = PageTableDirectory::new();
+    let mut vmas = VmaTree::uninit();
+    vmas.init();
+    let result = unsafe {
+        INODES.lock(inode);
+        let result = load(&mut pgdir, &mut vmas, inode, argv, envp);
+        INODES.unlock(inode);
+        result
+    };
+    let (entry, sp, brk) = match result {
+        Ok(result) => result,
+        Err(err) => {
+            vmas.clear(&mut pgdir);
+            pgdir.free();
+            return Err(err);
+        }
+    };
+
+    core::mem::swap(&mut proc.pgdir, &mut pgdir);
+    core::mem::swap(&mut proc.vmas, &mut vmas);
+    proc.brk_start = brk;
+    proc.brk = brk;
+    proc.signal.reset_handlers();
+    let context = unsafe { &mut *proc.user_context };
+    context.fp = 0;
+    context.lr = 0;
+    context.sp_el0 = sp as u64;
+    context.spsr_el1 = 0;
+    context.elr_el1 = entry as u64;
+    context.q = [0.0; 64];
+    context.x = [0; 32];
+    if ptr::eq(proc, thisproc()) {
+        proc.pgdir.attach();
+    }
+    // Now free the old address space.
+    vmas.clear(&mut pgdir);
+    pgdir.free();
+    Ok(())
+}
+
+// Run the executable at `path` in the current process.
+pub fn exec(path: &str, argv: &[&[u8]], envp: &[&[u8]]) -> Result<(), Errno> {
+    let inode = namei(path, ptr::null_mut(), ptr::null_mut()).ok_or(ENOENT)?;
+    let result = exec_inode(thisproc(), inode, argv, envp);
+    unsafe {
+        INODES.put(ptr::null_mut(), inode);
+    }
+    result
+}
+
+// On success, it does not return to the caller; the new program starts with all registers zeroed.
+define_syscall!(SYS_EXECVE, fn sys_execve(path: UserPtr<u8>, argv: UserPtr<u64>, envp: UserPtr<u64>) -> SyscallResult {
+    // Copy everything into the kernel first, since the old address space is gone once we succeed.
+    let proc = thisproc();
+    let (pgdir, vmas) = (&mut proc.pgdir, &mut proc.vmas);
+    let path = fetch_str(pgdir, vmas, path.addr())?;
+    let argv = fetch_str_array(pgdir, vmas, argv.addr())?;
+    let envp = fetch_str_array(pgdir, vmas, envp.addr())?;
+    let path = core::str::from_utf8(&path).map_err(|_| ENOENT)?;
+    let argv: Vec<&[u8]> = argv.iter().map(|s| s.as_slice()).collect();
+    let envp: Vec<&[u8]> = envp.iter().map(|s| s.as_slice()).collect();
+    exec(path, &argv, &envp)?;
+    Ok(0)
+});
@@ -0,0 +1,156 @@
+use alloc::vec::Vec;
+use core::fmt::{Debug, Display, Formatter};
+
+/*
+ * GUID Partition Table.
+ *
+ * See https://en.wikipedia.org/wiki/GUID_Partition_Table
+ */
+pub const GPT_SIGNATURE: [u8; 8] = *b"EFI PART";
+pub const GPT_HEADER_MIN_SIZE: usize = 92;
+pub const GPT_ENTRY_MIN_SIZE: usize = 128;
+// Refuse to read unreasonably large entry arrays from a corrupted header.
+pub const GPT_ENTRIES_MAX_SIZE: usize = 1 << 20;
+
+// A GUID as stored on disk, i.e. the first three fields are little-endian.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct Guid(pub [u8; 16]);
+
+impl Guid {
+    pub const ZERO: Self = Self([0; 16]);
+    // 0FC63DAF-8483-4772-8E79-3D69D8477DE4
+    pub const LINUX_FILESYSTEM: Self = Self([
+        0xAF, 0x3D, 0xC6, 0x0F, 0x83, 0x84, 0x72, 0x47, 0x8E, 0x79, 0x3D, 0x69, 0xD8, 0x47, 0x7D, 0xE4,
+    ]);
+    // C12A7328-F81F-11D2-BA4B-00A0C93EC93B
+    pub const EFI_SYSTEM: Self = Self([
+        0x28, 0x73, 0x2A, 0xC1, 0x1F, 0xF8, 0xD2, 0x11, 0xBA, 0x4B, 0x00, 0xA0, 0xC9, 0x3E, 0xC9, 0x3B,
+    ]);
+
+    pub fn parse(buf: &[u8]) -> Self {
+        let mut guid = [0; 16];
+        guid.copy_from_slice(&buf[..16]);
+        Self(guid)
+    }
+}
+
+impl Display for Guid {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        let b = &self.0;
+        write!(
+            f,
+            "{:08X}-{:04X}-{:04X}-{:02X}{:02X}-{:02X}{:02X}{:02X}{:02X}{:02X}{:02X}",
+            u32::from_le_bytes([b[0], b[1], b[2], b[3]]),
+            u16::from_le_bytes([b[4], b[5]]),
+            u16::from_le_bytes([b[6], b[7]]),
+            b[8], b[9], b[10], b[11], b[12], b[13], b[14], b[15]
+        )
+    }
+}
+
+impl Debug for Guid {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        Display::fmt(self, f)
+    }
+}
+
+// CRC-32 (IEEE 802.3, reflected, polynomial 0xEDB88320) used by GPT.
+pub fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for byte in data {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            crc = (crc >> 1) ^ (0xEDB8_8320 & (crc & 1).wrapping_neg());
+        }
+    }
+    !crc
+}
+
+fn read_u32(buf: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(buf[offset..offset + 4].try_into().unwrap())
+}
+
+fn read_u64(buf: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(buf[offset..offset + 8].try_into().unwrap())
+}
+
+#[derive(Debug)]
+pub struct GPTHeader {
+    pub current_lba: u64,
+    pub first_usable_lba: u64,
+    pub last_usable_lba: u64,
+    pub entries_lba: u64,
+    pub num_entries: u32,
+    pub entry_size: u32,
+    pub entries_crc32: u32,
+}
+
+impl GPTHeader {
+    // Parse a header and check its signature, size and CRC. Return `None` if it is not valid.
+    pub fn parse(buf: &[u8]) -> Option<Self> {
+        if buf[0..8] != GPT_SIGNATURE {
+            return None;
+        }
+        let header_size = read_u32(buf, 12) as usize;
+        if !(GPT_HEADER_MIN_SIZE..=buf.len()).contains(&header_size) {
+            return None;
+        }
+        // The CRC is calculated with the CRC field itself zeroed.
+        let mut header = [0u8; 512];
+        header[..header_size].copy_from_slice(&buf[..header_size]);
+        header[16..20].fill(0);
+        if crc32(&header[..header_size]) != read_u32(buf, 16) {
+            return None;
+        }
+        let this = Self {
+            current_lba: read_u64(buf, 24),
+            first_usable_lba: read_u64(buf, 40),
+            last_usable_lba: read_u64(buf, 48),
+            entries_lba: read_u64(buf, 72),
+            num_entries: read_u32(buf, 80),
+            entry_size: read_u32(buf, 84),
+            entries_crc32: read_u32(buf, 88),
+        };
+        let entry_size = this.entry_size as usize;
+        if entry_size < GPT_ENTRY_MIN_SIZE || entry_size % 8 != 0 || this.entries_size() > GPT_ENTRIES_MAX_SIZE {
+            return None;
+        }
+        Some(this)
+    }
+
+    pub fn entries_size(&self) -> usize {
+        self.num_entries as usize * self.entry_size as usize
+    }
+
+    // Parse the entry array read from `entries_lba`. Return `None` if its CRC does not match.
+    // Unused entries are skipped; the index of each entry in the array is kept.
+    pub fn parse_entries(&self, buf: &[u8]) -> Option<Vec<(usize, GPTEntry)>> {
+        let buf = &buf[..self.entries_size()];
+        if crc32(buf) != self.entries_crc32 {
+            return None;
+        }
+        Some(buf.chunks_exact(self.entry_size as usize)
+            .map(GPTEntry::parse)
+            .enumerate()
+            .filter(|(_, entry)| entry.type_guid != Guid::ZERO)
+            .collect())
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct GPTEntry {
+    pub type_guid: Guid,
+    pub first_lba: u64,
+    // Inclusive.
+    pub last_lba: u64,
+}
+
+impl GPTEntry {
+    pub fn parse(buf: &[u8]) -> Self {
+        Self {
+            type_guid: Guid::parse(&buf[0..16]),
+            first_lba: read_u64(buf, 32),
+            last_lba: read_u64(buf, 40),
+        }
+    }
+}
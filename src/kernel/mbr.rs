@@ -1,23 +1,40 @@
+// Partition types we care about. See https://en.wikipedia.org/wiki/Partition_type
+pub const PART_TYPE_EMPTY: u8 = 0x00;
+pub const PART_TYPE_EXTENDED_CHS: u8 = 0x05;
+pub const PART_TYPE_FAT32_LBA: u8 = 0x0C;
+pub const PART_TYPE_EXTENDED_LBA: u8 = 0x0F;
+pub const PART_TYPE_LINUX: u8 = 0x83;
+pub const PART_TYPE_LINUX_EXTENDED: u8 = 0x85;
+pub const PART_TYPE_GPT_PROTECTIVE: u8 = 0xEE;
+
+pub const MBR_SIGNATURE: [u8; 2] = [0x55, 0xAA];
+
 #[derive(Debug)]
 pub struct MBR {
     pub partitions: [MBRPartition; 4],
 }
 
 impl MBR {
-    pub fn parse(buf: &[u8]) -> Self {
-        Self {
+    // Parse a MBR (or an EBR, which has the same layout). Return `None` if the boot signature is missing.
+    pub fn parse(buf: &[u8]) -> Option<Self> {
+        if buf[0x1FE..0x200] != MBR_SIGNATURE {
+            return None;
+        }
+        Some(Self {
             partitions: [
                 MBRPartition::parse(&buf[0x1BE..0x1CE]),
                 MBRPartition::parse(&buf[0x1CE..0x1DE]),
                 MBRPartition::parse(&buf[0x1DE..0x1EE]),
                 MBRPartition::parse(&buf[0x1EE..0x1FE]),
             ],
-        }
+        })
     }
 }
-#[derive(Debug)]
+
+#[derive(Debug, Clone)]
 pub struct MBRPartition {
     pub bootable: bool,
+    pub typ: u8,
     pub start: u32,
     pub size: u32,
 }
@@ -25,12 +42,22 @@ pub struct MBRPartition {
 impl MBRPartition {
     pub fn parse(buf: &[u8]) -> Self {
         let bootable = buf[0] != 0;
+        let typ = buf[0x4];
         let start = u32::from_le_bytes([buf[0x8], buf[0x9], buf[0xA], buf[0xB]]);
         let size = u32::from_le_bytes([buf[0xC], buf[0xD], buf[0xE], buf[0xF]]);
         Self {
             bootable,
+            typ,
             start,
             size,
         }
     }
+
+    pub fn is_empty(&self) -> bool {
+        self.typ == PART_TYPE_EMPTY || self.size == 0
+    }
+
+    pub fn is_extended(&self) -> bool {
+        matches!(self.typ, PART_TYPE_EXTENDED_CHS | PART_TYPE_EXTENDED_LBA | PART_TYPE_LINUX_EXTENDED)
+    }
 }
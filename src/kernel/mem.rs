@@ -1,7 +1,10 @@
-use core::sync::atomic::AtomicUsize;
+use core::ptr;
+use core::sync::atomic::{AtomicU32, AtomicUsize};
 use spin::RwLock;
 use crate::aarch64::mmu::{_kernel2physical_mut, _physical2kernel_mut, kernel2physical, PHYSICAL_TOP};
-use crate::cores::physical_memory::{BuddyPageAllocation, PhysicalMemory, PhysicalMemoryTable};
+use crate::aarch64::mmu::PAGE_SIZE;
+use crate::common::round_up;
+use crate::cores::physical_memory::{BuddyPageAllocation, PageRefs, PhysicalMemory, PhysicalMemoryTable};
 use crate::cores::slob;
 use crate::cores::slob::KMemCache;
 use crate::define_early_init;
@@ -11,7 +14,8 @@ use crate::define_early_init;
 // We have to allocate the page table in the .data section directly, rather than create on the stack and copy it to .data,
 // which will blow up the tiny stack.
 static KERNEL_PHYSICAL_PT: RwLock<PhysicalMemory<BuddyPageAllocation>> = RwLock::new(PhysicalMemory {
-    table: BuddyPageAllocation::uninitialized()
+    table: BuddyPageAllocation::uninitialized(),
+    refs: PageRefs::uninitialized(),
 });
 // Record the number of allocated pages. Just for test.
 pub static ALLOC_PAGE_CNT: AtomicUsize = AtomicUsize::new(0);
@@ -23,20 +27,51 @@ pub extern "C" fn init_physical_page_table() {
     let mut binding = KERNEL_PHYSICAL_PT.write();
     binding.table.init(kernel2physical(ekernel as u64) as *mut u8,
                        PHYSICAL_TOP as *mut u8);
+    // The reference counts live in pages taken from the allocator itself.
+    let num_pages = PHYSICAL_TOP as usize / PAGE_SIZE;
+    let size = PageRefs::size_for(num_pages);
+    let counts = _physical2kernel_mut(binding.table.page_alloc(round_up(size, PAGE_SIZE) / PAGE_SIZE));
+    unsafe {
+        ptr::write_bytes(counts, 0, size);
+    }
+    binding.refs.init(counts as *mut AtomicU32, num_pages);
 }
 
 define_early_init!(init_physical_page_table);
 
+// Allocate `page_num` contiguous pages. The reference count of the first page is 1.
 pub fn kalloc_page(page_num: usize) -> *mut u8 {
     ALLOC_PAGE_CNT.fetch_add(1, core::sync::atomic::Ordering::AcqRel);
     let mut binding = KERNEL_PHYSICAL_PT.write();
-    _physical2kernel_mut(binding.table.page_alloc(page_num))
+    let page = binding.table.page_alloc(page_num);
+    binding.refs.set(page, 1);
+    _physical2kernel_mut(page)
 }
 
+// Free pages allocated by `kalloc_page`, no matter how many references there are.
 pub fn kfree_page(page_addr: *mut u8, page_num: usize) {
     ALLOC_PAGE_CNT.fetch_sub(1, core::sync::atomic::Ordering::AcqRel);
     let mut binding = KERNEL_PHYSICAL_PT.write();
-    binding.page_free(_kernel2physical_mut(page_addr), page_num)
+    let page = _kernel2physical_mut(page_addr);
+    binding.refs.set(page, 0);
+    binding.page_free(page, page_num)
+}
+
+// Take another reference to a page allocated by `kalloc_page(1)`.
+pub fn kshare_page(page_addr: *mut u8) {
+    KERNEL_PHYSICAL_PT.read().refs.inc(_kernel2physical_mut(page_addr));
+}
+
+// Drop a reference to a page allocated by `kalloc_page(1)`, and free it with the last one.
+pub fn kput_page(page_addr: *mut u8) {
+    let last = KERNEL_PHYSICAL_PT.read().refs.dec(_kernel2physical_mut(page_addr)) == 0;
+    if last {
+        kfree_page(page_addr, 1);
+    }
+}
+
+pub fn page_ref_count(page_addr: *mut u8) -> u32 {
+    KERNEL_PHYSICAL_PT.read().refs.count(_kernel2physical_mut(page_addr))
 }
 
 pub fn kmalloc(size: usize) -> *mut u8 {
@@ -2,8 +2,11 @@ use core::sync::atomic::AtomicBool;
 use crate::aarch64::intrinsic::{disable_trap, enable_trap, wfi};
 use crate::kernel::cpu::set_cpu_on;
 use crate::kernel::sched::yield_;
-use crate::{get_cpu_id, set_cpu_off, stop_cpu};
+use crate::{get_cpu_id, println, set_cpu_off, stop_cpu};
 use crate::kernel::init::do_rest_init;
+use crate::cores::console::init_console_input;
+use crate::kernel::block_device::init_block_device;
+use crate::kernel::errno::Errno::ENODEV;
 
 pub mod init;
 pub mod mem;
@@ -12,9 +15,20 @@ pub mod proc;
 pub mod cpu;
 pub mod sched;
 pub mod syscall;
+pub mod errno;
 pub mod sd_def;
 pub mod sd;
 pub mod mbr;
+pub mod gpt;
+pub mod partition;
+pub mod block_device;
+pub mod elf;
+pub mod exec;
+pub mod vm;
+pub mod uaccess;
+pub mod signal;
+pub mod session;
+pub mod procinfo;
 
 pub static PANIC_FLAG: AtomicBool = AtomicBool::new(false);
 pub const KERNEL_STACK_SIZE: usize = 65536;
@@ -59,12 +73,37 @@ macro_rules! define_rest_init {
     };
 }
 
+// Register a syscall. It comes in two forms:
+//
+// `define_syscall!(id, func)` registers `func: fn([u64; 6]) -> SyscallResult`, which decodes the registers itself.
+//
+// `define_syscall!(id, fn func(arg: Type, ...) -> SyscallResult { ... })` defines `func` with typed arguments,
+// and registers a wrapper decoding each register with `SyscallArg::decode`. If any of them fails, the wrapper
+// returns the error without calling `func`.
 #[macro_export]
 macro_rules! define_syscall {
     ($syscall_id:expr, $func:ident) => {
         paste::paste! {
             pub unsafe extern "C" fn [<__syscall_ $func>] () {
-                $crate::kernel::syscall::register_syscall($syscall_id, $func);
+                $crate::kernel::syscall::register_syscall($syscall_id, stringify!($func), "[u64; 6]", $func);
+            }
+            $crate::define_early_init!([<__syscall_ $func>]);
+        }
+    };
+    ($syscall_id:expr, fn $func:ident($($arg:ident: $ty:ty),* $(,)?) -> $ret:ty $body:block) => {
+        pub fn $func($($arg: $ty),*) -> $ret $body
+
+        paste::paste! {
+            fn [<__decode_ $func>](args: [u64; 6]) -> $crate::kernel::errno::SyscallResult {
+                const _: () = assert!(<[&str]>::len(&[$(stringify!($arg)),*]) <= 6, "A syscall takes at most 6 arguments");
+                #[allow(unused_mut, unused_variables)]
+                let mut args = args.iter();
+                $func($(<$ty as $crate::kernel::syscall::SyscallArg>::decode(*args.next().unwrap())?),*)
+            }
+
+            pub unsafe extern "C" fn [<__syscall_ $func>] () {
+                $crate::kernel::syscall::register_syscall($syscall_id, stringify!($func),
+                                                          stringify!($($arg: $ty),*), [<__decode_ $func>]);
             }
             $crate::define_early_init!([<__syscall_ $func>]);
         }
@@ -97,6 +136,12 @@ pub fn idle_entry() -> ! {
 
 pub fn kernel_entry(_arg: usize) -> ! {
     do_rest_init();
+    init_console_input();
+    match init_block_device() {
+        Ok(()) => {}
+        Err(ENODEV) => println!("kernel_entry: no file system partition found, running without a file system"),
+        Err(err) => println!("kernel_entry: cannot mount the file system: {:?}", err),
+    }
     #[cfg(test)]
     {
         use crate::run_test;
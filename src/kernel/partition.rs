@@ -0,0 +1,175 @@
+use alloc::vec;
+use alloc::vec::Vec;
+use core::fmt::{Debug, Formatter};
+use crate::kernel::block_device::SdBlockDevice;
+use crate::kernel::gpt::{GPTEntry, GPTHeader, Guid};
+use crate::kernel::mbr::{MBR, MBRPartition, PART_TYPE_GPT_PROTECTIVE, PART_TYPE_LINUX};
+use crate::println;
+
+pub const SECTOR_SIZE: usize = 512;
+// Logical partitions are numbered from 5, like Linux does.
+const FIRST_LOGICAL_INDEX: usize = 5;
+// Guard against loops in a corrupted EBR chain.
+const MAX_LOGICAL_PARTITIONS: usize = 128;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum PartitionType {
+    Mbr(u8),
+    Gpt(Guid),
+}
+
+impl Debug for PartitionType {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        match self {
+            PartitionType::Mbr(typ) => write!(f, "MBR({:#04x})", typ),
+            PartitionType::Gpt(guid) => write!(f, "GPT({})", guid),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Partition {
+    // 1-based, as in `/dev/mmcblk0p<index>`.
+    pub index: usize,
+    pub typ: PartitionType,
+    // In sectors.
+    pub start: usize,
+    pub size: usize,
+}
+
+impl Partition {
+    pub fn is_linux_data(&self) -> bool {
+        matches!(self.typ, PartitionType::Mbr(PART_TYPE_LINUX) | PartitionType::Gpt(Guid::LINUX_FILESYSTEM))
+    }
+
+    // A block device which only covers this partition.
+    pub fn device(&self) -> SdBlockDevice {
+        SdBlockDevice::new(self.start, self.size)
+    }
+
+    fn from_mbr(index: usize, base: usize, entry: &MBRPartition) -> Self {
+        Self {
+            index,
+            typ: PartitionType::Mbr(entry.typ),
+            start: base + entry.start as usize,
+            size: entry.size as usize,
+        }
+    }
+
+    fn from_gpt(index: usize, entry: &GPTEntry) -> Option<Self> {
+        if entry.last_lba < entry.first_lba {
+            return None;
+        }
+        Some(Self {
+            index,
+            typ: PartitionType::Gpt(entry.type_guid),
+            start: entry.first_lba as usize,
+            size: (entry.last_lba - entry.first_lba + 1) as usize,
+        })
+    }
+}
+
+/*
+ * Scan the partition table of a disk, using `read` to read a sector.
+ *
+ * 1. If the MBR has a protective partition (0xEE), the disk uses GPT. The primary header is at sector 1;
+ *    if it or its entry array is corrupted, the backup one at the last sector is used instead.
+ * 2. Otherwise, the primary MBR partitions are listed, and the logical partitions in an extended partition
+ *    are found by walking its chain of EBRs.
+ *
+ * Corrupted tables are reported and skipped, so the result may be empty.
+ */
+pub fn scan<F: FnMut(usize, &mut [u8; SECTOR_SIZE])>(mut read: F) -> Vec<Partition> {
+    let mut buf = [0u8; SECTOR_SIZE];
+    read(0, &mut buf);
+    let mbr = match MBR::parse(&buf) {
+        Some(mbr) => mbr,
+        None => {
+            println!("partition: no MBR signature");
+            return Vec::new();
+        }
+    };
+    if let Some(protective) = mbr.partitions.iter().find(|p| p.typ == PART_TYPE_GPT_PROTECTIVE) {
+        // The protective partition covers the whole disk starting from sector 1.
+        let last_lba = protective.start as usize + protective.size as usize - 1;
+        return scan_gpt(&mut read, last_lba);
+    }
+
+    let mut partitions = Vec::new();
+    let mut next_logical = FIRST_LOGICAL_INDEX;
+    for (i, entry) in mbr.partitions.iter().enumerate() {
+        if entry.is_empty() {
+            continue;
+        }
+        if entry.is_extended() {
+            scan_logical(&mut read, entry.start as usize, &mut next_logical, &mut partitions);
+        } else {
+            partitions.push(Partition::from_mbr(i + 1, 0, entry));
+        }
+    }
+    partitions
+}
+
+// Walk the EBR chain of the extended partition starting at `ext_start`.
+//
+// In each EBR, the first entry is a logical partition relative to the EBR itself,
+// and the second one points to the next EBR relative to `ext_start`.
+fn scan_logical<F: FnMut(usize, &mut [u8; SECTOR_SIZE])>(read: &mut F, ext_start: usize, next_index: &mut usize, partitions: &mut Vec<Partition>) {
+    let mut buf = [0u8; SECTOR_SIZE];
+    let mut ebr_lba = ext_start;
+    for _ in 0..MAX_LOGICAL_PARTITIONS {
+        read(ebr_lba, &mut buf);
+        let ebr = match MBR::parse(&buf) {
+            Some(ebr) => ebr,
+            None => {
+                println!("partition: bad EBR at sector {}", ebr_lba);
+                return;
+            }
+        };
+        let [logical, next, ..] = &ebr.partitions;
+        if !logical.is_empty() {
+            partitions.push(Partition::from_mbr(*next_index, ebr_lba, logical));
+            *next_index += 1;
+        }
+        if next.is_empty() {
+            return;
+        }
+        ebr_lba = ext_start + next.start as usize;
+    }
+    println!("partition: too many logical partitions");
+}
+
+fn read_gpt<F: FnMut(usize, &mut [u8; SECTOR_SIZE])>(read: &mut F, lba: usize) -> Option<Vec<(usize, GPTEntry)>> {
+    let mut buf = [0u8; SECTOR_SIZE];
+    read(lba, &mut buf);
+    let header = GPTHeader::parse(&buf)?;
+    if header.current_lba != lba as u64 {
+        return None;
+    }
+    let mut entries = vec![0u8; header.entries_size()];
+    for (i, chunk) in entries.chunks_mut(SECTOR_SIZE).enumerate() {
+        read(header.entries_lba as usize + i, &mut buf);
+        chunk.copy_from_slice(&buf[..chunk.len()]);
+    }
+    let entries = header.parse_entries(&entries)?;
+    // Drop entries outside of the usable area, so they can never overlap the tables.
+    Some(entries.into_iter()
+        .filter(|(_, e)| e.first_lba >= header.first_usable_lba && e.last_lba <= header.last_usable_lba)
+        .collect())
+}
+
+fn scan_gpt<F: FnMut(usize, &mut [u8; SECTOR_SIZE])>(read: &mut F, last_lba: usize) -> Vec<Partition> {
+    let entries = read_gpt(read, 1).or_else(|| {
+        println!("partition: primary GPT is corrupted, trying the backup one");
+        read_gpt(read, last_lba)
+    });
+    match entries {
+        Some(entries) => entries.iter()
+            .filter_map(|(i, entry)| Partition::from_gpt(i + 1, entry))
+            .collect(),
+        None => {
+            println!("partition: both GPTs are corrupted");
+            Vec::new()
+        }
+    }
+}
@@ -2,10 +2,17 @@ use crate::aarch64::mmu::PAGE_SIZE;
 use crate::common::Container;
 use crate::common::list::{ListLink, ListNode};
 use crate::common::sem::Semaphore;
-use crate::define_init;
+use crate::{define_init, define_syscall};
 use crate::kernel::{get_kernel_stack_bottom, kernel_entry, KERNEL_STACK_SIZE};
 use crate::kernel::mem::{kalloc_page, kfree_page};
-use crate::kernel::sched::{activate, thisproc, SchInfo, proc_entry, try_thisproc, sched, acquire_sched_lock, is_zombie, is_unused_no_lock, activate_no_lock};
+use crate::kernel::errno::Errno::{self, ECHILD, EINTR, EINVAL, EPERM, ESRCH};
+use crate::kernel::errno::SyscallResult;
+use crate::kernel::signal::{NSIG, send_signal, SIGCHLD, signal_exit_code, SignalState, SIGSEGV};
+use crate::kernel::syscall::{Flags, SYS_FORK, SYS_GETGID, SYS_GETUID, SYS_KILL, SYS_SETGID, SYS_SETUID, SYS_WAIT4};
+use crate::kernel::uaccess::UserPtr;
+use crate::kernel::vm::VmaTree;
+use crate::kernel::sched::{activate, thisproc, SchInfo, proc_entry, try_thisproc, sched, acquire_proc_lock, is_zombie, is_unused_no_lock, is_zombie_no_lock};
+use crate::kernel::session::{leave_session, send_signal_to_group};
 use alloc::boxed::Box;
 use core::mem::MaybeUninit;
 use core::ptr;
@@ -21,7 +28,8 @@ pub fn root_proc() -> &'static mut Process {
     unsafe { ROOT_PROC.assume_init_mut() }
 }
 
-#[derive(PartialEq, Debug)]
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+#[repr(u32)]
 pub enum ProcessState {
     Unused,
     Runnable,
@@ -30,7 +38,7 @@ pub enum ProcessState {
     Zombie,
 }
 
-static PROC_LOCK: Mutex<()> = Mutex::new(());
+pub(super) static PROC_LOCK: Mutex<()> = Mutex::new(());
 
 const PID_POOL_SIZE: usize = 1000;
 static PID_POOL: LockedArrayPool<usize, PID_POOL_SIZE> = LockedArrayPool::new();
@@ -41,7 +49,7 @@ const fn pid_generator(fill_count: usize, i: usize) -> usize {
 }
 
 #[repr(C)]
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy)]
 pub struct UserContext {
     pub fp: u64,
     pub lr: u64,
@@ -69,9 +77,16 @@ pub struct KernelContext {
 #[repr(C)]
 pub struct Process {
     pub pid: usize,
+    // Guards the state of the process, see `sched::acquire_proc_lock`.
+    pub(super) lock: Mutex<()>,
     pub killed: bool,
     pub idle: bool,
     pub exit_code: isize,
+    // The signal that terminated the process, or 0 if it exited by itself.
+    pub term_signal: usize,
+    // The process group and the session, see `session`. A new process joins those of its parent.
+    pub pgid: usize,
+    pub sid: usize,
     pub state: ProcessState,
     pub child_exit: Semaphore,
     pub first_child: Option<*mut Process>,
@@ -79,6 +94,15 @@ pub struct Process {
     pub parent: Option<*mut Process>,
     pub sch_info: SchInfo,
     pub pgdir: PageTableDirectory,
+    pub vmas: VmaTree,
+    // The heap is [brk_start, brk), see `vm::set_brk`. A process not loaded by `exec` has no heap.
+    pub brk_start: usize,
+    pub brk: usize,
+    // The credentials, checked by IPC and `kill`, and changed by `setuid` and `setgid`. uid 0 is root, which passes
+    // every check. A new process has those of its parent, and the root process is root.
+    pub uid: u32,
+    pub gid: u32,
+    pub signal: SignalState,
     pub kernel_stack: *mut u8,
     pub user_context: *mut UserContext,
     pub kernel_context: *mut KernelContext,
@@ -88,9 +112,13 @@ impl Process {
     // Fill base fields of a process.
     pub fn fill_default_fields(&mut self) {
         self.pid = 0;
+        self.lock = Mutex::new(());
         self.killed = false;
         self.idle = false;
         self.exit_code = 0;
+        self.term_signal = 0;
+        self.pgid = 0;
+        self.sid = 0;
         self.state = ProcessState::Unused;
         self.child_exit = Semaphore::uninit(0);
         self.child_exit.init();
@@ -101,6 +129,13 @@ impl Process {
         self.sch_info = SchInfo::uninit();
         self.sch_info.init();
         self.pgdir = PageTableDirectory::uninit();
+        self.vmas = VmaTree::uninit();
+        self.vmas.init();
+        self.brk_start = 0;
+        self.brk = 0;
+        self.uid = 0;
+        self.gid = 0;
+        self.signal = SignalState::new();
         self.kernel_stack = ptr::null_mut();
         self.user_context = ptr::null_mut();
         self.kernel_context = ptr::null_mut();
@@ -171,7 +206,7 @@ impl Process {
     }
 
     pub fn can_be_freed(&self) -> bool {
-        !self.idle && !self.killed && self.pid != root_proc().pid
+        !self.idle && self.pid != root_proc().pid
     }
 }
 
@@ -191,60 +226,166 @@ impl Default for Box<Process> {
     }
 }
 
+// Exit code of a process killed for an invalid memory access.
+pub const EXIT_SEGFAULT: isize = signal_exit_code(SIGSEGV);
+
 pub fn exit(code: isize) -> ! {
     let proc = thisproc();
     proc.exit_code = code;
+    if proc.killed {
+        // A killed kernel thread may exit by itself before a trap makes it, but the signal still killed it.
+        let sig = {
+            let _lock = acquire_proc_lock(proc);
+            proc.signal.fatal_signal()
+        };
+        proc.term_signal = sig;
+        proc.exit_code = signal_exit_code(sig);
+    }
     let proc_lock = PROC_LOCK.lock();
 
+    // Hang up our terminal and the process groups we orphan, while we still know our children.
+    leave_session(proc);
     // Transfer all children to the root process.
     proc.transfer_all_children_to_root();
     // Notify the parent that it is exiting.
     if let Some(parent) = proc.parent {
         unsafe { (*parent).child_exit.post() };
+        let parent = unsafe { &mut *parent };
+        let _lock = acquire_proc_lock(parent);
+        send_signal(parent, SIGCHLD);
     }
 
-    let lock = acquire_sched_lock();
+    let lock = acquire_proc_lock(proc);
     drop(proc_lock);
-    // This process is a zombie, and will be cleaned up by the parent's wait().
+    // This process is a zombie, and will be cleaned up by the parent's waitpid().
     sched(lock, ProcessState::Zombie);
 
     panic!("Zombie process should not be scheduled");
 }
 
-pub fn wait() -> Option<(usize, isize)> {
-    let proc = thisproc();
-    if proc.first_child.is_none() {
-        return None;
+// Exit for `sig`, which the parent can tell from `term_signal`.
+pub fn exit_signal(sig: usize) -> ! {
+    thisproc().term_signal = sig;
+    exit(signal_exit_code(sig))
+}
+
+// How a child ended, as reported by `waitpid`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ExitStatus {
+    pub code: isize,
+    // The signal that terminated the child, or 0 if it exited by itself.
+    pub signal: usize,
+}
+
+impl ExitStatus {
+    // The status word of `waitpid`, as on Linux: the signal in bits 0-6 if the child was killed by a signal,
+    // or else the low byte of the exit code in bits 8-15.
+    pub fn encode(&self) -> i32 {
+        if self.signal != 0 {
+            (self.signal & 0x7f) as i32
+        } else {
+            ((self.code & 0xff) << 8) as i32
+        }
     }
-    // Wait for a child to exit.
-    let child_exited = proc.child_exit.get_or_wait();
-    if !child_exited {
-        return None;
+}
+
+// Options of `waitpid`.
+pub const WNOHANG: u32 = 1;
+
+impl Process {
+    // Whether `waitpid(pid)` in a process of the group `pgid` waits for this process, which is a child.
+    fn waited_by(&self, pid: isize, pgid: usize) -> bool {
+        match pid {
+            -1 => true,
+            0 => self.pgid == pgid,
+            pid if pid < 0 => self.pgid == -pid as usize,
+            pid => self.pid == pid as usize,
+        }
     }
+}
 
-    let lock = PROC_LOCK.lock();
-    let child = proc.first_child().unwrap();
-    for x in child.link().iter::<Process>(false) {
-        if is_zombie(x) {
-            let exit_code = x.exit_code;
-            let pid = x.pid;
-            // Free stack and context.
-            proc.detach_child(x);
-            if x.can_be_freed() {
-                x.pgdir.free();
-                kfree_page(unsafe { x.kernel_stack.byte_sub(KERNEL_STACK_SIZE) },
-                           KERNEL_STACK_SIZE / PAGE_SIZE);
+// Free the zombie `child` of `proc`, and return its pid and exit status.
+//
+// Note: you should hold the lock of the process tree.
+fn reap(proc: &mut Process, child: &mut Process) -> (usize, ExitStatus) {
+    let status = ExitStatus {
+        code: child.exit_code,
+        signal: child.term_signal,
+    };
+    let pid = child.pid;
+    // Free stack and context.
+    proc.detach_child(child);
+    if child.can_be_freed() {
+        child.vmas.clear(&mut child.pgdir);
+        child.pgdir.free();
+        kfree_page(unsafe { child.kernel_stack.byte_sub(KERNEL_STACK_SIZE) },
+                   KERNEL_STACK_SIZE / PAGE_SIZE);
+    }
+    PID_POOL.free(pid);
+    // Scheduler has removed it and parent has also detached it, so we can free it.
+    let _proc_to_be_dropped = unsafe { Box::from_raw(child) };
+    (pid, status)
+}
+
+// Wait for a child to exit, and free it. Return its pid and exit status.
+//
+// `pid` selects the children as on Linux: -1 for any child, a positive pid for that child, 0 for the children in
+// our process group, or a negated process group id for the children in that group.
+// Return `ECHILD` if no child is selected, `EINTR` if a signal comes first, or None if `WNOHANG` is in `options`
+// and no selected child has exited yet.
+pub fn waitpid(pid: isize, options: u32) -> Result<Option<(usize, ExitStatus)>, Errno> {
+    let proc = thisproc();
+    loop {
+        // `child_exit` only tells us to look again, since we may be waiting for a particular child.
+        // A child posts it before it becomes a zombie, but we cannot see it until then with the lock held.
+        proc.child_exit.try_get_all();
+        let lock = PROC_LOCK.lock();
+        let mut selected = false;
+        if let Some(first_child) = proc.first_child() {
+            for child in first_child.link().iter::<Process>(false) {
+                if !child.waited_by(pid, proc.pgid) {
+                    continue;
+                }
+                selected = true;
+                if is_zombie(child) {
+                    return Ok(Some(reap(proc, child)));
+                }
             }
-            PID_POOL.free(pid);
-            // Scheduler has removed it and parent has also detached it, so we can free it.
-            let _proc_to_be_dropped = unsafe { Box::from_raw(x) };
-            return Some((pid, exit_code));
+        }
+        drop(lock);
+        if !selected {
+            return Err(ECHILD);
+        }
+        if options & WNOHANG != 0 {
+            return Ok(None);
+        }
+        if !proc.child_exit.get_or_wait() {
+            return Err(EINTR);
         }
     }
-    drop(lock);
-    panic!("child_exit is posted, but no zombie child is found!");
 }
 
+// Wait for any child to exit, and free it. Return its pid and exit code, or None if there is no child.
+pub fn wait() -> Option<(usize, isize)> {
+    waitpid(-1, 0).ok().flatten().map(|(pid, status)| (pid, status.code))
+}
+
+// `rusage` is not supported, so it must be null.
+define_syscall!(SYS_WAIT4, fn sys_wait4(pid: i32, status: UserPtr<i32>, options: Flags<WNOHANG>, rusage: usize) -> SyscallResult {
+    if rusage != 0 {
+        return Err(EINVAL);
+    }
+    match waitpid(pid as isize, options.0)? {
+        Some((pid, exit_status)) => {
+            if !status.is_null() {
+                status.write(exit_status.encode())?;
+            }
+            Ok(pid as u64)
+        }
+        None => Ok(0),
+    }
+});
+
 fn _find_proc(pid: usize, proc: &'static mut Process) -> Option<&'static mut Process> {
     if proc.pid == pid {
         return Some(proc);
@@ -259,25 +400,176 @@ fn _find_proc(pid: usize, proc: &'static mut Process) -> Option<&'static mut Pro
     None
 }
 
-fn find_proc(pid: usize) -> Option<&'static mut Process> {
+pub(super) fn find_proc(pid: usize) -> Option<&'static mut Process> {
     _find_proc(pid, root_proc())
 }
 
-pub fn kill(pid: usize) -> bool {
+fn _for_each_proc(proc: &'static mut Process, f: &mut dyn FnMut(&'static mut Process)) {
+    if let Some(first_child) = proc.first_child() {
+        for child in first_child.link().iter::<Process>(false) {
+            _for_each_proc(child, f);
+        }
+    }
+    f(proc);
+}
+
+// Call `f` on every process in the tree.
+//
+// Note: you should hold the lock of the process tree, and `f` must not change the tree.
+pub(super) fn for_each_proc(mut f: impl FnMut(&'static mut Process)) {
+    _for_each_proc(root_proc(), &mut f)
+}
+
+// Send `sig` to the process `pid`. Signal 0 only checks that the process exists.
+// If `uid` is given, the sender must be root or have the same uid as the process.
+fn send_signal_to(pid: usize, sig: usize, uid: Option<u32>) -> Result<(), Errno> {
+    if sig > NSIG {
+        return Err(EINVAL);
+    }
     let _lock = PROC_LOCK.lock();
-    if let Some(proc) = find_proc(pid) {
-        let _sched_lock = acquire_sched_lock();
-        if is_unused_no_lock(proc) {
-            return false;
+    let proc = find_proc(pid).ok_or(ESRCH)?;
+    let _proc_lock = acquire_proc_lock(proc);
+    if is_unused_no_lock(proc) {
+        return Err(ESRCH);
+    }
+    if uid.map_or(false, |uid| uid != 0 && uid != proc.uid) {
+        return Err(EPERM);
+    }
+    if sig != 0 {
+        send_signal(proc, sig);
+    }
+    Ok(())
+}
+
+// Send `sig` to every live process for which `select` holds, except the root process.
+// Fail with ESRCH if none is selected, or with EPERM if `uid` (see `send_signal_to`) may signal none of them.
+//
+// Note: you should hold the lock of the process tree.
+pub(super) fn send_signal_where(select: impl Fn(&Process) -> bool, sig: usize, uid: Option<u32>) -> Result<(), Errno> {
+    if sig > NSIG {
+        return Err(EINVAL);
+    }
+    let root_pid = root_proc().pid;
+    let (mut found, mut sent) = (false, false);
+    for_each_proc(|proc| {
+        let _lock = acquire_proc_lock(proc);
+        if proc.pid == root_pid || is_unused_no_lock(proc) || is_zombie_no_lock(proc) || !select(proc) {
+            return;
         }
-        proc.killed = true;
-        activate_no_lock(proc);
-        true
-    } else {
-        false
+        found = true;
+        if uid.map_or(false, |uid| uid != 0 && uid != proc.uid) {
+            return;
+        }
+        sent = true;
+        if sig != 0 {
+            send_signal(proc, sig);
+        }
+    });
+    match (found, sent) {
+        (_, true) => Ok(()),
+        (true, false) => Err(EPERM),
+        _ => Err(ESRCH),
     }
 }
 
+pub fn kill(pid: usize, sig: usize) -> Result<(), Errno> {
+    send_signal_to(pid, sig, None)
+}
+
+// As on Linux, a positive `pid` names a process, 0 the group of the caller, -1 every process but the root
+// process and the caller, and any other negative value the group `-pid`.
+define_syscall!(SYS_KILL, fn sys_kill(pid: i32, sig: i32) -> SyscallResult {
+    if sig < 0 {
+        return Err(EINVAL);
+    }
+    let (me, sig) = (thisproc(), sig as usize);
+    let uid = Some(me.uid);
+    match pid {
+        pid if pid > 0 => send_signal_to(pid as usize, sig, uid)?,
+        0 => send_signal_to_group(me.pgid, sig, uid)?,
+        -1 => {
+            let _lock = PROC_LOCK.lock();
+            send_signal_where(|proc| proc.pid != me.pid, sig, uid)?
+        }
+        pid => send_signal_to_group(pid.unsigned_abs() as usize, sig, uid)?,
+    }
+    Ok(0)
+});
+
+// Change the uid of the current process. Any other process may only set the uid it already has, so a root process
+// which takes another uid gives its privileges up for good.
+pub fn setuid(uid: u32) -> Result<(), Errno> {
+    let me = thisproc();
+    let _lock = PROC_LOCK.lock();
+    if me.uid != 0 && me.uid != uid {
+        return Err(EPERM);
+    }
+    me.uid = uid;
+    Ok(())
+}
+
+// Change the gid of the current process, which must be root unless `gid` is its gid already.
+pub fn setgid(gid: u32) -> Result<(), Errno> {
+    let me = thisproc();
+    let _lock = PROC_LOCK.lock();
+    if me.uid != 0 && me.gid != gid {
+        return Err(EPERM);
+    }
+    me.gid = gid;
+    Ok(())
+}
+
+// There is a single uid and a single gid per process, so these stand for the real, effective and saved ids alike.
+define_syscall!(SYS_GETUID, fn sys_getuid() -> SyscallResult {
+    Ok(thisproc().uid as u64)
+});
+
+define_syscall!(SYS_GETGID, fn sys_getgid() -> SyscallResult {
+    Ok(thisproc().gid as u64)
+});
+
+define_syscall!(SYS_SETUID, fn sys_setuid(uid: u32) -> SyscallResult {
+    setuid(uid)?;
+    Ok(0)
+});
+
+define_syscall!(SYS_SETGID, fn sys_setgid(gid: u32) -> SyscallResult {
+    setgid(gid)?;
+    Ok(0)
+});
+
+extern "C" {
+    fn trap_return(_: usize);
+}
+
+// Duplicate the current process, which must have trapped from user space.
+// The child shares every user page copy-on-write, and gets a copy of the user context and the scheduling info,
+// and returns to user space with 0 in x0. Return the child's PID.
+pub fn fork() -> usize {
+    let parent = thisproc();
+    // `create_proc` attaches the child to us.
+    let child = create_proc();
+    child.pgdir.copy_from(&parent.pgdir);
+    // Our writable pages are read-only now.
+    parent.pgdir.attach();
+    parent.vmas.copy_to(&mut child.vmas);
+    child.brk_start = parent.brk_start;
+    child.brk = parent.brk;
+    child.uid = parent.uid;
+    child.gid = parent.gid;
+    child.signal = parent.signal.forked();
+    unsafe {
+        ptr::copy_nonoverlapping(parent.user_context, child.user_context, 1);
+        (*child.user_context).x[0] = 0;
+    }
+    child.sch_info.nice = parent.sch_info.nice;
+    start_proc(child, trap_return as *const fn(usize), 0)
+}
+
+define_syscall!(SYS_FORK, fn sys_fork() -> SyscallResult {
+    Ok(fork() as u64)
+});
+
 // Create a new process.
 // It will allocate stack and pid for `p`, and fill default fields.
 // If the caller is a running process, it will also attach `p` to the caller.
@@ -293,10 +585,16 @@ unsafe fn init_proc(p: &mut Process) {
     proc.kernel_context = proc.user_context
         .byte_sub(core::mem::size_of::<KernelContext>()) as *mut KernelContext;
     proc.pid = PID_POOL.alloc(pid_generator).unwrap();
+    proc.pgid = proc.pid;
+    proc.sid = proc.pid;
     // Set up the proc tree, if the caller is a running process.
     if let Some(parent) = try_thisproc() {
         let _lock = PROC_LOCK.lock();
         parent.attach_child(proc);
+        if !parent.idle {
+            proc.pgid = parent.pgid;
+            proc.sid = parent.sid;
+        }
     }
 }
 
@@ -339,9 +637,13 @@ pub fn start_proc(p: &mut Process, entry: *const fn(usize), arg: usize) -> usize
 pub mod guard {
     use crate::kernel::KERNEL_STACK_SIZE;
 
+    // Fill the whole stack with the guard byte, not only the guard bits at the bottom,
+    // so that `stack_usage` can tell how deep the stack has ever grown.
+    // This writes all 64 KiB once per process, about as much as zeroing the stack would, and only when the process
+    // is created; `check_guard_bits` on each switch still reads the bottom 16 bytes only.
     pub unsafe fn put_guard_bits(mut addr: *mut u8) {
         addr = addr.byte_sub(KERNEL_STACK_SIZE);
-        addr.write_bytes(0x55, 16);
+        addr.write_bytes(0x55, KERNEL_STACK_SIZE);
     }
 
     pub unsafe fn check_guard_bits(mut addr: *mut u8) -> bool {
@@ -353,6 +655,16 @@ pub mod guard {
         }
         true
     }
+
+    // The most bytes of the stack ever used, i.e. up to the lowest byte which is not the guard byte any more.
+    pub unsafe fn stack_usage(mut addr: *mut u8) -> usize {
+        addr = addr.byte_sub(KERNEL_STACK_SIZE);
+        let words = addr as *const u64;
+        let untouched = (0..KERNEL_STACK_SIZE / 8)
+            .find(|&i| words.add(i).read() != 0x5555_5555_5555_5555)
+            .unwrap_or(KERNEL_STACK_SIZE / 8);
+        KERNEL_STACK_SIZE - untouched * 8
+    }
 }
 
 pub fn create_idle_process() -> Box<Process> {
@@ -0,0 +1,104 @@
+/*
+ * A snapshot of the process tree, as `ps` shows it.
+ *
+ * `snapshot` walks the tree with the tree locked, so that no process comes or goes while we look, and locks each
+ * process in turn to read its state. The processes still run meanwhile, so that a snapshot is not taken at one
+ * instant, as with `ps` on Linux. `sys_procinfo` copies the snapshot to user space, and `ps` prints it on
+ * the console when Ctrl-P is typed there.
+ */
+use alloc::vec::Vec;
+use crate::{define_syscall, println};
+use crate::kernel::errno::SyscallResult;
+use crate::kernel::proc::{for_each_proc, Process, ProcessState, PROC_LOCK};
+use crate::kernel::proc::guard::stack_usage;
+use crate::kernel::sched::{acquire_proc_lock, thisproc, SCHED_MEDIUM_NICE};
+use crate::kernel::syscall::SYS_PROCINFO;
+use crate::kernel::uaccess::UserPtr;
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ProcInfo {
+    pub pid: u64,
+    // The parent. The root process is its own parent.
+    pub ppid: u64,
+    pub pgid: u64,
+    pub sid: u64,
+    pub state: ProcessState,
+    // From -20 to 19, as on Linux.
+    pub nice: i32,
+    pub vruntime: u64,
+    // The CPU the process has run on most recently, or -1 if it has never run.
+    pub last_cpu: i32,
+    _pad: u32,
+    // The most bytes of the kernel stack ever used.
+    pub stack_used: u64,
+    // The number of user pages mapped, or -1 if the process is running or may start running on another CPU,
+    // whose page table may change under us.
+    pub pages: i64,
+}
+
+impl ProcInfo {
+    // Note: you should hold both the lock of the process tree and the lock of `proc`.
+    fn of(proc: &Process) -> Self {
+        let running_elsewhere = matches!(proc.state, ProcessState::Running | ProcessState::Runnable)
+            && proc.pid != thisproc().pid;
+        Self {
+            pid: proc.pid as u64,
+            ppid: proc.parent.map_or(0, |parent| unsafe { (*parent).pid }) as u64,
+            pgid: proc.pgid as u64,
+            sid: proc.sid as u64,
+            state: proc.state,
+            nice: proc.sch_info.nice as i32 - SCHED_MEDIUM_NICE as i32,
+            vruntime: proc.sch_info.vruntime,
+            last_cpu: proc.sch_info.last_cpu.map_or(-1, |cpu| cpu as i32),
+            _pad: 0,
+            stack_used: unsafe { stack_usage(proc.kernel_stack) } as u64,
+            pages: if running_elsewhere { -1 } else { proc.pgdir.count_pages() as i64 },
+        }
+    }
+}
+
+// Every process in the tree, zombies and processes not started yet included, ordered by pid.
+pub fn snapshot() -> Vec<ProcInfo> {
+    let mut procs = Vec::new();
+    {
+        let _lock = PROC_LOCK.lock();
+        for_each_proc(|proc| {
+            let _proc_lock = acquire_proc_lock(proc);
+            procs.push(ProcInfo::of(proc));
+        });
+    }
+    procs.sort_unstable_by_key(|info| info.pid);
+    procs
+}
+
+fn state_name(state: ProcessState) -> &'static str {
+    match state {
+        ProcessState::Unused => "unused",
+        ProcessState::Runnable => "runnable",
+        ProcessState::Running => "running",
+        ProcessState::Sleeping => "sleeping",
+        ProcessState::Zombie => "zombie",
+    }
+}
+
+// Print the snapshot on the console.
+pub fn ps() {
+    println!("{:>6} {:>6} {:>6} {:>6} {:<8} {:>3} {:>12} {:>3} {:>6} {:>6}",
+             "PID", "PPID", "PGID", "SID", "STATE", "NI", "VRUNTIME", "CPU", "STACK", "PAGES");
+    for info in snapshot() {
+        println!("{:>6} {:>6} {:>6} {:>6} {:<8} {:>3} {:>12} {:>3} {:>6} {:>6}",
+                 info.pid, info.ppid, info.pgid, info.sid, state_name(info.state), info.nice, info.vruntime,
+                 info.last_cpu, info.stack_used, info.pages);
+    }
+}
+
+// Copy at most `count` entries of the snapshot to `buf`, and return the number of processes,
+// so that the caller can retry with a larger buffer if it is more than `count`.
+define_syscall!(SYS_PROCINFO, fn sys_procinfo(buf: UserPtr<ProcInfo>, count: usize) -> SyscallResult {
+    let procs = snapshot();
+    for (i, info) in procs.iter().take(count).enumerate() {
+        buf.add(i).write(*info)?;
+    }
+    Ok(procs.len() as u64)
+});
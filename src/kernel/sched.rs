@@ -1,52 +1,87 @@
 use crate::{common::{
     list::ListNode,
     Container,
-}, define_early_init, kernel::proc::{KernelContext, Process, ProcessState}};
+}, kernel::proc::{KernelContext, Process, ProcessState}};
 use core::arch::global_asm;
 use core::assert_matches::assert_matches;
-use core::cmp::min;
-use core::mem::MaybeUninit;
-use core::sync::atomic::{AtomicU64, Ordering};
+use core::cmp::{max, min};
+use core::mem::forget;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use field_offset::offset_of;
 use spin::{Mutex, MutexGuard};
-use crate::aarch64::intrinsic::get_time_us;
+use crate::aarch64::intrinsic::{get_cpu_id, get_time_us};
 use crate::common::tree::{RbTree, RbTreeLink};
 use crate::cores::virtual_memory::VirtualMemoryPageTable;
-use crate::kernel::cpu::{add_cpu_timer, CPU_NUM, get_cpu_info_ref, Timer};
+use crate::kernel::cpu::{add_cpu_timer, CPU_NUM, get_cpu_info_mut, Timer};
 use crate::kernel::proc::guard::check_guard_bits;
 
 use super::cpu::get_cpu_info;
 
+// Each CPU has its own run queue. A process is put into a run queue when it wakes up, and moves to another CPU
+// only when that CPU balances the load: on each tick with one other CPU, and whenever it runs out of processes
+// and would go idle, by stealing from another one.
+//
+// Locking: each run queue has a lock of its own, and the state of each process is guarded by the lock of the
+// process (see `acquire_proc_lock`), which is taken first. While a process waits in a run queue, its `sch_info`
+// belongs to the queue, and the CPU of the queue sets it running with the queue locked only. When two run queues
+// are locked together to move a process, the one of the lower CPU is locked first (see `lock_pair`).
+//
+// `sched` holds the lock of the current process and the lock of the run queue of this CPU across `swtch`, so that
+// nobody wakes the process up or runs it on another CPU before we are off its stack. The process switched to
+// releases both, see `finish_switch`.
 pub struct Sched {
     pub cur_proc: Option<*mut Process>,
     pub idle_proc: Option<*mut Process>,
+    // Guards `run_queue` and `min_vruntime`.
+    lock: Mutex<()>,
+    // The runnable processes waiting for this CPU. The running process is not in it.
+    run_queue: RbTree<SchInfo>,
+    // The least vruntime on this CPU, which never goes back. A process waking up here starts from it.
+    min_vruntime: u64,
+    // The length of `run_queue`, and whether a process other than the idle one is running.
+    // Other CPUs read them without the lock, to guess the load of this CPU.
+    waiting: AtomicUsize,
+    busy: AtomicBool,
+    // The process we have just switched from, whose lock `finish_switch` releases.
+    prev: Option<*mut Process>,
+    // Which of the other CPUs to balance the load with on the next tick.
+    balance_next: usize,
+}
+
+fn sch_info_cmp(a: &mut SchInfo, b: &mut SchInfo) -> bool {
+    if a.vruntime != b.vruntime {
+        a.vruntime < b.vruntime
+    } else {
+        Process::get_parent::<Process>(a).pid < Process::get_parent::<Process>(b).pid
+    }
 }
 
-static mut RUN_QUEUE: MaybeUninit<RbTree<SchInfo>> = MaybeUninit::uninit();
-static MIN_VRUNTIME: AtomicU64 = AtomicU64::new(0);
-
-
-pub unsafe extern "C" fn init_run_queue() {
-    RUN_QUEUE = MaybeUninit::new(RbTree::new(|a, b| {
-        if a.vruntime != b.vruntime {
-            a.vruntime < b.vruntime
-        } else {
-            Process::get_parent::<Process>(a).pid < Process::get_parent::<Process>(b).pid
-        }
-    }));
-}
-define_early_init!(init_run_queue);
-
 impl Sched {
-    // Note: this function will not initialize the run queue. DO IT MANUALLY.
     pub const fn uninit() -> Self {
         Self {
             cur_proc: None,
             idle_proc: None,
+            lock: Mutex::new(()),
+            run_queue: RbTree::new(sch_info_cmp),
+            min_vruntime: 0,
+            waiting: AtomicUsize::new(0),
+            busy: AtomicBool::new(false),
+            prev: None,
+            balance_next: 0,
         }
     }
 
     pub fn init(&mut self) {}
+
+    fn enqueue(&mut self, sch_info: &mut SchInfo) {
+        self.run_queue.insert(sch_info);
+        self.waiting.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn dequeue(&mut self, sch_info: &mut SchInfo) {
+        self.run_queue.delete(sch_info);
+        self.waiting.fetch_sub(1, Ordering::Relaxed);
+    }
 }
 
 #[repr(C)]
@@ -55,6 +90,10 @@ pub struct SchInfo {
     pub vruntime: u64,
     pub nice: usize,
     pub start_time: u64,
+    // The CPU the process has run on most recently, or None if it has never run.
+    pub last_cpu: Option<usize>,
+    // The CPU whose run queue the process is in, if it is runnable.
+    pub cpu: usize,
 }
 
 impl SchInfo {
@@ -64,6 +103,8 @@ impl SchInfo {
             nice: SCHED_MEDIUM_NICE,
             vruntime: 0,
             ptnode: RbTreeLink::new(),
+            last_cpu: None,
+            cpu: 0,
         }
     }
     pub fn init(&mut self) {}
@@ -75,8 +116,6 @@ impl ListNode<RbTreeLink> for SchInfo {
     }
 }
 
-static SCHED_LOCK: Mutex<()> = Mutex::new(());
-
 global_asm!(include_str!("../aarch64/swtch.asm"));
 extern "C" {
     fn swtch(new: *mut KernelContext, old: *mut *mut KernelContext);
@@ -84,12 +123,18 @@ extern "C" {
 
 pub fn preemptive_sched(timer: &mut Timer) {
     add_cpu_timer(timer);
-    yield_();
+    // The tick may come while this CPU holds any of the locks, so we only try them.
+    if let Some(proc_lock) = try_acquire_proc_lock(thisproc()) {
+        load_balance();
+        if let Some(queue_lock) = get_cpu_sched().lock.try_lock() {
+            switch(proc_lock, queue_lock, ProcessState::Runnable);
+        }
+    }
 }
 
 #[inline(always)]
 pub fn yield_() {
-    if let Some(lock) = try_acquire_sched_lock() {
+    if let Some(lock) = try_acquire_proc_lock(thisproc()) {
         sched(lock, ProcessState::Runnable);
     }
 }
@@ -98,20 +143,21 @@ fn get_cpu_sched() -> &'static mut Sched {
     &mut get_cpu_info().sched
 }
 
+// Note: you should hold the lock of its run queue to touch anything but the atomics.
+fn get_sched_of(cpu: usize) -> &'static mut Sched {
+    unsafe { &mut get_cpu_info_mut(cpu).sched }
+}
+
 #[inline(always)]
 pub fn is_zombie(proc: &Process) -> bool {
-    let lock = acquire_sched_lock();
-    let ret = matches!(proc.state, ProcessState::Zombie);
-    release_sched_lock(lock);
-    ret
+    let _lock = acquire_proc_lock(proc);
+    matches!(proc.state, ProcessState::Zombie)
 }
 
 #[inline(always)]
 pub fn is_unused(proc: &Process) -> bool {
-    let lock = acquire_sched_lock();
-    let ret = matches!(proc.state, ProcessState::Unused);
-    release_sched_lock(lock);
-    ret
+    let _lock = acquire_proc_lock(proc);
+    matches!(proc.state, ProcessState::Unused)
 }
 
 #[inline(always)]
@@ -133,20 +179,24 @@ pub fn thisproc() -> &'static mut Process {
     try_thisproc().unwrap()
 }
 
+// Note: you should hold the lock of `proc`.
 pub fn activate_no_lock(proc: &mut Process) {
     _activate(proc);
 }
 
 pub fn activate(proc: &mut Process) {
-    let lock = acquire_sched_lock();
+    let _lock = acquire_proc_lock(proc);
     _activate(proc);
-    release_sched_lock(lock);
 }
 
 fn _activate(proc: &mut Process) {
     match proc.state {
         ProcessState::Unused | ProcessState::Sleeping => {
-            proc.sch_info.vruntime = MIN_VRUNTIME.load(Ordering::SeqCst);
+            let cpu = select_cpu(proc);
+            let sched = get_sched_of(cpu);
+            let _queue_lock = sched.lock.lock();
+            proc.sch_info.cpu = cpu;
+            proc.sch_info.vruntime = sched.min_vruntime;
             proc.sch_info.start_time = 0;
             update_proc_state(proc, ProcessState::Runnable);
         }
@@ -157,34 +207,30 @@ fn _activate(proc: &mut Process) {
     }
 }
 
-pub fn acquire_sched_lock<'a>() -> MutexGuard<'a, ()> {
-    SCHED_LOCK.lock()
-}
-
-pub fn try_acquire_sched_lock<'a>() -> Option<MutexGuard<'a, ()>> {
-    SCHED_LOCK.try_lock()
-}
-
-pub fn release_sched_lock(_sched_lock: MutexGuard<()>) {
-    // We don't need to do anything here, since the MutexGuard will be dropped at the end of this scope.
+// Lock the state of `proc`: its `state`, `killed` and `signal`, and its `sch_info` unless it is runnable.
+//
+// Note: you should not hold the lock of another process, or of a run queue.
+pub fn acquire_proc_lock(proc: &Process) -> MutexGuard<'static, ()> {
+    // A process is only freed by `reap`, after its last lock is released.
+    unsafe { &*(&proc.lock as *const Mutex<()>) }.lock()
 }
 
-pub unsafe fn force_release_sched_lock() {
-    SCHED_LOCK.force_unlock();
+pub fn try_acquire_proc_lock(proc: &Process) -> Option<MutexGuard<'static, ()>> {
+    unsafe { &*(&proc.lock as *const Mutex<()>) }.try_lock()
 }
 
 // Update the current CPU's process to the next process.
-// Since all processes are in a single queue, we are more likely to pass in a pointer than a reference.
 fn update_this_proc(proc: *mut Process) {
-    get_cpu_sched().cur_proc = Some(proc);
+    let sched = get_cpu_sched();
+    sched.cur_proc = Some(proc);
+    sched.busy.store(unsafe { !(*proc).idle }, Ordering::Relaxed);
 }
 
 pub fn start_idle_proc() {
-    let lock = acquire_sched_lock();
+    let _lock = get_cpu_sched().lock.lock();
     let idle_proc = get_cpu_sched().idle_proc.unwrap();
     unsafe { (*idle_proc).state = ProcessState::Running };
     update_this_proc(idle_proc);
-    release_sched_lock(lock);
 }
 
 const SCHED_PRIO_TO_WEIGHT: [u64; 40] = [
@@ -197,12 +243,105 @@ const SCHED_PRIO_TO_WEIGHT: [u64; 40] = [
     /*  10 */     110, 87, 70, 56, 45,
     /*  15 */     36, 29, 23, 18, 15,
 ];
-const SCHED_MEDIUM_NICE: usize = 20;
+pub const SCHED_MEDIUM_NICE: usize = 20;
 const SCHED_MIN_GRANULARITY_US: u64 = 1000;
 
+// The number of processes on `cpu`, running or waiting. It is only a guess, since we do not lock the queue.
+fn load_of(cpu: usize) -> usize {
+    let sched = get_sched_of(cpu);
+    sched.waiting.load(Ordering::Relaxed) + sched.busy.load(Ordering::Relaxed) as usize
+}
+
+fn online(cpu: usize) -> bool {
+    unsafe { get_cpu_info_mut(cpu).online }
+}
+
+// Choose the CPU to wake `proc` up on: the CPU it ran on last, whose caches may still be warm, unless the current
+// CPU has fewer processes. Only these two are looked at, so that a wakeup costs the same however many CPUs there
+// are; `load_balance` spreads the processes over the others.
+fn select_cpu(proc: &Process) -> usize {
+    let this = get_cpu_id();
+    match proc.sch_info.last_cpu {
+        Some(cpu) if online(cpu) && (!online(this) || load_of(cpu) <= load_of(this)) => cpu,
+        _ => this,
+    }
+}
+
+// Lock the run queues of the current CPU and `other`, the one of the lower CPU first, so that two CPUs moving
+// processes between each other cannot deadlock. With `try_only`, give up instead of waiting for a lock, since a
+// tick may have come while this CPU holds one of them.
+fn lock_pair(other: usize, try_only: bool) -> Option<(MutexGuard<'static, ()>, MutexGuard<'static, ()>)> {
+    let this = get_cpu_id();
+    let lock = |cpu: usize| {
+        let lock = &get_sched_of(cpu).lock;
+        if try_only { lock.try_lock() } else { Some(lock.lock()) }
+    };
+    let first = lock(min(this, other))?;
+    let second = lock(max(this, other))?;
+    Some((first, second))
+}
+
+// Move the process waiting longest in the run queue of `from`, i.e. the one to run last there, to the run
+// queue of the current CPU. Its vruntime is shifted, so that it keeps its place relative to the others.
+//
+// Note: you should hold the locks of both run queues, see `lock_pair`.
+fn migrate_one(from: usize) -> bool {
+    let to = get_cpu_id();
+    let src = get_sched_of(from);
+    let Some(sch_info) = src.run_queue.maximum() else {
+        return false;
+    };
+    let proc = Process::get_parent::<Process>(sch_info);
+    src.dequeue(&mut proc.sch_info);
+    let dst = get_sched_of(to);
+    proc.sch_info.vruntime = proc.sch_info.vruntime.saturating_sub(src.min_vruntime) + dst.min_vruntime;
+    proc.sch_info.cpu = to;
+    dst.enqueue(&mut proc.sch_info);
+    true
+}
+
+// Balance the load with one other CPU, a different one on each tick: pull processes from it until the load of
+// both differs by at most one. Looking at one CPU keeps the tick cheap, and going round evens out the load of
+// all of them within a few ticks.
+fn load_balance() {
+    if CPU_NUM < 2 {
+        return;
+    }
+    let this = get_cpu_id();
+    let sched = get_cpu_sched();
+    let other = (this + 1 + sched.balance_next) % CPU_NUM;
+    sched.balance_next = (sched.balance_next + 1) % (CPU_NUM - 1);
+    let moves = load_of(other).saturating_sub(load_of(this)) / 2;
+    if moves == 0 {
+        return;
+    }
+    if let Some(_locks) = lock_pair(other, true) {
+        for _ in 0..moves {
+            if !migrate_one(other) {
+                break;
+            }
+        }
+    }
+}
+
+// Take a process from the first other CPU with one waiting, since the current CPU has nothing to run.
+fn steal() {
+    let this = get_cpu_id();
+    for i in 1..CPU_NUM {
+        let cpu = (this + i) % CPU_NUM;
+        if get_sched_of(cpu).waiting.load(Ordering::Relaxed) == 0 {
+            continue;
+        }
+        let _locks = lock_pair(cpu, false);
+        if migrate_one(cpu) {
+            return;
+        }
+    }
+}
+
 // Choose the next process to run.
 fn pick_next() -> *mut Process {
-    let sch_info = unsafe { RUN_QUEUE.assume_init_mut().minimum() };
+    let sch_info = get_cpu_sched().run_queue.minimum();
     if let Some(sch_info) = sch_info {
         let proc = Process::get_parent::<Process>(sch_info);
         let this = thisproc();
@@ -217,6 +356,7 @@ fn pick_next() -> *mut Process {
     }
 }
 
+// Note: you should hold the lock of the run queue the process is in or goes to.
 fn update_proc_state(proc: &mut Process, state: ProcessState) {
     if proc.state == state {
         return;
@@ -225,49 +365,38 @@ fn update_proc_state(proc: &mut Process, state: ProcessState) {
     if proc.idle {
         return;
     }
+    // A process becoming runnable joins the queue chosen by `_activate`, or ours if it was running here.
+    let cpu = proc.sch_info.cpu;
     match proc.state {
         ProcessState::Unused => panic!("Try to set a process to unused state"),
         ProcessState::Runnable => {
-            unsafe {
-                RUN_QUEUE.assume_init_mut().insert(&mut proc.sch_info);
-            }
+            get_sched_of(cpu).enqueue(&mut proc.sch_info);
         }
         ProcessState::Running | ProcessState::Sleeping | ProcessState::Zombie => {
-            unsafe {
-                RUN_QUEUE.assume_init_mut().delete(&mut proc.sch_info);
-            }
+            get_sched_of(cpu).dequeue(&mut proc.sch_info);
         }
     }
 }
 
 fn update_this_state(state: ProcessState) {
-    update_proc_state(thisproc(), state)
-}
-
-fn get_min_vruntime() -> u64 {
-    let mut valid = false;
-    let mut min_vruntime = u64::MAX;
-    let sch_info = unsafe { RUN_QUEUE.assume_init_mut().minimum() };
-    if let Some(sch_info) = sch_info {
-        min_vruntime = min(min_vruntime, sch_info.vruntime);
-        valid = true;
-    }
-    for i in 0..CPU_NUM {
-        unsafe {
-            if let Some(proc) = get_cpu_info_ref(i).sched.cur_proc {
-                let proc = unsafe { &mut *proc };
-                if proc.idle {
-                    continue;
-                }
-                min_vruntime = min(min_vruntime, proc.sch_info.vruntime);
-                valid = true;
-            }
+    let this = thisproc();
+    this.sch_info.cpu = get_cpu_id();
+    update_proc_state(this, state)
+}
+
+// Move the current CPU's min_vruntime up to the least vruntime of its processes, if any.
+fn update_min_vruntime() {
+    let sched = get_cpu_sched();
+    let mut min_vruntime = sched.run_queue.minimum().map(|sch_info| sch_info.vruntime);
+    if let Some(proc) = sched.cur_proc {
+        let proc = unsafe { &*proc };
+        if !proc.idle {
+            let vruntime = proc.sch_info.vruntime;
+            min_vruntime = Some(min_vruntime.map_or(vruntime, |v| min(v, vruntime)));
         }
     }
-    if valid {
-        min_vruntime
-    } else {
-        0
+    if let Some(min_vruntime) = min_vruntime {
+        sched.min_vruntime = max(sched.min_vruntime, min_vruntime);
     }
 }
 
@@ -277,22 +406,32 @@ fn stop_tick_and_update_vruntime(cur: &mut Process) {
         cur.sch_info.vruntime += wall_time / SCHED_PRIO_TO_WEIGHT[SCHED_MEDIUM_NICE] * SCHED_PRIO_TO_WEIGHT[cur.sch_info.nice];
     }
     // Update the min vruntime.
-    MIN_VRUNTIME.store(get_min_vruntime(), Ordering::SeqCst);
+    update_min_vruntime();
 }
 
 fn start_tick(cur: &mut Process) {
     cur.sch_info.start_time = get_time_us();
 }
 
-pub fn sched(sched_lock: MutexGuard<()>, new_state: ProcessState) {
+// Give the CPU up, leaving the current process in `new_state`. `proc_lock` is the lock of the current process.
+pub fn sched(proc_lock: MutexGuard<'static, ()>, new_state: ProcessState) {
+    if get_cpu_sched().waiting.load(Ordering::Relaxed) == 0 {
+        steal();
+    }
+    let queue_lock = get_cpu_sched().lock.lock();
+    switch(proc_lock, queue_lock, new_state);
+}
+
+fn switch(proc_lock: MutexGuard<'static, ()>, queue_lock: MutexGuard<'static, ()>, new_state: ProcessState) {
     assert!(!matches!(new_state, ProcessState::Unused | ProcessState::Running));
 
     let this = thisproc();
     assert!(matches!(this.state, ProcessState::Running));
 
     // Refuse to schedule a killed process. It should be awaken until it is exited.
-    if this.killed && new_state != ProcessState::Zombie {
-        release_sched_lock(sched_lock);
+    // Likewise, a process with a signal to handle should not sleep.
+    if this.killed && new_state != ProcessState::Zombie
+        || new_state == ProcessState::Sleeping && this.signal.has_deliverable() {
         return;
     }
     stop_tick_and_update_vruntime(this);
@@ -305,15 +444,31 @@ pub fn sched(sched_lock: MutexGuard<()>, new_state: ProcessState) {
     let next = unsafe { &mut *next };
     assert_matches!(next.state, ProcessState::Runnable);
     update_proc_state(next, ProcessState::Running);
+    next.sch_info.last_cpu = Some(get_cpu_id());
     start_tick(next);
     if next.pid != this.pid {
+        // Both locks are released by `next`.
+        forget(proc_lock);
+        forget(queue_lock);
+        get_cpu_sched().prev = Some(this);
         unsafe {
             next.pgdir.attach();
             swtch(next.kernel_context, &mut this.kernel_context);
         }
+        // When executing this line, we have been back to the process that was running before the call to `sched`,
+        // maybe on another CPU.
+        finish_switch();
+    }
+}
+
+// Release the locks `switch` held across `swtch` for the process we have switched from.
+fn finish_switch() {
+    let sched = get_cpu_sched();
+    let prev = sched.prev.take().unwrap();
+    unsafe {
+        (*prev).lock.force_unlock();
+        sched.lock.force_unlock();
     }
-    // When executing this line, we have been back to the process that was running before the call to `sched`.
-    release_sched_lock(sched_lock);
 }
 
 extern {
@@ -322,8 +477,8 @@ extern {
 }
 
 pub extern "C" fn proc_entry(real_entry: extern "C" fn(usize), arg: usize) -> usize {
+    finish_switch();
     unsafe {
-        force_release_sched_lock();
         // Return to real_entry
         addr_of_return_address().write_volatile(real_entry);
     }
@@ -1,14 +1,16 @@
+use alloc::vec::Vec;
+use core::mem::MaybeUninit;
 use crate::aarch64::intrinsic::addr::{EMMC_BLKSIZECNT, EMMC_DATA, EMMC_INTERRUPT};
 use crate::aarch64::intrinsic::{get_u32, put_u32};
 use crate::common::list::{ListLink, ListNode};
 use crate::common::sem::Semaphore;
 use crate::driver::interrupt::{set_interrupt_handler, InterruptType};
-use crate::kernel::sd_def::{sd_send_command_a, sd_wait_for_interrupt, INT_WRITE_RDY, IX_READ_SINGLE, IX_WRITE_SINGLE, SD_CARD, SD_TYPE_2_HC};
+use crate::kernel::sd_def::{sd_reset_lines, sd_send_command_a, sd_wait_for_interrupt, INT_CMD_TIMEOUT, INT_DATA_DONE, INT_DATA_TIMEOUT, INT_ERROR_MASK, INT_READ_RDY, INT_WRITE_RDY, IX_READ_MULTI, IX_READ_SINGLE, IX_SET_BLOCKCNT, IX_STOP_TRANS, IX_WRITE_MULTI, IX_WRITE_SINGLE, SD_CARD, SD_ERROR, SD_OK, SD_SUPP_SET_BLOCK_COUNT, SD_TIMEOUT, SD_TYPE_2_HC};
 use crate::{define_rest_init, dsb_sy, println};
 use field_offset::offset_of;
 use spin::Mutex;
 
-use super::mbr::MBR;
+use super::partition::{Partition, scan};
 use super::sd_def::sd_init;
 
 pub const B_VALID: u32 = 0x2; /* Buffer has been read from disk. */
@@ -20,6 +22,7 @@ pub struct Buffer {
     pub data: [u8; 512],
     link: ListLink,
     sleep: Semaphore,
+    status: u32,
 }
 
 impl Buffer {
@@ -30,6 +33,7 @@ impl Buffer {
             data: [0; 512],
             link: ListLink::uninit(),
             sleep: Semaphore::uninit(0),
+            status: SD_OK,
         }
     }
 
@@ -53,14 +57,37 @@ impl ListNode<ListLink> for Buffer {
     }
 }
 
+// At most this many buffers are merged into a request.
+const SD_MAX_MERGE: usize = 16;
+const SD_MAX_RETRIES: usize = 3;
+
+// The buffers being transferred by the card. They are taken off `BUF_QUEUE`.
+struct Request {
+    bufs: [*mut Buffer; SD_MAX_MERGE],
+    count: usize,
+    // Number of blocks read so far.
+    done: usize,
+    write: bool,
+    retries: usize,
+}
+
 static mut BUF_QUEUE: ListLink = ListLink::uninit();
+static mut REQUEST: Request = Request {
+    bufs: [core::ptr::null_mut(); SD_MAX_MERGE],
+    count: 0,
+    done: 0,
+    write: false,
+    retries: 0,
+};
 static SD_LOCK: Mutex<()> = Mutex::new(());
+static mut SD_PARTITIONS: MaybeUninit<Vec<Partition>> = MaybeUninit::uninit();
 /*
- * Initialize SD card and parse MBR.
+ * Initialize SD card and scan its partition table (MBR or GPT).
  * 1. The first partition should be FAT and is used for booting.
- * 2. The second partition is used by our file system.
+ * 2. The first Linux data partition is used by our file system.
  *
  * See https://en.wikipedia.org/wiki/Master_boot_record
+ * and https://en.wikipedia.org/wiki/GUID_Partition_Table
  */
 pub fn init_sd() {
     // * 1.call sdInit.
@@ -84,123 +111,251 @@ pub fn init_sd() {
     set_interrupt_handler(InterruptType::IRQ_SDIO, sd_interrupt_handler);
     set_interrupt_handler(InterruptType::IRQ_ARASANSDIO, sd_interrupt_handler);
     drop(lock);
-    let mut buf = Buffer::read_uninit(0);
-    buf.init();
-    sd_rw(&mut buf);
-    let mbr = MBR::parse(&buf.data);
-    println!("MBR: {:?}", mbr);
+    let partitions = scan(|block_no, data| {
+        let mut buf = Buffer::read_uninit(block_no as u32);
+        buf.init();
+        match sd_rw(&mut buf) {
+            Ok(()) => data.copy_from_slice(&buf.data),
+            Err(code) => {
+                println!("init_sd: failed to read block {}: {}", block_no, code);
+                data.fill(0);
+            }
+        }
+    });
+    for partition in &partitions {
+        println!("Partition: {:?}", partition);
+    }
+    unsafe {
+        SD_PARTITIONS = MaybeUninit::new(partitions);
+    }
 }
 define_rest_init!(init_sd);
 
-fn sd_start(buf: &Buffer) {
-    let block_no = if unsafe { SD_CARD.typ } == SD_TYPE_2_HC {
-        buf.block_no
+// Get the partitions found by `init_sd`.
+pub fn get_partitions() -> &'static [Partition] {
+    unsafe { SD_PARTITIONS.assume_init_ref() }
+}
+
+fn is_write(buf: &Buffer) -> bool {
+    buf.flags & B_DIRTY != 0
+}
+
+// Find the oldest queued buffer at `block_no`. It can join the current request only if it goes in the same
+// direction; otherwise, it must wait so that it is not reordered with that buffer.
+unsafe fn find_adjacent(block_no: u32, write: bool) -> Option<*mut Buffer> {
+    let head = &mut BUF_QUEUE as *mut ListLink;
+    let mut link = BUF_QUEUE.prev;
+    while link != head {
+        let buf = Buffer::container_ptr::<Buffer>(link);
+        if (*buf).block_no == block_no {
+            return if is_write(&*buf) == write { Some(buf) } else { None };
+        }
+        link = (*link).prev;
+    }
+    None
+}
+
+// Take the oldest buffer in the queue as a new request, and merge buffers of the following blocks into it.
+// Return false if the queue is empty.
+unsafe fn collect_request() -> bool {
+    let first = match BUF_QUEUE.prev::<Buffer>() {
+        Some(buf) => buf as *mut Buffer,
+        None => return false,
+    };
+    (*first).link.detach();
+    let write = is_write(&*first);
+    REQUEST.bufs[0] = first;
+    REQUEST.count = 1;
+    REQUEST.write = write;
+    while REQUEST.count < SD_MAX_MERGE {
+        let next_block = (*REQUEST.bufs[REQUEST.count - 1]).block_no.wrapping_add(1);
+        match find_adjacent(next_block, write) {
+            Some(buf) => {
+                (*buf).link.detach();
+                REQUEST.bufs[REQUEST.count] = buf;
+                REQUEST.count += 1;
+            }
+            None => break,
+        }
+    }
+    true
+}
+
+// Issue the commands of the current request. For writes, the data is sent here as well.
+unsafe fn sd_start() -> Result<(), u32> {
+    let first = &*REQUEST.bufs[0];
+    let addr = if SD_CARD.typ == SD_TYPE_2_HC {
+        first.block_no
     } else {
-        buf.block_no << 9
+        first.block_no << 9
     };
-    let write = buf.flags & B_DIRTY != 0;
+    let count = REQUEST.count as u32;
     dsb_sy();
-    if get_u32(EMMC_INTERRUPT) != 0 {
-        panic!("sd_start: interrupt before start");
+    let ival = get_u32(EMMC_INTERRUPT);
+    if ival != 0 {
+        // Left over by a failed transfer.
+        put_u32(EMMC_INTERRUPT, ival);
+        return Err(SD_ERROR);
     }
     dsb_sy();
-    let cmd_index = if write {
-        IX_WRITE_SINGLE
-    } else {
-        IX_READ_SINGLE
+    let cmd_index = match (count > 1, REQUEST.write) {
+        (false, false) => IX_READ_SINGLE,
+        (false, true) => IX_WRITE_SINGLE,
+        (true, false) => IX_READ_MULTI,
+        (true, true) => IX_WRITE_MULTI,
     };
-    put_u32(EMMC_BLKSIZECNT, 512);
-    let resp = unsafe { sd_send_command_a(cmd_index, block_no) };
-    if resp.is_err() {
-        panic!("sd_start: sd_send_command_a failed");
+    if count > 1 && SD_CARD.support & SD_SUPP_SET_BLOCK_COUNT != 0 {
+        sd_send_command_a(IX_SET_BLOCKCNT, count)?;
     }
+    put_u32(EMMC_BLKSIZECNT, (count << 16) | 512);
+    sd_send_command_a(cmd_index, addr)?;
 
-    let data_ptr = &buf.data as *const _ as usize;
+    if REQUEST.write {
+        for buf in &REQUEST.bufs[..REQUEST.count] {
+            sd_wait_for_interrupt(INT_WRITE_RDY)?;
+            for qword in block_words(&mut **buf).iter() {
+                put_u32(EMMC_DATA, *qword);
+            }
+        }
+    }
+    Ok(())
+}
+
+fn block_words(buf: &mut Buffer) -> &mut [u32; 512 / (32 / 8)] {
+    let data_ptr = &mut buf.data as *mut _ as usize;
     assert_eq!(
         data_ptr & 0x3,
         0,
-        "sd_start: data_ptr is not 4-byte aligned"
+        "sd: data_ptr is not 4-byte aligned"
     );
+    unsafe { &mut *(data_ptr as *mut [u32; 512 / (32 / 8)]) }
+}
+
+// Move the current request forward on an interrupt. Return whether it is finished.
+unsafe fn sd_continue() -> Result<bool, u32> {
+    let ival = get_u32(EMMC_INTERRUPT);
+    if ival & (INT_CMD_TIMEOUT | INT_DATA_TIMEOUT) != 0 {
+        put_u32(EMMC_INTERRUPT, ival);
+        return Err(SD_TIMEOUT);
+    } else if ival & INT_ERROR_MASK != 0 {
+        put_u32(EMMC_INTERRUPT, ival);
+        return Err(SD_ERROR);
+    }
+    if !REQUEST.write && ival & INT_READ_RDY != 0 && REQUEST.done < REQUEST.count {
+        put_u32(EMMC_INTERRUPT, INT_READ_RDY);
+        for qword in block_words(&mut *REQUEST.bufs[REQUEST.done]).iter_mut() {
+            *qword = get_u32(EMMC_DATA);
+        }
+        REQUEST.done += 1;
+    }
+    let finished = if REQUEST.write {
+        ival & INT_DATA_DONE != 0
+    } else {
+        REQUEST.done == REQUEST.count
+    };
+    if !finished {
+        return Ok(false);
+    }
+    if ival & INT_DATA_DONE == 0 {
+        sd_wait_for_interrupt(INT_DATA_DONE)?;
+    }
+    put_u32(EMMC_INTERRUPT, get_u32(EMMC_INTERRUPT));
+    // Without CMD23, the card keeps transferring until it is told to stop.
+    if REQUEST.count > 1 && SD_CARD.support & SD_SUPP_SET_BLOCK_COUNT == 0 {
+        sd_send_command_a(IX_STOP_TRANS, 0)?;
+    }
+    Ok(true)
+}
 
-    if write {
-        let resp = sd_wait_for_interrupt(INT_WRITE_RDY);
-        if resp.is_err() {
-            panic!("sd_start: sd_wait_for_interrupt timeout");
+// Complete all buffers of the current request, and make the card idle.
+unsafe fn sd_finish(result: Result<(), u32>) {
+    for buf in &REQUEST.bufs[..REQUEST.count] {
+        let buf = &mut **buf;
+        match result {
+            Ok(()) => {
+                buf.flags &= !B_DIRTY;
+                buf.flags |= B_VALID;
+                buf.status = SD_OK;
+            }
+            Err(code) => buf.status = code,
         }
-        if get_u32(EMMC_INTERRUPT) != 0 {
-            panic!("sd_start: interrupt before start");
+        // The owner may free the buffer as soon as it is woken up, so this must be the last access.
+        buf.sleep.post();
+    }
+    REQUEST.count = 0;
+    REQUEST.done = 0;
+    REQUEST.retries = 0;
+}
+
+// Recover from a failed transfer by resetting the command and data lines, or the whole card if that does not work.
+// The current request is restarted from scratch, until it has failed `SD_MAX_RETRIES` times.
+unsafe fn sd_fail(code: u32) {
+    println!("sd: request of {} blocks at {} failed with {}, retried {} times",
+             REQUEST.count, (*REQUEST.bufs[0]).block_no, code, REQUEST.retries);
+    let reset = sd_reset_lines().or_else(|_| {
+        SD_CARD.init = false;
+        sd_init()
+    });
+    if reset.is_ok() && REQUEST.retries < SD_MAX_RETRIES {
+        REQUEST.retries += 1;
+        REQUEST.done = 0;
+    } else {
+        sd_finish(Err(code));
+    }
+}
+
+// Start the current request, which is being retried, or the next one if there is none.
+unsafe fn sd_kick() {
+    loop {
+        if REQUEST.count == 0 && !collect_request() {
+            return;
         }
-        let data = unsafe { &*(data_ptr as *const [u32; 512 / (32 / 8)]) };
-        for qword in data {
-            put_u32(EMMC_DATA, *qword);
+        match sd_start() {
+            Ok(()) => return,
+            Err(code) => sd_fail(code),
         }
     }
 }
 
 pub fn sd_interrupt_handler() {
-    /*
-     * Pay attention to whether there is any element in the buflist.
-     * Understand the meanings of EMMC_INTERRUPT, EMMC_DATA, INT_DATA_DONE,
-     * INT_READ_RDY, B_DIRTY, B_VALID and some other flags.
-     *
-     * Notice that reading and writing are different, you can use flags
-     * to identify.
-     *
-     * If B_DIRTY is set, write buf to disk, clear B_DIRTY, set B_VALID.
-     * Else if B_VALID is not set, read buf from disk, set B_VALID.
-     *
-     * Remember to clear the flags after reading/writing.
-     *
-     * When finished, remember to use pop and check whether the list is
-     * empty, if not, continue to read/write.
-     *
-     * You may use some buflist functions, arch_dsb_sy(), sd_start(), post_sem()
-     * and sdWaitForInterrupt() to complete this function.
-     *
-     * TODO: Lab5 driver.
-     */
     let _lock = SD_LOCK.lock();
-    let buf = unsafe { BUF_QUEUE.prev::<Buffer>().unwrap() };
-    let mut done = false;
-    if (buf.flags & B_DIRTY) != 0 {
-        done = true;
-    } else if buf.flags & B_VALID == 0 {
-        // Read data from EMMC
-        let data_ptr = &mut buf.data as *mut _ as usize;
-        let data = unsafe { &mut *(data_ptr as *mut [u32; 512 / (32 / 8)]) };
-        for qword in data {
-            *qword = get_u32(EMMC_DATA);
+    unsafe {
+        if REQUEST.count == 0 {
+            // Nothing is in flight.
+            put_u32(EMMC_INTERRUPT, get_u32(EMMC_INTERRUPT));
+            return;
         }
-        done = true;
-    }
-    if done {
-        put_u32(EMMC_INTERRUPT, get_u32(EMMC_INTERRUPT));
-        buf.flags &= !B_DIRTY;
-        buf.flags |= B_VALID;
-        buf.link.detach();
-        buf.sleep.post();
-    }
-    if let Some(next) = unsafe { BUF_QUEUE.prev::<Buffer>() } {
-        sd_start(next);
+        match sd_continue() {
+            Ok(true) => sd_finish(Ok(())),
+            Ok(false) => return,
+            Err(code) => sd_fail(code),
+        }
+        sd_kick();
     }
 }
 
-pub fn sd_rw(buf: &mut Buffer) {
-    // * 1.add buf to the queue
-    //  * 2.if no buf in queue before,send request now
-    //  * 3.'loop' until buf flag is modified
-    //  *
-    //  * You may use some buflist functions, arch_dsb_sy(),
-    //  * sd_start(), wait_sem() to complete this function.
-    //  *  TODO: Lab5 driver.
-    let lock = SD_LOCK.lock();
-    let single = unsafe { BUF_QUEUE.is_single() };
+// Queue `buf` for reading (or writing, if `B_DIRTY` is set), without waiting for it.
+// Buffers of adjacent blocks queued together are transferred by a single command.
+pub fn sd_submit(buf: &mut Buffer) {
+    let _lock = SD_LOCK.lock();
     unsafe {
         BUF_QUEUE.insert_at_first(buf);
+        if REQUEST.count == 0 {
+            sd_kick();
+        }
     }
-    if single {
-        sd_start(buf);
-    }
-    drop(lock);
+}
+
+// Wait for a buffer queued by `sd_submit`, and return the error code of the SD card if it failed.
+pub fn sd_wait(buf: &mut Buffer) -> Result<(), u32> {
     buf.sleep.get_or_wait();
-}
\ No newline at end of file
+    match buf.status {
+        SD_OK => Ok(()),
+        code => Err(code),
+    }
+}
+
+pub fn sd_rw(buf: &mut Buffer) -> Result<(), u32> {
+    sd_submit(buf);
+    sd_wait(buf)
+}
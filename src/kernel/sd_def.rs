@@ -778,6 +778,28 @@ unsafe fn sd_set_clock(freq: u32) -> Result<u32, u32> {
     Ok(SD_OK)
 }
 
+// Reset the command and data lines after a failed transfer. Unlike `sd_reset_card`, the card stays initialized.
+pub unsafe fn sd_reset_lines() -> Result<u32, u32> {
+    let reset_type = C1_SRST_CMD | C1_SRST_DATA;
+    let ctrl = get_u32(EMMC_CONTROL1);
+    put_u32(EMMC_CONTROL1, ctrl | reset_type);
+    sd_delay_us(10);
+
+    let mut timeout = 10_000;
+    while get_u32(EMMC_CONTROL1) & reset_type != 0 && timeout > 0 {
+        timeout -= 1;
+        sd_delay_us(10);
+    }
+    put_u32(EMMC_INTERRUPT, get_u32(EMMC_INTERRUPT));
+    if timeout <= 0 {
+        return Err(SD_ERROR_RESET);
+    }
+    // The card may still be in the middle of a multi-block transfer.
+    let _ = sd_send_command_a(IX_STOP_TRANS, 0);
+    put_u32(EMMC_INTERRUPT, get_u32(EMMC_INTERRUPT));
+    Ok(SD_OK)
+}
+
 unsafe fn sd_reset_card(reset_type: u32) -> Result<u32, u32> {
     put_u32(EMMC_CONTROL0, 0);
     let ctrl = get_u32(EMMC_CONTROL1);
@@ -0,0 +1,313 @@
+/*
+ * Process groups, sessions and the controlling terminal.
+ *
+ * Every process belongs to a process group, and every group to a session. Both are named by the pid of the
+ * process that created them, their leader. A new process joins the group and the session of its parent, and
+ * can move with `setpgid` and `setsid`, so that a shell puts each job into a group of its own and signals the
+ * whole job with `kill(-pgid, sig)`.
+ *
+ * The console is the only terminal. A session leader makes it the controlling terminal of its session with
+ * `ioctl(TIOCSCTTY)`, and the session picks its foreground group with `ioctl(TIOCSPGRP)`. The signals typed on
+ * the terminal, e.g. SIGINT for Ctrl-C, go to the foreground group (see `tty_signal`). When the session leader
+ * exits, the foreground group gets SIGHUP and the terminal is free again.
+ *
+ * A group is orphaned if none of its members has a parent in another group of the same session, i.e. no shell
+ * is left to resume it. When an exit orphans a group with a stopped member, every member gets SIGHUP and then
+ * SIGCONT, as POSIX requires, so that the group does not stay stopped forever.
+ *
+ * Lock order: the lock of the process tree, then `TTY`, then the lock of each process.
+ */
+use alloc::vec::Vec;
+use spin::Mutex;
+use crate::common::list::ListNode;
+use crate::define_syscall;
+use crate::kernel::errno::Errno::{self, EBADF, EINVAL, ENOTTY, EPERM, ESRCH};
+use crate::kernel::errno::SyscallResult;
+use crate::kernel::proc::{find_proc, for_each_proc, root_proc, send_signal_where, Process, PROC_LOCK};
+use crate::kernel::sched::{acquire_proc_lock, is_zombie, thisproc};
+use crate::kernel::signal::{SIGCONT, SIGHUP};
+use crate::kernel::syscall::{Fd, SYS_GETPGID, SYS_GETSID, SYS_IOCTL, SYS_SETPGID, SYS_SETSID};
+use crate::kernel::uaccess::UserPtr;
+
+// Requests of `ioctl` on the terminal, as on Linux.
+pub const TIOCSCTTY: u32 = 0x540E;
+pub const TIOCGPGRP: u32 = 0x540F;
+pub const TIOCSPGRP: u32 = 0x5410;
+pub const TIOCNOTTY: u32 = 0x5422;
+pub const TIOCGSID: u32 = 0x5429;
+
+// The console. 0 means no session or no foreground group.
+struct Tty {
+    session: usize,
+    foreground: usize,
+}
+
+static TTY: Mutex<Tty> = Mutex::new(Tty { session: 0, foreground: 0 });
+
+fn parent_of(proc: &Process) -> &'static mut Process {
+    unsafe { &mut *proc.parent.unwrap() }
+}
+
+// Find the process `pid`, or the current process if `pid` is 0.
+//
+// Note: you should hold the lock of the process tree.
+fn find_proc_or_self(pid: usize) -> Result<&'static mut Process, Errno> {
+    match pid {
+        0 => Ok(thisproc()),
+        pid => find_proc(pid).ok_or(ESRCH),
+    }
+}
+
+// Whether the group `pgid` exists in the session `sid`.
+//
+// Note: you should hold the lock of the process tree.
+fn group_exists(pgid: usize, sid: usize) -> bool {
+    let mut exists = false;
+    for_each_proc(|proc| exists |= proc.pgid == pgid && proc.sid == sid);
+    exists
+}
+
+// Send `sig` to every process of the group `pgid`.
+// If `uid` is given, it must be allowed to signal at least one of them, see `send_signal_to`.
+pub fn send_signal_to_group(pgid: usize, sig: usize, uid: Option<u32>) -> Result<(), Errno> {
+    let _lock = PROC_LOCK.lock();
+    send_signal_where(|proc| proc.pgid == pgid, sig, uid)
+}
+
+pub fn kill_pgrp(pgid: usize, sig: usize) -> Result<(), Errno> {
+    send_signal_to_group(pgid, sig, None)
+}
+
+// Move the process `pid` (0 for the current process) into the group `pgid` (0 for a new group led by it).
+// The process must be the caller or one of its children, in the same session, and not a session leader.
+// The group must be new or in the same session.
+pub fn setpgid(pid: usize, pgid: usize) -> Result<(), Errno> {
+    let me = thisproc();
+    let _lock = PROC_LOCK.lock();
+    let proc = find_proc_or_self(pid)?;
+    if proc.pid != me.pid && (proc.parent.is_none() || parent_of(proc).pid != me.pid || is_zombie(proc)) {
+        return Err(ESRCH);
+    }
+    if proc.sid != me.sid || proc.sid == proc.pid {
+        return Err(EPERM);
+    }
+    let pgid = if pgid == 0 { proc.pid } else { pgid };
+    if pgid != proc.pid && !group_exists(pgid, me.sid) {
+        return Err(EPERM);
+    }
+    proc.pgid = pgid;
+    Ok(())
+}
+
+pub fn getpgid(pid: usize) -> Result<usize, Errno> {
+    let _lock = PROC_LOCK.lock();
+    Ok(find_proc_or_self(pid)?.pgid)
+}
+
+// Make the current process the leader of a new session and of a new group in it, without a controlling
+// terminal. Return the new session ID. A group leader cannot do this, for its group would span two sessions.
+pub fn setsid() -> Result<usize, Errno> {
+    let me = thisproc();
+    let _lock = PROC_LOCK.lock();
+    let mut leads_group = false;
+    for_each_proc(|proc| leads_group |= proc.pgid == me.pid);
+    if leads_group {
+        return Err(EPERM);
+    }
+    me.sid = me.pid;
+    me.pgid = me.pid;
+    Ok(me.sid)
+}
+
+pub fn getsid(pid: usize) -> Result<usize, Errno> {
+    let _lock = PROC_LOCK.lock();
+    Ok(find_proc_or_self(pid)?.sid)
+}
+
+// Check that the terminal is the controlling terminal of the current process.
+fn check_ctty(tty: &Tty) -> Result<(), Errno> {
+    if tty.session == 0 || tty.session != thisproc().sid {
+        return Err(ENOTTY);
+    }
+    Ok(())
+}
+
+// Make the terminal the controlling terminal of the session led by the current process, with the group of the
+// caller in the foreground. Fail if it is the controlling terminal of another session.
+pub fn tiocsctty() -> Result<(), Errno> {
+    let me = thisproc();
+    let _lock = PROC_LOCK.lock();
+    let mut tty = TTY.lock();
+    if me.sid != me.pid || (tty.session != 0 && tty.session != me.sid) {
+        return Err(EPERM);
+    }
+    if tty.session != me.sid {
+        tty.session = me.sid;
+        tty.foreground = me.pgid;
+    }
+    Ok(())
+}
+
+// Hang up the terminal: send SIGHUP and SIGCONT to the foreground group except `exiting`, and free it.
+//
+// Note: you should hold the lock of the process tree.
+fn hangup(tty: &mut Tty, exiting: usize) {
+    let foreground = tty.foreground;
+    for sig in [SIGHUP, SIGCONT] {
+        let _ = send_signal_where(|proc| proc.pgid == foreground && proc.pid != exiting, sig, None);
+    }
+    tty.session = 0;
+    tty.foreground = 0;
+}
+
+// Give the controlling terminal up. The terminal belongs to the whole session, so only the session leader can do
+// this, which hangs up the foreground group as its exit would. For any other member this does nothing.
+pub fn tiocnotty() -> Result<(), Errno> {
+    let me = thisproc();
+    let _lock = PROC_LOCK.lock();
+    let mut tty = TTY.lock();
+    check_ctty(&tty)?;
+    if me.sid == me.pid {
+        hangup(&mut tty, 0);
+    }
+    Ok(())
+}
+
+pub fn tcgetpgrp() -> Result<usize, Errno> {
+    let tty = TTY.lock();
+    check_ctty(&tty)?;
+    Ok(tty.foreground)
+}
+
+// Put the group `pgid`, which must be in the session of the terminal, in the foreground.
+pub fn tcsetpgrp(pgid: usize) -> Result<(), Errno> {
+    let _lock = PROC_LOCK.lock();
+    let mut tty = TTY.lock();
+    check_ctty(&tty)?;
+    if !group_exists(pgid, tty.session) {
+        return Err(EPERM);
+    }
+    tty.foreground = pgid;
+    Ok(())
+}
+
+pub fn tcgetsid() -> Result<usize, Errno> {
+    let tty = TTY.lock();
+    check_ctty(&tty)?;
+    Ok(tty.session)
+}
+
+// Send `sig` to the foreground group of the terminal, for input such as Ctrl-C.
+pub fn tty_signal(sig: usize) {
+    let _lock = PROC_LOCK.lock();
+    let tty = TTY.lock();
+    if tty.foreground != 0 {
+        let foreground = tty.foreground;
+        let _ = send_signal_where(|proc| proc.pgid == foreground, sig, None);
+    }
+}
+
+// Whether the group `pgid` is orphaned once `exiting` is gone, and whether it has a stopped member.
+// The children of `exiting` count as children of the root process, which they are about to be.
+//
+// Note: you should hold the lock of the process tree.
+fn orphaned_and_stopped(pgid: usize, exiting: &Process) -> (bool, bool) {
+    let (mut orphaned, mut stopped) = (true, false);
+    for_each_proc(|proc| {
+        if proc.pgid != pgid || proc.pid == exiting.pid || proc.parent.is_none() {
+            return;
+        }
+        let mut parent = parent_of(proc);
+        if parent.pid == exiting.pid {
+            parent = root_proc();
+        }
+        if parent.pgid != pgid && parent.sid == proc.sid {
+            orphaned = false;
+        }
+        let _lock = acquire_proc_lock(proc);
+        stopped |= proc.signal.stopped;
+    });
+    (orphaned, stopped)
+}
+
+// Called by `exit` before the children of `proc` are given to the root process: hang up the terminal if `proc`
+// leads its session, and send SIGHUP and SIGCONT to each group that `proc` orphans if it has a stopped member.
+// Only our group, tied to the session by our parent, and the groups of our children, tied by us, may be orphaned.
+//
+// Note: you should hold the lock of the process tree.
+pub(super) fn leave_session(proc: &Process) {
+    {
+        let mut tty = TTY.lock();
+        if proc.sid == proc.pid && tty.session == proc.sid {
+            hangup(&mut tty, proc.pid);
+        }
+    }
+
+    let mut groups = Vec::new();
+    if let Some(parent) = proc.parent {
+        let parent = unsafe { &*parent };
+        if parent.pgid != proc.pgid && parent.sid == proc.sid {
+            groups.push(proc.pgid);
+        }
+    }
+    if let Some(first_child) = proc.first_child() {
+        for child in first_child.link().iter::<Process>(false) {
+            if child.pgid != proc.pgid && child.sid == proc.sid && !groups.contains(&child.pgid) {
+                groups.push(child.pgid);
+            }
+        }
+    }
+    for pgid in groups {
+        if orphaned_and_stopped(pgid, proc) == (true, true) {
+            for sig in [SIGHUP, SIGCONT] {
+                let _ = send_signal_where(|member| member.pgid == pgid && member.pid != proc.pid, sig, None);
+            }
+        }
+    }
+}
+
+define_syscall!(SYS_SETPGID, fn sys_setpgid(pid: i32, pgid: i32) -> SyscallResult {
+    if pid < 0 || pgid < 0 {
+        return Err(EINVAL);
+    }
+    setpgid(pid as usize, pgid as usize)?;
+    Ok(0)
+});
+
+define_syscall!(SYS_GETPGID, fn sys_getpgid(pid: i32) -> SyscallResult {
+    if pid < 0 {
+        return Err(ESRCH);
+    }
+    Ok(getpgid(pid as usize)? as u64)
+});
+
+define_syscall!(SYS_GETSID, fn sys_getsid(pid: i32) -> SyscallResult {
+    if pid < 0 {
+        return Err(ESRCH);
+    }
+    Ok(getsid(pid as usize)? as u64)
+});
+
+define_syscall!(SYS_SETSID, fn sys_setsid() -> SyscallResult {
+    Ok(setsid()? as u64)
+});
+
+// Only the terminal requests above are supported, on the standard streams, which are all the console.
+define_syscall!(SYS_IOCTL, fn sys_ioctl(fd: Fd, request: u32, arg: usize) -> SyscallResult {
+    if fd.0 > 2 {
+        return Err(EBADF);
+    }
+    let pgrp = UserPtr::<i32>::new(arg);
+    match request {
+        TIOCSCTTY => tiocsctty()?,
+        TIOCNOTTY => tiocnotty()?,
+        TIOCGPGRP => pgrp.write(tcgetpgrp()? as i32)?,
+        TIOCSPGRP => match pgrp.read()? {
+            pgid if pgid < 0 => return Err(EINVAL),
+            pgid => tcsetpgrp(pgid as usize)?,
+        },
+        TIOCGSID => pgrp.write(tcgetsid()? as i32)?,
+        _ => return Err(ENOTTY),
+    }
+    Ok(0)
+});
@@ -0,0 +1,418 @@
+/*
+ * POSIX signals.
+ *
+ * A signal is sent by setting its bit in the pending mask of the target, and delivered when the target
+ * returns to user space (see `do_signal`): either by its default action, or by running the user handler
+ * registered with `sigaction`. To run a handler, we save the user context in a `SignalFrame` on the user
+ * stack and return to the handler, whose return address is the restorer given to `sigaction`. The restorer
+ * calls `rt_sigreturn`, which restores the saved context.
+ *
+ * A fatal signal marks the process `killed` when it is sent, so that it is woken up from any sleep and exits
+ * at the next trap, even in the kernel. Other signals interrupt a sleeping process if they are not blocked,
+ * and a blocking syscall then returns `EINTR`.
+ *
+ * Signal numbers and the layout of `SigAction` follow Linux on AArch64.
+ */
+
+use core::mem::size_of;
+use crate::define_syscall;
+use crate::kernel::errno::Errno::{self, EINVAL};
+use crate::kernel::errno::SyscallResult;
+use crate::kernel::proc::{exit_signal, Process, UserContext};
+use crate::kernel::sched::{acquire_proc_lock, activate_no_lock, sched, thisproc};
+use crate::kernel::proc::ProcessState::Sleeping;
+use crate::kernel::syscall::{SYS_RT_SIGACTION, SYS_RT_SIGPENDING, SYS_RT_SIGPROCMASK, SYS_RT_SIGRETURN};
+use crate::kernel::uaccess::UserPtr;
+
+pub const NSIG: usize = 64;
+
+pub const SIGHUP: usize = 1;
+pub const SIGINT: usize = 2;
+pub const SIGKILL: usize = 9;
+pub const SIGUSR1: usize = 10;
+pub const SIGSEGV: usize = 11;
+pub const SIGUSR2: usize = 12;
+pub const SIGTERM: usize = 15;
+pub const SIGCHLD: usize = 17;
+pub const SIGCONT: usize = 18;
+pub const SIGSTOP: usize = 19;
+pub const SIGTSTP: usize = 20;
+pub const SIGTTIN: usize = 21;
+pub const SIGTTOU: usize = 22;
+pub const SIGURG: usize = 23;
+pub const SIGWINCH: usize = 28;
+
+// A set of signals, with bit `sig - 1` for `sig`.
+pub type SigSet = u64;
+
+pub const fn sigmask(sig: usize) -> SigSet {
+    1 << (sig - 1)
+}
+
+// Signals which cannot be caught, blocked or ignored.
+const UNBLOCKABLE: SigSet = sigmask(SIGKILL) | sigmask(SIGSTOP);
+const STOP_SIGNALS: SigSet = sigmask(SIGSTOP) | sigmask(SIGTSTP) | sigmask(SIGTTIN) | sigmask(SIGTTOU);
+
+pub const SIG_DFL: u64 = 0;
+pub const SIG_IGN: u64 = 1;
+
+pub const SA_RESTORER: u64 = 0x04000000;
+pub const SA_NODEFER: u64 = 0x40000000;
+pub const SA_RESETHAND: u64 = 0x80000000;
+
+pub const SIG_BLOCK: i32 = 0;
+pub const SIG_UNBLOCK: i32 = 1;
+pub const SIG_SETMASK: i32 = 2;
+
+// The exit code of a process terminated by `sig`, as a shell reports it.
+pub const fn signal_exit_code(sig: usize) -> isize {
+    128 + sig as isize
+}
+
+fn valid_signal(sig: usize) -> bool {
+    (1..=NSIG).contains(&sig)
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SigAction {
+    // `SIG_DFL`, `SIG_IGN`, or the address of the handler.
+    pub handler: u64,
+    pub flags: u64,
+    // Where the handler returns to. It must call `rt_sigreturn`.
+    pub restorer: u64,
+    // Signals blocked while the handler runs, besides the signal itself.
+    pub mask: SigSet,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DefaultAction {
+    // We do not dump cores, so the signals dumping a core on Linux just terminate the process.
+    Terminate,
+    Ignore,
+    Stop,
+    Continue,
+}
+
+pub fn default_action(sig: usize) -> DefaultAction {
+    match sig {
+        SIGCHLD | SIGURG | SIGWINCH => DefaultAction::Ignore,
+        SIGCONT => DefaultAction::Continue,
+        SIGSTOP | SIGTSTP | SIGTTIN | SIGTTOU => DefaultAction::Stop,
+        _ => DefaultAction::Terminate,
+    }
+}
+
+// The signal state of a process. It is protected by the lock of the process, since senders wake the process up.
+#[derive(Clone)]
+pub struct SignalState {
+    pub pending: SigSet,
+    pub blocked: SigSet,
+    // Stopped by a stop signal, until a `SIGCONT` or `SIGKILL`.
+    pub stopped: bool,
+    actions: [SigAction; NSIG],
+}
+
+impl SignalState {
+    pub const fn new() -> Self {
+        Self {
+            pending: 0,
+            blocked: 0,
+            stopped: false,
+            actions: [SigAction { handler: SIG_DFL, flags: 0, restorer: 0, mask: 0 }; NSIG],
+        }
+    }
+
+    pub fn action(&self, sig: usize) -> &SigAction {
+        &self.actions[sig - 1]
+    }
+
+    fn ignored(&self, sig: usize) -> bool {
+        match self.action(sig).handler {
+            SIG_IGN => true,
+            SIG_DFL => matches!(default_action(sig), DefaultAction::Ignore | DefaultAction::Continue),
+            _ => false,
+        }
+    }
+
+    // Whether `sig` terminates the process as soon as it is sent.
+    fn fatal(&self, sig: usize) -> bool {
+        sig == SIGKILL || (self.blocked & sigmask(sig) == 0 && self.action(sig).handler == SIG_DFL
+            && default_action(sig) == DefaultAction::Terminate)
+    }
+
+    // Whether a signal is waiting to be delivered, so that the process should not sleep.
+    pub fn has_deliverable(&self) -> bool {
+        !self.stopped && self.pending & !self.blocked != 0
+    }
+
+    // The signal to report for a killed process: `SIGKILL` if it is pending, or else the first fatal one.
+    pub fn fatal_signal(&self) -> usize {
+        if self.pending & sigmask(SIGKILL) != 0 {
+            return SIGKILL;
+        }
+        (1..=NSIG).find(|&sig| self.pending & sigmask(sig) != 0 && self.fatal(sig)).unwrap_or(SIGKILL)
+    }
+
+    // The state of a child created by `fork`, which keeps the handlers and the blocked signals.
+    pub fn forked(&self) -> Self {
+        Self {
+            pending: 0,
+            stopped: false,
+            ..self.clone()
+        }
+    }
+
+    // `exec` resets the handlers, since they are gone with the old image. Ignored signals stay ignored.
+    pub fn reset_handlers(&mut self) {
+        for action in self.actions.iter_mut() {
+            if action.handler != SIG_IGN {
+                *action = SigAction::default();
+            }
+        }
+    }
+}
+
+// Send `sig` to `proc`.
+//
+// Note: you should hold the lock of `proc`, and make sure that it is alive.
+pub fn send_signal(proc: &mut Process, sig: usize) {
+    let state = &mut proc.signal;
+    let mask = sigmask(sig);
+    let mut wake = false;
+    if sig == SIGKILL || sig == SIGCONT {
+        // Both resume a stopped process, whatever the handler is.
+        state.pending &= !STOP_SIGNALS;
+        wake = state.stopped;
+        state.stopped = false;
+    } else if mask & STOP_SIGNALS != 0 {
+        state.pending &= !sigmask(SIGCONT);
+    }
+    if state.ignored(sig) && state.blocked & mask == 0 {
+        // An ignored signal is discarded, unless it is blocked, in which case the handler may change before it
+        // is unblocked.
+    } else {
+        state.pending |= mask;
+        if state.fatal(sig) {
+            state.stopped = false;
+            proc.killed = true;
+        }
+    }
+    if wake || proc.killed || proc.signal.has_deliverable() {
+        activate_no_lock(proc);
+    }
+}
+
+// Send `sig` to the current process for a fault it cannot go on without handling, e.g. `SIGSEGV`.
+// If the signal is blocked or ignored, it is unblocked and handled by the default action, as on Linux.
+pub fn force_signal(sig: usize) {
+    let proc = thisproc();
+    let _lock = acquire_proc_lock(proc);
+    let state = &mut proc.signal;
+    if state.blocked & sigmask(sig) != 0 || state.action(sig).handler == SIG_IGN {
+        state.blocked &= !sigmask(sig);
+        state.actions[sig - 1] = SigAction::default();
+    }
+    send_signal(proc, sig);
+}
+
+// The frame `do_signal` puts on the user stack before running a handler.
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct SignalFrame {
+    context: UserContext,
+    // The blocked signals to restore.
+    blocked: SigSet,
+    _padding: u64,
+}
+
+// The mode bits of SPSR are 0 when the trap comes from EL0.
+const SPSR_MODE_MASK: u64 = 0xF;
+// The condition flags, which are all the user may change in SPSR.
+const SPSR_NZCV_MASK: u64 = 0xF << 28;
+
+// Set up `context` to return to the handler of `sig`, saving it and `blocked` on the user stack.
+fn setup_frame(context: &mut UserContext, sig: usize, action: &SigAction, blocked: SigSet) -> Result<(), Errno> {
+    let sp = (context.sp_el0 as usize).wrapping_sub(size_of::<SignalFrame>()) & !0xF;
+    UserPtr::<SignalFrame>::new(sp).write(SignalFrame { context: *context, blocked, _padding: 0 })?;
+    context.sp_el0 = sp as u64;
+    context.elr_el1 = action.handler;
+    context.x[0] = sig as u64;
+    context.x[30] = action.restorer;
+    Ok(())
+}
+
+// Deliver pending signals before returning to `context`. Called at the end of each trap.
+//
+// A killed process exits here. Other signals are only delivered when returning to user space, one at a time:
+// the next one is delivered after the handler calls `rt_sigreturn`.
+pub fn do_signal(context: &mut UserContext) {
+    let proc = thisproc();
+    loop {
+        if proc.killed {
+            let sig = {
+                let _lock = acquire_proc_lock(proc);
+                proc.signal.fatal_signal()
+            };
+            exit_signal(sig);
+        }
+        if context.spsr_el1 & SPSR_MODE_MASK != 0 {
+            return;
+        }
+        let mut lock = acquire_proc_lock(proc);
+        let deliverable = proc.signal.pending & !proc.signal.blocked;
+        if deliverable == 0 {
+            return;
+        }
+        let sig = deliverable.trailing_zeros() as usize + 1;
+        proc.signal.pending &= !sigmask(sig);
+        let action = *proc.signal.action(sig);
+        match action.handler {
+            SIG_IGN => continue,
+            SIG_DFL => match default_action(sig) {
+                DefaultAction::Ignore | DefaultAction::Continue => continue,
+                DefaultAction::Terminate => {
+                    drop(lock);
+                    exit_signal(sig);
+                }
+                DefaultAction::Stop => {
+                    // Sleep until `SIGCONT` or a fatal signal, which another process sends meanwhile.
+                    proc.signal.stopped = true;
+                    loop {
+                        sched(lock, Sleeping);
+                        lock = acquire_proc_lock(proc);
+                        if !proc.signal.stopped || proc.killed {
+                            break;
+                        }
+                    }
+                    continue;
+                }
+            },
+            _ => {}
+        }
+        // Run the user handler.
+        let blocked = proc.signal.blocked;
+        if action.flags & SA_RESETHAND != 0 {
+            proc.signal.actions[sig - 1] = SigAction::default();
+        }
+        proc.signal.blocked |= action.mask;
+        if action.flags & SA_NODEFER == 0 {
+            proc.signal.blocked |= sigmask(sig);
+        }
+        proc.signal.blocked &= !UNBLOCKABLE;
+        drop(lock);
+        if setup_frame(context, sig, &action, blocked).is_err() {
+            // The stack is bad, so the handler cannot run.
+            exit_signal(SIGSEGV);
+        }
+        return;
+    }
+}
+
+// Change the action for `sig` of the current process to `act`, if any. Return the old action.
+pub fn sigaction(sig: usize, act: Option<SigAction>) -> Result<SigAction, Errno> {
+    if !valid_signal(sig) {
+        return Err(EINVAL);
+    }
+    let proc = thisproc();
+    let _lock = acquire_proc_lock(proc);
+    let old = *proc.signal.action(sig);
+    if let Some(mut act) = act {
+        if sigmask(sig) & UNBLOCKABLE != 0 {
+            return Err(EINVAL);
+        }
+        // We have no trampoline to return from a handler, so the user must give one.
+        if act.handler != SIG_DFL && act.handler != SIG_IGN && act.flags & SA_RESTORER == 0 {
+            return Err(EINVAL);
+        }
+        act.mask &= !UNBLOCKABLE;
+        proc.signal.actions[sig - 1] = act;
+        // A pending signal is discarded when it becomes ignored.
+        if proc.signal.ignored(sig) {
+            proc.signal.pending &= !sigmask(sig);
+        }
+    }
+    Ok(old)
+}
+
+// Change the blocked signals of the current process by `how` with `set`, if any. Return the old ones.
+pub fn sigprocmask(how: i32, set: Option<SigSet>) -> Result<SigSet, Errno> {
+    let proc = thisproc();
+    let _lock = acquire_proc_lock(proc);
+    let old = proc.signal.blocked;
+    if let Some(set) = set {
+        let blocked = match how {
+            SIG_BLOCK => old | set,
+            SIG_UNBLOCK => old & !set,
+            SIG_SETMASK => set,
+            _ => return Err(EINVAL),
+        };
+        proc.signal.blocked = blocked & !UNBLOCKABLE;
+    }
+    Ok(old)
+}
+
+// The signals of the current process which are pending but blocked.
+pub fn sigpending() -> SigSet {
+    let proc = thisproc();
+    let _lock = acquire_proc_lock(proc);
+    proc.signal.pending & proc.signal.blocked
+}
+
+// Return from a handler, restoring the context and the blocked signals saved by `setup_frame`.
+pub fn sigreturn() -> Result<u64, Errno> {
+    let proc = thisproc();
+    let context = unsafe { &mut *proc.user_context };
+    let frame = match UserPtr::<SignalFrame>::new(context.sp_el0 as usize).read() {
+        Ok(frame) => frame,
+        Err(err) => {
+            force_signal(SIGSEGV);
+            return Err(err);
+        }
+    };
+    *context = frame.context;
+    // Never return to the kernel, or with interrupts masked.
+    context.spsr_el1 &= SPSR_NZCV_MASK;
+    let _lock = acquire_proc_lock(proc);
+    proc.signal.blocked = frame.blocked & !UNBLOCKABLE;
+    // Keep x0 of the restored context.
+    Ok(context.x[0])
+}
+
+// `sigsetsize` must be the size of `SigSet`, as on Linux.
+fn check_sigsetsize(sigsetsize: usize) -> Result<(), Errno> {
+    if sigsetsize != size_of::<SigSet>() {
+        return Err(EINVAL);
+    }
+    Ok(())
+}
+
+define_syscall!(SYS_RT_SIGACTION, fn sys_rt_sigaction(sig: usize, act: UserPtr<SigAction>, oact: UserPtr<SigAction>, sigsetsize: usize) -> SyscallResult {
+    check_sigsetsize(sigsetsize)?;
+    let act = if act.is_null() { None } else { Some(act.read()?) };
+    let old = sigaction(sig, act)?;
+    if !oact.is_null() {
+        oact.write(old)?;
+    }
+    Ok(0)
+});
+
+define_syscall!(SYS_RT_SIGPROCMASK, fn sys_rt_sigprocmask(how: i32, set: UserPtr<SigSet>, oset: UserPtr<SigSet>, sigsetsize: usize) -> SyscallResult {
+    check_sigsetsize(sigsetsize)?;
+    let set = if set.is_null() { None } else { Some(set.read()?) };
+    let old = sigprocmask(how, set)?;
+    if !oset.is_null() {
+        oset.write(old)?;
+    }
+    Ok(0)
+});
+
+define_syscall!(SYS_RT_SIGPENDING, fn sys_rt_sigpending(set: UserPtr<SigSet>, sigsetsize: usize) -> SyscallResult {
+    check_sigsetsize(sigsetsize)?;
+    set.write(sigpending())?;
+    Ok(0)
+});
+
+define_syscall!(SYS_RT_SIGRETURN, fn sys_rt_sigreturn() -> SyscallResult {
+    sigreturn()
+});
@@ -1,37 +1,158 @@
-use crate::define_syscall;
+use core::sync::atomic::{AtomicBool, Ordering};
+use crate::{define_syscall, println};
+use crate::kernel::errno::{encode_result, Errno, SyscallResult};
+use crate::kernel::errno::Errno::{EBADF, EINVAL, ENOSYS};
 use crate::kernel::proc::UserContext;
+use crate::kernel::sched::thisproc;
+use crate::kernel::uaccess::UserPtr;
 
-const MAX_SYSCALLS: usize = 256;
-static mut SYSCALL_TABLE: [Option<fn([u64; 6]) -> u64>; MAX_SYSCALLS] = [None; MAX_SYSCALLS];
+const MAX_SYSCALLS: usize = 512;
 
-pub unsafe fn register_syscall(syscall: usize, func: fn([u64; 6]) -> u64) {
+// Syscall numbers. Where Linux on AArch64 has a counterpart, we use the same number.
+pub const SYS_IOCTL: usize = 29;
+pub const SYS_KILL: usize = 129;
+pub const SYS_RT_SIGACTION: usize = 134;
+pub const SYS_RT_SIGPROCMASK: usize = 135;
+pub const SYS_RT_SIGPENDING: usize = 136;
+pub const SYS_RT_SIGRETURN: usize = 139;
+pub const SYS_SETGID: usize = 144;
+pub const SYS_SETUID: usize = 146;
+pub const SYS_SETPGID: usize = 154;
+pub const SYS_GETPGID: usize = 155;
+pub const SYS_GETSID: usize = 156;
+pub const SYS_SETSID: usize = 157;
+pub const SYS_GETUID: usize = 174;
+pub const SYS_GETGID: usize = 176;
+pub const SYS_MSGGET: usize = 186;
+pub const SYS_MSGCTL: usize = 187;
+pub const SYS_MSGRCV: usize = 188;
+pub const SYS_MSGSND: usize = 189;
+pub const SYS_BRK: usize = 214;
+pub const SYS_MUNMAP: usize = 215;
+pub const SYS_FORK: usize = 220; // `clone` on Linux, which we only support with fork semantics.
+pub const SYS_EXECVE: usize = 221;
+pub const SYS_MMAP: usize = 222;
+pub const SYS_MPROTECT: usize = 226;
+// Linux leaves 244-259 to architecture-specific syscalls. Ours without a Linux counterpart go there.
+pub const SYS_SBRK: usize = 244;
+pub const SYS_PROCINFO: usize = 245;
+
+pub const SYS_WAIT4: usize = 260;
+
+// A syscall argument, decoded from its register.
+pub trait SyscallArg: Sized {
+    fn decode(raw: u64) -> Result<Self, Errno>;
+}
+
+macro_rules! impl_integer_arg {
+    ($($ty:ty),*) => {
+        $(impl SyscallArg for $ty {
+            fn decode(raw: u64) -> Result<Self, Errno> {
+                Ok(raw as $ty)
+            }
+        })*
+    };
+}
+
+// Like Linux, narrower integers just take the low bits of the register.
+impl_integer_arg!(u64, i64, usize, isize, u32, i32);
+
+// The pointer is only checked when accessed.
+impl<T: Copy> SyscallArg for UserPtr<T> {
+    fn decode(raw: u64) -> Result<Self, Errno> {
+        Ok(UserPtr::new(raw as usize))
+    }
+}
+
+// A file descriptor, which must not be negative.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Fd(pub usize);
+
+impl SyscallArg for Fd {
+    fn decode(raw: u64) -> Result<Self, Errno> {
+        match raw as i32 {
+            fd if fd < 0 => Err(EBADF),
+            fd => Ok(Fd(fd as usize)),
+        }
+    }
+}
+
+// Flags of which only the bits in `VALID` may be set.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Flags<const VALID: u32>(pub u32);
+
+impl<const VALID: u32> SyscallArg for Flags<VALID> {
+    fn decode(raw: u64) -> Result<Self, Errno> {
+        let flags = raw as u32;
+        if flags & !VALID != 0 {
+            return Err(EINVAL);
+        }
+        Ok(Flags(flags))
+    }
+}
+
+#[derive(Clone, Copy)]
+pub struct SyscallInfo {
+    pub name: &'static str,
+    // The arguments as declared, e.g. "addr: usize, len: usize".
+    pub signature: &'static str,
+    func: fn([u64; 6]) -> SyscallResult,
+}
+
+static mut SYSCALL_TABLE: [Option<SyscallInfo>; MAX_SYSCALLS] = [None; MAX_SYSCALLS];
+static TRACE_SYSCALLS: AtomicBool = AtomicBool::new(false);
+
+pub unsafe fn register_syscall(syscall: usize, name: &'static str, signature: &'static str, func: fn([u64; 6]) -> SyscallResult) {
     if syscall >= MAX_SYSCALLS {
         panic!("Syscall number out of range");
     }
     if SYSCALL_TABLE[syscall].is_some() {
         panic!("Syscall number already registered");
     }
-    SYSCALL_TABLE[syscall] = Some(func);
+    SYSCALL_TABLE[syscall] = Some(SyscallInfo { name, signature, func });
 }
 
-pub fn syscall_entry(context: *mut UserContext) {
+pub fn syscall_info(syscall: usize) -> Option<&'static SyscallInfo> {
+    unsafe { SYSCALL_TABLE.get(syscall)?.as_ref() }
+}
 
-    let syscall_id = unsafe { (*context).x[8] as usize };
-    if syscall_id >= MAX_SYSCALLS {
-        panic!("Invalid syscall id {}", syscall_id);
+// Print every registered syscall as `number name(signature)`, one per line,
+// from which user space stubs can be generated.
+pub fn print_syscall_table() {
+    for (i, info) in unsafe { SYSCALL_TABLE.iter() }.enumerate() {
+        if let Some(info) = info {
+            println!("{} {}({})", i, info.name, info.signature);
+        }
     }
-    let syscall = unsafe { SYSCALL_TABLE[syscall_id] };
-    if syscall.is_none() {
-        panic!("Unimplemented syscall id {}", syscall_id);
-    }
-    let syscall = syscall.unwrap();
+}
+
+// Print each syscall and its result, if `on`.
+pub fn set_syscall_trace(on: bool) {
+    TRACE_SYSCALLS.store(on, Ordering::Relaxed);
+}
+
+pub fn syscall_trace() -> bool {
+    TRACE_SYSCALLS.load(Ordering::Relaxed)
+}
+
+pub fn syscall_entry(context: *mut UserContext) {
+    let syscall_id = unsafe { (*context).x[8] as usize };
     // Get args from context
     let args: [u64; 6] = unsafe { (*context).x[0..6].try_into().unwrap() };
-    let ret = syscall(args);
+    let info = syscall_info(syscall_id);
+    let ret = encode_result(match info {
+        Some(info) => (info.func)(args),
+        None => Err(ENOSYS),
+    });
+    if syscall_trace() {
+        let name = info.map_or("unknown", |info| info.name);
+        println!("pid {}: {}#{}({:#x}, {:#x}, {:#x}, {:#x}, {:#x}, {:#x}) = {}", thisproc().pid, name, syscall_id,
+                 args[0], args[1], args[2], args[3], args[4], args[5], ret as i64);
+    }
     unsafe { (*context).x[0] = ret };
 }
 
-pub fn hello_world(_args: [u64; 6]) -> u64 {
-    0x114514
+pub fn hello_world(_args: [u64; 6]) -> SyscallResult {
+    Ok(0x114514)
 }
 define_syscall!(0, hello_world);
\ No newline at end of file
@@ -0,0 +1,170 @@
+/*
+ * Access to user memory from the kernel.
+ *
+ * Syscalls get raw user addresses, which may point anywhere. We never dereference them directly.
+ * Instead, each page is faulted in as if the user accessed it, checking the VMAs, and read or written
+ * through its kernel address. So a bad pointer is an `EFAULT` rather than a data abort in the kernel,
+ * and a write to a copy-on-write page copies it first, like a user write does.
+ */
+use core::cmp::min;
+use core::marker::PhantomData;
+use core::mem::{MaybeUninit, size_of};
+use core::{ptr, slice};
+use crate::aarch64::mmu::{KSPACE_MASK, PAGE_SIZE, physical2kernel};
+use crate::cores::virtual_memory::{PageTableDirectory, VirtualMemoryPageTable};
+use crate::kernel::errno::Errno::{self, EFAULT};
+use crate::kernel::sched::thisproc;
+use crate::kernel::vm::{USER_SPACE_END, VmaTree};
+
+// Check that [addr, addr + len) is in user space.
+fn check_range(addr: usize, len: usize) -> Result<(), Errno> {
+    match addr.checked_add(len) {
+        Some(end) if addr & KSPACE_MASK == 0 && end <= USER_SPACE_END => Ok(()),
+        _ => Err(EFAULT),
+    }
+}
+
+// Return the kernel address of the user page containing `addr`, faulting it in for reading or writing.
+pub fn user_page(pgdir: &mut PageTableDirectory, vmas: &mut VmaTree, addr: usize, write: bool) -> Result<*mut u8, Errno> {
+    check_range(addr, 1)?;
+    if !vmas.fault_in(pgdir, addr, write, false) {
+        return Err(EFAULT);
+    }
+    let pte = pgdir.walk(addr, false).ok_or(EFAULT)?;
+    Ok(physical2kernel(unsafe { (*pte).addr(3) } as u64) as *mut u8)
+}
+
+// Call `f` with the kernel address of each piece of [addr, addr + len) in a user page, and the
+// offset of the piece in the range.
+fn for_each_page<F: FnMut(*mut u8, usize, usize)>(pgdir: &mut PageTableDirectory, vmas: &mut VmaTree,
+                                                  addr: usize, len: usize, write: bool, mut f: F) -> Result<(), Errno> {
+    check_range(addr, len)?;
+    let mut done = 0;
+    while done < len {
+        let va = addr + done;
+        let offset = va % PAGE_SIZE;
+        let n = min(PAGE_SIZE - offset, len - done);
+        let page = user_page(pgdir, vmas, va, write)?;
+        f(unsafe { page.add(offset) }, done, n);
+        done += n;
+    }
+    Ok(())
+}
+
+// Copy the user memory at `src` of `pgdir` to `dst`.
+pub fn copy_in(pgdir: &mut PageTableDirectory, vmas: &mut VmaTree, dst: &mut [u8], src: usize) -> Result<(), Errno> {
+    for_each_page(pgdir, vmas, src, dst.len(), false, |page, done, n| unsafe {
+        ptr::copy_nonoverlapping(page, dst[done..].as_mut_ptr(), n);
+    })
+}
+
+// Copy `src` to the user memory at `dst` of `pgdir`.
+pub fn copy_out(pgdir: &mut PageTableDirectory, vmas: &mut VmaTree, dst: usize, src: &[u8]) -> Result<(), Errno> {
+    for_each_page(pgdir, vmas, dst, src.len(), true, |page, done, n| unsafe {
+        ptr::copy_nonoverlapping(src[done..].as_ptr(), page, n);
+    })
+}
+
+// Copy the user memory at `src` of the current process to `dst`.
+pub fn copy_from_user(dst: &mut [u8], src: usize) -> Result<(), Errno> {
+    let proc = thisproc();
+    copy_in(&mut proc.pgdir, &mut proc.vmas, dst, src)
+}
+
+// Copy `src` to the user memory at `dst` of the current process.
+pub fn copy_to_user(dst: usize, src: &[u8]) -> Result<(), Errno> {
+    let proc = thisproc();
+    copy_out(&mut proc.pgdir, &mut proc.vmas, dst, src)
+}
+
+// A pointer to a `T` in the memory of the current process.
+pub struct UserPtr<T> {
+    addr: usize,
+    _marker: PhantomData<*mut T>,
+}
+
+impl<T> Clone for UserPtr<T> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<T> Copy for UserPtr<T> {}
+
+impl<T: Copy> UserPtr<T> {
+    pub fn new(addr: usize) -> Self {
+        Self {
+            addr,
+            _marker: PhantomData,
+        }
+    }
+
+    pub fn addr(&self) -> usize {
+        self.addr
+    }
+
+    pub fn is_null(&self) -> bool {
+        self.addr == 0
+    }
+
+    // The pointer to the `n`-th `T` after this one.
+    pub fn add(&self, n: usize) -> Self {
+        Self::new(self.addr.wrapping_add(n.wrapping_mul(size_of::<T>())))
+    }
+
+    // `T` must be valid for any bytes, since the user may put anything there.
+    pub fn read(&self) -> Result<T, Errno> {
+        let mut value = MaybeUninit::<T>::uninit();
+        let buf = unsafe { slice::from_raw_parts_mut(value.as_mut_ptr() as *mut u8, size_of::<T>()) };
+        copy_from_user(buf, self.addr)?;
+        Ok(unsafe { value.assume_init() })
+    }
+
+    pub fn write(&self, value: T) -> Result<(), Errno> {
+        let buf = unsafe { slice::from_raw_parts(&value as *const T as *const u8, size_of::<T>()) };
+        copy_to_user(self.addr, buf)
+    }
+}
+
+// `len` bytes at `addr` in the memory of the current process.
+#[derive(Clone, Copy)]
+pub struct UserSlice {
+    addr: usize,
+    len: usize,
+}
+
+impl UserSlice {
+    pub fn new(addr: usize, len: usize) -> Self {
+        Self { addr, len }
+    }
+
+    pub fn addr(&self) -> usize {
+        self.addr
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    // Copy the first `buf.len()` bytes of the slice to `buf`.
+    pub fn read(&self, buf: &mut [u8]) -> Result<(), Errno> {
+        assert!(buf.len() <= self.len, "UserSlice: reading beyond the end");
+        copy_from_user(buf, self.addr)
+    }
+
+    // Copy `data` to the start of the slice.
+    pub fn write(&self, data: &[u8]) -> Result<(), Errno> {
+        assert!(data.len() <= self.len, "UserSlice: writing beyond the end");
+        copy_to_user(self.addr, data)
+    }
+
+    // Check that the whole slice can be written, without changing it.
+    pub fn check_writable(&self) -> Result<(), Errno> {
+        let proc = thisproc();
+        for_each_page(&mut proc.pgdir, &mut proc.vmas, self.addr, self.len, true, |_, _, _| {})
+    }
+}
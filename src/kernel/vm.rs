@@ -0,0 +1,450 @@
+use alloc::boxed::Box;
+use core::cmp::{max, min};
+use core::mem::MaybeUninit;
+use core::{ptr, slice};
+use field_offset::offset_of;
+use file_system::inode::{Inode, InodeTree, INODES};
+use crate::aarch64::intrinsic::{flush_tlb, sync_icache};
+use crate::aarch64::mmu::{kernel2physical, PAGE_SIZE, physical2kernel};
+use crate::common::list::ListNode;
+use crate::common::tree::{RbTree, RbTreeLink};
+use crate::cores::virtual_memory::{AccessPermission, PageTableDirectory, PageTableEntry, pte_flags, VirtualMemoryPageTable};
+use crate::define_syscall;
+use crate::kernel::mem::{kalloc_page, kput_page, page_ref_count};
+use crate::kernel::sched::thisproc;
+use crate::kernel::errno::Errno::{self, EBADF, EINVAL, ENOMEM};
+use crate::kernel::errno::SyscallResult;
+use crate::kernel::exec::{USER_STACK_SIZE, USER_STACK_TOP};
+use crate::kernel::proc::Process;
+use crate::kernel::syscall::{Flags, SYS_BRK, SYS_MMAP, SYS_MPROTECT, SYS_MUNMAP, SYS_SBRK};
+
+// User space is [0, USER_SPACE_END).
+pub const USER_SPACE_END: usize = 1 << 48;
+// Where `mmap` starts looking for free space, if not given an address.
+pub const MMAP_BASE: usize = 1 << 40;
+
+pub const PROT_NONE: u32 = 0;
+pub const PROT_READ: u32 = 1;
+pub const PROT_WRITE: u32 = 2;
+pub const PROT_EXEC: u32 = 4;
+
+pub const MAP_SHARED: u32 = 0x01;
+pub const MAP_PRIVATE: u32 = 0x02;
+pub const MAP_FIXED: u32 = 0x10;
+pub const MAP_ANONYMOUS: u32 = 0x20;
+
+pub enum Backing {
+    // Zero-filled memory.
+    Anonymous,
+    // Addresses in [data_start, data_end) read the file from `offset` on, and the rest of the VMA is zero,
+    // like an ELF segment. Each VMA holds a reference to `inode`.
+    File {
+        inode: *mut Inode,
+        offset: usize,
+        data_start: usize,
+        data_end: usize,
+    },
+}
+
+impl Backing {
+    fn dup(&self) -> Self {
+        match *self {
+            Backing::Anonymous => Backing::Anonymous,
+            Backing::File { inode, offset, data_start, data_end } => Backing::File {
+                inode: unsafe { INODES.share(inode) },
+                offset,
+                data_start,
+                data_end,
+            },
+        }
+    }
+}
+
+// A virtual memory area of a process, [start, end) in pages.
+// Pages of it are allocated on the first access.
+#[repr(C)]
+pub struct Vma {
+    pub start: usize,
+    pub end: usize,
+    pub prot: u32,
+    pub backing: Backing,
+    link: RbTreeLink,
+}
+
+impl ListNode<RbTreeLink> for Vma {
+    fn get_link_offset() -> usize { offset_of!(Vma => link).get_byte_offset() }
+}
+
+impl Drop for Vma {
+    fn drop(&mut self) {
+        if let Backing::File { inode, .. } = self.backing {
+            unsafe { INODES.put(ptr::null_mut(), inode) };
+        }
+    }
+}
+
+impl Vma {
+    pub fn new(start: usize, end: usize, prot: u32, backing: Backing) -> Box<Self> {
+        assert!(start % PAGE_SIZE == 0 && end % PAGE_SIZE == 0 && start < end && end <= USER_SPACE_END,
+                "Vma: bad area {:#x}-{:#x}", start, end);
+        Box::new(Self {
+            start,
+            end,
+            prot,
+            backing,
+            link: RbTreeLink::new(),
+        })
+    }
+
+    pub fn contains(&self, addr: usize) -> bool {
+        self.start <= addr && addr < self.end
+    }
+
+    pub fn readable(&self) -> bool {
+        self.prot != PROT_NONE
+    }
+
+    pub fn writable(&self) -> bool {
+        self.prot & PROT_WRITE != 0
+    }
+
+    pub fn executable(&self) -> bool {
+        self.prot & PROT_EXEC != 0
+    }
+
+    // A new VMA of [start, end) of this one, with the same protection and backing.
+    fn slice(&self, start: usize, end: usize) -> Box<Self> {
+        Self::new(start, end, self.prot, self.backing.dup())
+    }
+
+    // Fill the new page at `va` with its content.
+    fn fill(&self, va: usize, page: *mut u8) {
+        if let Backing::File { inode, offset, data_start, data_end } = self.backing {
+            let lo = max(va, data_start);
+            let hi = min(va + PAGE_SIZE, data_end);
+            if lo < hi {
+                unsafe {
+                    let dest = slice::from_raw_parts_mut(page.add(lo - va), hi - lo);
+                    INODES.lock(inode);
+                    // If the file is shorter now, the rest is left zero.
+                    INODES.read(inode, dest, offset + lo - data_start);
+                    INODES.unlock(inode);
+                }
+            }
+        }
+        if self.executable() {
+            sync_icache(page as usize, PAGE_SIZE);
+        }
+    }
+}
+
+// Set the permissions of a user page. Shared copy-on-write pages are kept read-only.
+fn set_prot(pte: &mut PageTableEntry, prot: u32) {
+    if prot == PROT_NONE {
+        pte.set_access_permission(AccessPermission::El1rwEl0n);
+        pte.set_execute_never(true);
+        pte.set_privileged_execute_never(true);
+    } else {
+        pte_flags::user_prot(pte, prot & PROT_WRITE != 0 && !pte.cow(), prot & PROT_EXEC != 0);
+    }
+}
+
+// Give the page mapped by `pte` to this page table alone, copying it if it is still shared.
+fn copy_on_write(pte: &mut PageTableEntry) {
+    let page = physical2kernel(pte.addr(3) as u64) as *mut u8;
+    if page_ref_count(page) > 1 {
+        let copy = kalloc_page(1);
+        unsafe {
+            ptr::copy_nonoverlapping(page, copy, PAGE_SIZE);
+        }
+        pte.set_addr(kernel2physical(copy as u64) as usize, 3);
+        kput_page(page);
+    }
+    pte.set_access_permission(AccessPermission::El1rwEl0rw);
+    pte.set_cow(false);
+    // Drop the read-only entry cached in TLB.
+    flush_tlb();
+}
+
+// The VMAs of a process, sorted by address. They never overlap.
+pub struct VmaTree(MaybeUninit<RbTree<Vma>>);
+
+impl VmaTree {
+    pub const fn uninit() -> Self {
+        Self(MaybeUninit::uninit())
+    }
+
+    pub fn init(&mut self) {
+        self.0 = MaybeUninit::new(RbTree::new(|a, b| a.start < b.start));
+    }
+
+    fn tree(&mut self) -> &mut RbTree<Vma> {
+        unsafe { self.0.assume_init_mut() }
+    }
+
+    pub fn len(&mut self) -> usize {
+        self.tree().len()
+    }
+
+    // Return the first VMA ending after `addr`.
+    pub fn first_after(&mut self, addr: usize) -> Option<&'static mut Vma> {
+        self.tree().first_after(|vma| vma.end <= addr)
+    }
+
+    pub fn next(&mut self, vma: &mut Vma) -> Option<&'static mut Vma> {
+        self.tree().next(vma)
+    }
+
+    pub fn find(&mut self, addr: usize) -> Option<&'static mut Vma> {
+        self.first_after(addr).filter(|vma| vma.contains(addr))
+    }
+
+    // Return whether [start, end) does not overlap any VMA.
+    pub fn is_free(&mut self, start: usize, end: usize) -> bool {
+        self.first_after(start).map_or(true, |vma| vma.start >= end)
+    }
+
+    pub fn insert(&mut self, vma: Box<Vma>) {
+        assert!(self.is_free(vma.start, vma.end), "VmaTree: {:#x}-{:#x} overlaps", vma.start, vma.end);
+        self.tree().insert(Box::into_raw(vma));
+    }
+
+    fn remove(&mut self, vma: &mut Vma) -> Box<Vma> {
+        self.tree().delete(vma);
+        unsafe { Box::from_raw(vma) }
+    }
+
+    // Find `len` bytes of free space at or above `from`. Return its start.
+    pub fn find_free(&mut self, from: usize, len: usize) -> Option<usize> {
+        let mut start = from;
+        let mut next = self.first_after(start);
+        while let Some(vma) = next {
+            if start.checked_add(len)? <= vma.start {
+                break;
+            }
+            start = max(start, vma.end);
+            next = self.next(vma);
+        }
+        if start.checked_add(len)? <= USER_SPACE_END {
+            Some(start)
+        } else {
+            None
+        }
+    }
+
+    // Split the VMAs across `start` and `end`, so that every VMA is either in [start, end) or out of it.
+    fn split(&mut self, start: usize, end: usize) {
+        for at in [start, end] {
+            if let Some(vma) = self.find(at).filter(|vma| vma.start != at) {
+                let tail = vma.slice(at, vma.end);
+                vma.end = at;
+                self.insert(tail);
+            }
+        }
+    }
+
+    // Remove the mappings in [start, end), and free their pages.
+    pub fn unmap(&mut self, pgdir: &mut PageTableDirectory, start: usize, end: usize) {
+        self.split(start, end);
+        while let Some(vma) = self.first_after(start).filter(|vma| vma.start < end) {
+            pgdir.unmap(vma.start, vma.end);
+            self.remove(vma);
+        }
+        flush_tlb();
+    }
+
+    // Remove all mappings, with their page tables. Only the root table is left to `PageTableDirectory::free`.
+    pub fn clear(&mut self, pgdir: &mut PageTableDirectory) {
+        self.unmap(pgdir, 0, USER_SPACE_END);
+    }
+
+    // Change the protection of [start, end), which must be fully mapped.
+    pub fn protect(&mut self, pgdir: &mut PageTableDirectory, start: usize, end: usize, prot: u32) -> Result<(), Errno> {
+        // Check that there is no hole first, so that nothing is changed on failure.
+        let mut addr = start;
+        while addr < end {
+            addr = self.find(addr).ok_or(ENOMEM)?.end;
+        }
+        self.split(start, end);
+        let mut next = self.first_after(start);
+        while let Some(vma) = next.filter(|vma| vma.start < end) {
+            vma.prot = prot;
+            pgdir.for_each_page(vma.start, vma.end, |pte| set_prot(pte, prot));
+            next = self.next(vma);
+        }
+        flush_tlb();
+        Ok(())
+    }
+
+    // Give `dst` a copy of every VMA.
+    pub fn copy_to(&mut self, dst: &mut VmaTree) {
+        let mut next = self.first_after(0);
+        while let Some(vma) = next {
+            dst.insert(vma.slice(vma.start, vma.end));
+            next = self.next(vma);
+        }
+    }
+
+    // Make the page at `addr` accessible as a page fault does, i.e. allocate it or copy it on write.
+    // Return false if the access is not allowed.
+    pub fn fault_in(&mut self, pgdir: &mut PageTableDirectory, addr: usize, write: bool, exec: bool) -> bool {
+        let vma = match self.find(addr) {
+            Some(vma) => vma,
+            None => return false,
+        };
+        if !vma.readable() || (write && !vma.writable()) || (exec && !vma.executable()) {
+            return false;
+        }
+        let va = addr & !(PAGE_SIZE - 1);
+        if let Some(pte) = pgdir.walk(va, false) {
+            let pte = unsafe { &mut *pte };
+            if write && pte.cow() {
+                copy_on_write(pte);
+            }
+            return !write || matches!(pte.access_permission(), AccessPermission::El1rwEl0rw);
+        }
+        let page = kalloc_page(1);
+        unsafe {
+            ptr::write_bytes(page, 0, PAGE_SIZE);
+        }
+        vma.fill(va, page);
+        let pte = unsafe { &mut *pgdir.walk(va, true).unwrap() };
+        pte.set_addr(kernel2physical(page as u64) as usize, 3);
+        pte_flags::user_page(pte);
+        set_prot(pte, vma.prot);
+        pte.set_owned(true);
+        true
+    }
+}
+
+pub fn align_up(addr: usize) -> usize {
+    (addr + PAGE_SIZE - 1) & !(PAGE_SIZE - 1)
+}
+
+#[derive(Debug, PartialEq)]
+pub enum FaultKind {
+    // No valid mapping.
+    Translation,
+    AccessFlag,
+    Permission,
+    // Alignment, external aborts and so on, which we never handle.
+    Other,
+}
+
+// A page fault from user space, decoded from ESR_EL1 and FAR_EL1.
+#[derive(Debug)]
+pub struct PageFault {
+    pub addr: usize,
+    pub kind: FaultKind,
+    pub write: bool,
+    pub exec: bool,
+}
+
+// Try to resolve a page fault of the current process. Return false if the access is invalid.
+pub fn handle_page_fault(fault: &PageFault) -> bool {
+    if !matches!(fault.kind, FaultKind::Translation | FaultKind::Permission) {
+        return false;
+    }
+    let proc = thisproc();
+    proc.vmas.fault_in(&mut proc.pgdir, fault.addr, fault.write, fault.exec)
+}
+
+fn page_range(addr: usize, len: usize) -> Result<(usize, usize), Errno> {
+    if addr % PAGE_SIZE != 0 || len == 0 {
+        return Err(EINVAL);
+    }
+    let end = addr.checked_add(len).and_then(|end| end.checked_add(PAGE_SIZE - 1)).ok_or(ENOMEM)? & !(PAGE_SIZE - 1);
+    if end > USER_SPACE_END {
+        return Err(ENOMEM);
+    }
+    Ok((addr, end))
+}
+
+pub type Prot = Flags<{ PROT_READ | PROT_WRITE | PROT_EXEC }>;
+
+// Only private anonymous mappings are supported, so `fd` and `offset` are ignored.
+define_syscall!(SYS_MMAP, fn sys_mmap(addr: usize, len: usize, prot: Prot, flags: u32, _fd: i32, _offset: usize) -> SyscallResult {
+    if flags & MAP_ANONYMOUS == 0 {
+        return Err(EBADF);
+    }
+    if flags & (MAP_SHARED | MAP_PRIVATE) != MAP_PRIVATE {
+        return Err(EINVAL);
+    }
+    let proc = thisproc();
+    let (_, size) = page_range(0, len)?;
+    let start = if flags & MAP_FIXED != 0 {
+        let (start, end) = page_range(addr, len)?;
+        proc.vmas.unmap(&mut proc.pgdir, start, end);
+        start
+    } else {
+        // Take `addr` as a hint.
+        let from = if addr != 0 && addr % PAGE_SIZE == 0 { addr } else { MMAP_BASE };
+        proc.vmas.find_free(from, size)
+            .or_else(|| proc.vmas.find_free(PAGE_SIZE, size))
+            .ok_or(ENOMEM)?
+    };
+    proc.vmas.insert(Vma::new(start, start + size, prot.0, Backing::Anonymous));
+    Ok(start as u64)
+});
+
+define_syscall!(SYS_MUNMAP, fn sys_munmap(addr: usize, len: usize) -> SyscallResult {
+    let (start, end) = page_range(addr, len)?;
+    let proc = thisproc();
+    proc.vmas.unmap(&mut proc.pgdir, start, end);
+    Ok(0)
+});
+
+define_syscall!(SYS_MPROTECT, fn sys_mprotect(addr: usize, len: usize, prot: Prot) -> SyscallResult {
+    let (start, end) = page_range(addr, len)?;
+    let proc = thisproc();
+    proc.vmas.protect(&mut proc.pgdir, start, end, prot.0)?;
+    Ok(0)
+});
+
+// Move the program break of `proc` to `brk`. The heap is mapped by whole pages, which are
+// allocated on demand when it grows, and freed when it shrinks.
+pub fn set_brk(proc: &mut Process, brk: usize) -> Result<(), Errno> {
+    // Keep the heap below the stack, leaving a page as the guard.
+    if proc.brk_start == 0 || brk < proc.brk_start || brk > USER_STACK_TOP - USER_STACK_SIZE - PAGE_SIZE {
+        return Err(ENOMEM);
+    }
+    let (old_end, new_end) = (align_up(proc.brk), align_up(brk));
+    if new_end > old_end {
+        if !proc.vmas.is_free(old_end, new_end) {
+            return Err(ENOMEM);
+        }
+        // Extend the last VMA of the heap if we can, so that the heap does not end up in pieces.
+        let last = proc.vmas.find(old_end - 1).filter(|vma| {
+            old_end > proc.brk_start && vma.end == old_end
+                && vma.prot == PROT_READ | PROT_WRITE && matches!(vma.backing, Backing::Anonymous)
+        });
+        match last {
+            Some(vma) => vma.end = new_end,
+            None => proc.vmas.insert(Vma::new(old_end, new_end, PROT_READ | PROT_WRITE, Backing::Anonymous)),
+        }
+    } else if new_end < old_end {
+        proc.vmas.unmap(&mut proc.pgdir, new_end, old_end);
+    }
+    proc.brk = brk;
+    Ok(())
+}
+
+// Return the new program break, or the current one if it cannot be moved.
+define_syscall!(SYS_BRK, fn sys_brk(brk: usize) -> SyscallResult {
+    let proc = thisproc();
+    let _ = set_brk(proc, brk);
+    Ok(proc.brk as u64)
+});
+
+// Return the old program break.
+define_syscall!(SYS_SBRK, fn sys_sbrk(increment: i64) -> SyscallResult {
+    let proc = thisproc();
+    let old = proc.brk;
+    let brk = if increment >= 0 {
+        old.checked_add(increment as usize)
+    } else {
+        old.checked_sub(increment.unsigned_abs() as usize)
+    };
+    set_brk(proc, brk.ok_or(ENOMEM)?)?;
+    Ok(old as u64)
+});
@@ -0,0 +1,191 @@
+use alloc::vec;
+use alloc::vec::Vec;
+use core::arch::global_asm;
+use core::slice;
+use file_system::cache::{BlockCache, OpContext, SCACHE};
+use file_system::defines::INODE_REGULAR;
+use file_system::inode::{Inode, InodeTree, INODES};
+use crate::common::sem::Semaphore;
+use crate::kernel::block_device::get_super_block;
+use crate::cores::virtual_memory::VirtualMemoryPageTable;
+use crate::kernel::elf::{ELF_HEADER_SIZE, PF_R, PF_W, PF_X, PROGRAM_HEADER_SIZE};
+use crate::kernel::errno::Errno::{ENOENT, ENOEXEC};
+use crate::kernel::exec::{exec, exec_inode};
+use crate::kernel::errno::SyscallResult;
+use crate::kernel::proc::{create_proc, kill, start_proc, wait};
+use crate::kernel::signal::{signal_exit_code, SIGKILL};
+use crate::{define_syscall, println};
+
+static mut DONE: Semaphore = Semaphore::uninit(0);
+static mut REPORT: [u64; 4] = [0; 4];
+
+global_asm!(include_str!("user/exec.asm"));
+
+extern "C" {
+    fn exec_start();
+    fn exec_end();
+    fn trap_return(_: usize);
+}
+
+const BASE_ADDR: u64 = 0x400000;
+const DATA_ADDR: u64 = 0x800000;
+const DATA: u64 = 0x1234;
+
+pub fn exec_report(args: [u64; 6]) -> SyscallResult {
+    unsafe {
+        REPORT.copy_from_slice(&args[..4]);
+        DONE.post();
+    }
+    Ok(0)
+}
+
+define_syscall!(116, exec_report);
+
+fn put_u16(buf: &mut [u8], offset: usize, value: u16) {
+    buf[offset..offset + 2].copy_from_slice(&value.to_le_bytes());
+}
+
+fn put_u32(buf: &mut [u8], offset: usize, value: u32) {
+    buf[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+}
+
+fn put_u64(buf: &mut [u8], offset: usize, value: u64) {
+    buf[offset..offset + 8].copy_from_slice(&value.to_le_bytes());
+}
+
+// Build an executable with a code segment from `exec.asm`, and a data segment whose second page is bss.
+fn build_elf() -> Vec<u8> {
+    let code = unsafe { slice::from_raw_parts(exec_start as *const u8, exec_end as usize - exec_start as usize) };
+    let code_offset = ELF_HEADER_SIZE + 2 * PROGRAM_HEADER_SIZE;
+    let data_offset = code_offset + code.len();
+    let mut elf = vec![0u8; data_offset + 8];
+    elf[0..4].copy_from_slice(b"\x7fELF");
+    elf[4] = 2;
+    elf[5] = 1;
+    elf[6] = 1;
+    put_u16(&mut elf, 16, 2);
+    put_u16(&mut elf, 18, 183);
+    put_u64(&mut elf, 24, BASE_ADDR);
+    put_u64(&mut elf, 32, ELF_HEADER_SIZE as u64);
+    put_u16(&mut elf, 54, PROGRAM_HEADER_SIZE as u16);
+    put_u16(&mut elf, 56, 2);
+    let segments = [
+        (PF_R | PF_X, code_offset, BASE_ADDR, code.len() as u64, code.len() as u64),
+        (PF_R | PF_W, data_offset, DATA_ADDR, 8, 0x2000),
+    ];
+    for (i, (flags, offset, vaddr, file_size, mem_size)) in segments.into_iter().enumerate() {
+        let ph = ELF_HEADER_SIZE + i * PROGRAM_HEADER_SIZE;
+        put_u32(&mut elf, ph, 1);
+        put_u32(&mut elf, ph + 4, flags);
+        put_u64(&mut elf, ph + 8, offset as u64);
+        put_u64(&mut elf, ph + 16, vaddr);
+        put_u64(&mut elf, ph + 32, file_size);
+        put_u64(&mut elf, ph + 40, mem_size);
+    }
+    elf[code_offset..data_offset].copy_from_slice(code);
+    put_u64(&mut elf, data_offset, DATA);
+    elf
+}
+
+// Create a file not linked into any directory, so that it is freed by `remove_file`.
+fn create_file(data: &[u8]) -> *mut Inode {
+    let mut ctx = OpContext::new();
+    unsafe {
+        SCACHE.begin_op(&mut ctx);
+        let inode = INODES.get(INODES.alloc(&mut ctx, INODE_REGULAR));
+        INODES.lock(inode);
+        assert_eq!(INODES.write(&mut ctx, inode, data, 0), data.len());
+        INODES.unlock(inode);
+        SCACHE.end_op(&mut ctx);
+        inode
+    }
+}
+
+fn remove_file(inode: *mut Inode) {
+    let mut ctx = OpContext::new();
+    unsafe {
+        SCACHE.begin_op(&mut ctx);
+        INODES.put(&mut ctx, inode);
+        SCACHE.end_op(&mut ctx);
+    }
+}
+
+#[test_case]
+pub fn exec_test() {
+    println!("exec_test: start");
+    if get_super_block().is_none() {
+        println!("exec_test: SKIP (no file system)");
+        return;
+    }
+    unsafe {
+        DONE.init();
+    }
+    let proc = create_proc();
+    let inode = create_file(&build_elf());
+    assert_eq!(exec_inode(proc, inode, &[b"exec_test", b"hello"], &[b"A=1"]), Ok(()));
+    remove_file(inode);
+    let pid = start_proc(proc, trap_return as *const fn(usize), 0);
+    unsafe {
+        assert!(DONE.get_or_wait());
+        assert_eq!(REPORT, [2, b'h' as u64, DATA, 0]);
+    }
+    assert_eq!(kill(pid, SIGKILL), Ok(()));
+    let (_, code) = wait().unwrap();
+    assert_eq!(code, signal_exit_code(SIGKILL));
+    println!("exec_test: PASS");
+}
+
+#[test_case]
+pub fn exec_bad_elf() {
+    println!("exec_bad_elf_test: start");
+    if get_super_block().is_none() {
+        println!("exec_bad_elf_test: SKIP (no file system)");
+        return;
+    }
+    unsafe {
+        DONE.init();
+    }
+    assert_eq!(exec("/no_such_file", &[], &[]), Err(ENOENT));
+
+    const DATA_PH: usize = ELF_HEADER_SIZE + PROGRAM_HEADER_SIZE;
+    let elf = build_elf();
+    let corrupt: [fn(&mut Vec<u8>); 6] = [
+        // Bad magic.
+        |elf| elf[0] = 0,
+        // Not AArch64.
+        |elf| put_u16(elf, 18, 62),
+        // Truncated.
+        |elf| elf.truncate(ELF_HEADER_SIZE + 1),
+        // The data segment reaches beyond the end of file.
+        |elf| put_u64(elf, DATA_PH + 8, 0x10000),
+        // The data segment has more file bytes than memory.
+        |elf| put_u64(elf, DATA_PH + 40, 4),
+        // The data segment is in kernel space.
+        |elf| put_u64(elf, DATA_PH + 16, 0xffff000000000000),
+    ];
+    let proc = create_proc();
+    let page_table = proc.pgdir.get_page_table() as *const _;
+    for f in corrupt {
+        let mut bad = elf.clone();
+        f(&mut bad);
+        let inode = create_file(&bad);
+        assert_eq!(exec_inode(proc, inode, &[], &[]), Err(ENOEXEC));
+        remove_file(inode);
+        // The old address space is kept.
+        assert_eq!(proc.pgdir.get_page_table() as *const _, page_table);
+        assert!(proc.pgdir.walk(BASE_ADDR as usize, false).is_none());
+    }
+    // It can still run a good one.
+    let inode = create_file(&elf);
+    assert_eq!(exec_inode(proc, inode, &[b"exec_test", b"again"], &[]), Ok(()));
+    remove_file(inode);
+    let pid = start_proc(proc, trap_return as *const fn(usize), 0);
+    unsafe {
+        assert!(DONE.get_or_wait());
+        assert_eq!(REPORT, [2, b'a' as u64, DATA, 0]);
+    }
+    assert_eq!(kill(pid, SIGKILL), Ok(()));
+    let (_, code) = wait().unwrap();
+    assert_eq!(code, signal_exit_code(SIGKILL));
+    println!("exec_bad_elf_test: PASS");
+}
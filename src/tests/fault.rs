@@ -0,0 +1,142 @@
+use core::arch::global_asm;
+use core::sync::atomic::Ordering::Relaxed;
+use crate::aarch64::mmu::{kernel2physical, physical2kernel, PAGE_SIZE};
+use crate::common::sem::Semaphore;
+use crate::cores::virtual_memory::{pte_flags, VirtualMemoryPageTable};
+use crate::kernel::exec::USER_STACK_TOP;
+use crate::kernel::mem::{ALLOC_PAGE_CNT, page_ref_count};
+use crate::kernel::errno::Errno::ENOMEM;
+use crate::kernel::errno::SyscallResult;
+use crate::kernel::proc::{create_proc, EXIT_SEGFAULT, kill, Process, start_proc, wait};
+use crate::kernel::signal::{signal_exit_code, SIGKILL};
+use crate::kernel::vm::{Backing, PROT_READ, PROT_WRITE, set_brk, Vma};
+use crate::{define_syscall, println};
+
+static mut DONE: Semaphore = Semaphore::uninit(0);
+static mut REPORT: [u64; 2] = [0; 2];
+
+global_asm!(include_str!("user/fault.asm"));
+
+extern "C" {
+    fn fault_start();
+    fn fault_end();
+    fn trap_return(_: usize);
+}
+
+const BASE_ADDR: usize = 0x400000;
+const REGION_ADDR: usize = 0x1000000;
+const HEAP_ADDR: usize = 0x2000000;
+
+pub fn fault_report(args: [u64; 6]) -> SyscallResult {
+    unsafe {
+        REPORT.copy_from_slice(&args[..2]);
+        DONE.post();
+    }
+    Ok(0)
+}
+
+define_syscall!(117, fault_report);
+
+// Create a process accessing `addr`, with a region of 16 pages at `REGION_ADDR`.
+fn create_fault_proc(addr: usize, writable: bool) -> &'static mut Process {
+    let proc = create_proc();
+    let mut q = fault_start as usize;
+    while q < fault_end as usize {
+        let pte = proc.pgdir.walk(BASE_ADDR + q - fault_start as usize, true).unwrap();
+        unsafe {
+            (*pte).set_addr(kernel2physical(q as u64) as usize, 3);
+            pte_flags::user_page(&mut *pte);
+        }
+        q += PAGE_SIZE;
+    }
+    let prot = if writable { PROT_READ | PROT_WRITE } else { PROT_READ };
+    proc.vmas.insert(Vma::new(REGION_ADDR, REGION_ADDR + 16 * PAGE_SIZE, prot, Backing::Anonymous));
+    unsafe {
+        (*proc.user_context).x[0] = addr as u64;
+        (*proc.user_context).elr_el1 = BASE_ADDR as u64;
+        (*proc.user_context).spsr_el1 = 0;
+    }
+    proc
+}
+
+#[test_case]
+pub fn fault_test() {
+    println!("fault_test: start");
+    unsafe {
+        DONE.init();
+    }
+    // A page of the region is allocated on the first access.
+    let pages = ALLOC_PAGE_CNT.load(Relaxed);
+    let addr = REGION_ADDR + 5 * PAGE_SIZE + 8;
+    let proc = create_fault_proc(addr, true);
+    assert!(proc.pgdir.walk(addr, false).is_none());
+    let pid = start_proc(proc, trap_return as *const fn(usize), 0);
+    unsafe {
+        assert!(DONE.get_or_wait());
+        assert_eq!(REPORT, [addr as u64, 42]);
+    }
+    assert_eq!(kill(pid, SIGKILL), Ok(()));
+    let (_, code) = wait().unwrap();
+    assert_eq!(code, signal_exit_code(SIGKILL));
+    // Reaping a killed process frees all its memory, including the page it touched.
+    assert_eq!(ALLOC_PAGE_CNT.load(Relaxed), pages);
+
+    // Accessing memory out of any region, or writing to a read-only region, kills only the process.
+    for (addr, writable) in [(0x10, true), (REGION_ADDR + 16 * PAGE_SIZE, true), (REGION_ADDR, false)] {
+        let proc = create_fault_proc(addr, writable);
+        let pid = start_proc(proc, trap_return as *const fn(usize), 0);
+        assert_eq!(wait(), Some((pid, EXIT_SEGFAULT)));
+    }
+    assert_eq!(ALLOC_PAGE_CNT.load(Relaxed), pages);
+    unsafe {
+        assert!(!DONE.try_get());
+    }
+    println!("fault_test: PASS");
+}
+
+#[test_case]
+pub fn brk_test() {
+    println!("brk_test: start");
+    unsafe {
+        DONE.init();
+    }
+    let addr = HEAP_ADDR + 2 * PAGE_SIZE + 8;
+    let proc = create_fault_proc(addr, true);
+    proc.brk_start = HEAP_ADDR;
+    proc.brk = HEAP_ADDR;
+    // The heap grows by whole pages, which are allocated on the first access.
+    assert_eq!(set_brk(proc, HEAP_ADDR + 3 * PAGE_SIZE + 1), Ok(()));
+    assert!(proc.pgdir.walk(addr, false).is_none());
+    assert!(proc.vmas.fault_in(&mut proc.pgdir, HEAP_ADDR + 3 * PAGE_SIZE, true, false));
+    let pte = proc.pgdir.walk(HEAP_ADDR + 3 * PAGE_SIZE, false).unwrap();
+    let page = physical2kernel(unsafe { (*pte).addr(3) } as u64) as *mut u8;
+    // Shrinking frees the pages above the new break.
+    assert_eq!(set_brk(proc, HEAP_ADDR + 3 * PAGE_SIZE), Ok(()));
+    assert_eq!(page_ref_count(page), 0);
+    assert!(proc.pgdir.walk(HEAP_ADDR + 3 * PAGE_SIZE, false).is_none());
+    // It cannot grow into another mapping or the stack, or shrink below its start.
+    proc.vmas.insert(Vma::new(HEAP_ADDR + 4 * PAGE_SIZE, HEAP_ADDR + 5 * PAGE_SIZE, PROT_READ, Backing::Anonymous));
+    assert_eq!(set_brk(proc, HEAP_ADDR + 4 * PAGE_SIZE + 1), Err(ENOMEM));
+    assert_eq!(set_brk(proc, USER_STACK_TOP - PAGE_SIZE), Err(ENOMEM));
+    assert_eq!(set_brk(proc, HEAP_ADDR - 1), Err(ENOMEM));
+    assert_eq!(proc.brk, HEAP_ADDR + 3 * PAGE_SIZE);
+    assert_eq!(proc.vmas.find(HEAP_ADDR).unwrap().end, HEAP_ADDR + 3 * PAGE_SIZE);
+
+    let pid = start_proc(proc, trap_return as *const fn(usize), 0);
+    unsafe {
+        assert!(DONE.get_or_wait());
+        assert_eq!(REPORT, [addr as u64, 42]);
+    }
+    assert_eq!(kill(pid, SIGKILL), Ok(()));
+    let (_, code) = wait().unwrap();
+    assert_eq!(code, signal_exit_code(SIGKILL));
+
+    // Memory above the break is not accessible.
+    let proc = create_fault_proc(HEAP_ADDR + 3 * PAGE_SIZE, true);
+    proc.brk_start = HEAP_ADDR;
+    proc.brk = HEAP_ADDR;
+    assert_eq!(set_brk(proc, HEAP_ADDR + 3 * PAGE_SIZE), Ok(()));
+    let pid = start_proc(proc, trap_return as *const fn(usize), 0);
+    assert_eq!(wait(), Some((pid, EXIT_SEGFAULT)));
+    println!("brk_test: PASS");
+}
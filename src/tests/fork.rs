@@ -0,0 +1,116 @@
+use core::arch::global_asm;
+use core::ptr;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use crate::aarch64::mmu::{kernel2physical, PAGE_SIZE};
+use crate::common::sem::Semaphore;
+use crate::cores::virtual_memory::{AccessPermission, PageTableDirectory, pte_flags, VirtualMemoryPageTable};
+use crate::kernel::mem::{kalloc_page, page_ref_count};
+use crate::kernel::errno::SyscallResult;
+use crate::kernel::proc::{create_proc, kill, start_proc, wait};
+use crate::kernel::signal::{signal_exit_code, SIGKILL};
+use crate::kernel::vm::{Backing, PROT_READ, PROT_WRITE, Vma};
+use crate::{define_syscall, println};
+
+static mut DONE: Semaphore = Semaphore::uninit(0);
+static REPORT_CNT: AtomicUsize = AtomicUsize::new(0);
+// (return value of fork, value read back from the data page)
+static mut REPORTS: [(u64, u64); 2] = [(0, 0); 2];
+
+global_asm!(include_str!("user/fork.asm"));
+
+extern "C" {
+    fn fork_start();
+    fn fork_end();
+    fn trap_return(_: usize);
+}
+
+const BASE_ADDR: usize = 0x400000;
+const DATA_ADDR: usize = 0x800000;
+
+pub fn fork_report(args: [u64; 6]) -> SyscallResult {
+    let i = REPORT_CNT.fetch_add(1, Ordering::SeqCst);
+    assert!(i < 2);
+    unsafe {
+        REPORTS[i] = (args[0], args[1]);
+        DONE.post();
+    }
+    Ok(0)
+}
+
+define_syscall!(115, fork_report);
+
+#[test_case]
+pub fn fork_test() {
+    println!("fork_test: start");
+    unsafe {
+        DONE.init();
+    }
+    let proc = create_proc();
+    let mut q = fork_start as usize;
+    while q < fork_end as usize {
+        let pte = proc.pgdir.walk(BASE_ADDR + q - fork_start as usize, true).unwrap();
+        unsafe {
+            (*pte).set_addr(kernel2physical(q as u64) as usize, 3);
+            pte_flags::user_page(&mut *pte);
+        }
+        q += PAGE_SIZE;
+    }
+    // The data page is mapped before fork, so that the processes share it copy-on-write.
+    proc.vmas.insert(Vma::new(DATA_ADDR, DATA_ADDR + PAGE_SIZE, PROT_READ | PROT_WRITE, Backing::Anonymous));
+    let data = kalloc_page(1);
+    unsafe {
+        ptr::write_bytes(data, 0, PAGE_SIZE);
+        let pte = &mut *proc.pgdir.walk(DATA_ADDR, true).unwrap();
+        pte.set_addr(kernel2physical(data as u64) as usize, 3);
+        pte_flags::user_page(pte);
+        pte.set_owned(true);
+        (*proc.user_context).elr_el1 = BASE_ADDR as u64;
+        (*proc.user_context).spsr_el1 = 0;
+    }
+    let parent = start_proc(proc, trap_return as *const fn(usize), 0);
+    unsafe {
+        assert!(DONE.get_or_wait());
+        assert!(DONE.get_or_wait());
+    }
+    let reports = unsafe { REPORTS };
+    let (child, _) = reports.iter().find(|(ret, _)| *ret != 0).copied().unwrap();
+    assert_ne!(child as usize, parent);
+    // Each process sees its own copy of the data page.
+    assert!(reports.contains(&(child, child)));
+    assert!(reports.contains(&(0, 0)));
+    assert_eq!(kill(parent, SIGKILL), Ok(()));
+    assert_eq!(kill(child as usize, SIGKILL), Ok(()));
+    for _ in 0..2 {
+        let (_, code) = wait().unwrap();
+        assert_eq!(code, signal_exit_code(SIGKILL));
+    }
+    println!("fork_test: PASS");
+}
+
+#[test_case]
+pub fn cow_test() {
+    println!("cow_test: start");
+    let mut src = PageTableDirectory::new();
+    let mut dst = PageTableDirectory::new();
+    let page = kalloc_page(1);
+    unsafe {
+        let pte = &mut *src.walk(DATA_ADDR, true).unwrap();
+        pte.set_addr(kernel2physical(page as u64) as usize, 3);
+        pte_flags::user_page(pte);
+        pte.set_owned(true);
+    }
+    dst.copy_from(&src);
+    // Both map the same page read-only, until one of them writes it.
+    for pgdir in [&mut src, &mut dst] {
+        let pte = unsafe { &*pgdir.walk(DATA_ADDR, false).unwrap() };
+        assert!(pte.cow());
+        assert!(matches!(pte.access_permission(), AccessPermission::EL1rEL0r));
+        assert_eq!(pte.addr(3), kernel2physical(page as u64) as usize);
+    }
+    assert_eq!(page_ref_count(page), 2);
+    src.free();
+    assert_eq!(page_ref_count(page), 1);
+    dst.free();
+    assert_eq!(page_ref_count(page), 0);
+    println!("cow_test: PASS");
+}
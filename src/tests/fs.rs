@@ -0,0 +1,32 @@
+use core::ptr;
+use file_system::defines::{INODE_DIRECTORY, ROOT_INODE_NO};
+use file_system::inode::{INODES, InodeTree};
+use file_system::path::namei;
+use crate::kernel::block_device::get_super_block;
+use crate::kernel::sd::get_partitions;
+use crate::println;
+
+#[test_case]
+pub fn fs_mount() {
+    println!("fs_mount_test: start");
+    let sblock = match get_super_block() {
+        Some(sblock) => sblock,
+        None => {
+            println!("fs_mount_test: SKIP (no file system)");
+            return;
+        }
+    };
+    let partition = get_partitions().iter().find(|p| p.is_linux_data()).unwrap();
+    assert!(sblock.num_blocks as usize <= partition.size);
+    assert!(sblock.num_inodes > ROOT_INODE_NO as u32);
+
+    let root = namei("/", ptr::null_mut(), ptr::null_mut()).expect("root not found");
+    unsafe {
+        assert_eq!((*root).inode_no, ROOT_INODE_NO);
+        INODES.lock(root);
+        assert_eq!((*root).entry.typ, INODE_DIRECTORY);
+        INODES.unlock(root);
+        INODES.put(ptr::null_mut(), root);
+    }
+    println!("fs_mount_test: PASS");
+}
@@ -1,8 +1,19 @@
 use alloc::boxed::Box;
+use alloc::vec::Vec;
+use core::arch::global_asm;
 use core::mem::MaybeUninit;
-use crate::common::ipc::{AsMessageBuffer, IPC_CREATE, IPC_EXCL, IPC_RMID, sys_msgctl, sys_msgget, sys_msgrcv, sys_msgsend};
-use crate::kernel::proc::{create_proc, exit, start_proc, wait};
-use crate::println;
+use crate::aarch64::mmu::{kernel2physical, PAGE_SIZE};
+use crate::common::ipc::{AsMessageBuffer, IPC_CREATE, IPC_EXCL, IPC_INFO, IPC_NOWAIT, IPC_PRIVATE, IPC_RMID, IPC_STAT, MSGMAX, MSGMNB, MsgInfo, MsgLimits, msg_limits, msg_set, msg_stat, msgctl, msgget, MsqidDs, msgrcv, msgsnd, set_msg_limits, sys_msgctl, sys_msgrcv, sys_msgsnd};
+use crate::common::sem::Semaphore;
+use crate::cores::virtual_memory::{pte_flags, VirtualMemoryPageTable};
+use crate::kernel::errno::Errno::{EACCES, EAGAIN, EFAULT, EIDRM, EINVAL, ENOMSG, ENOSPC, EPERM};
+use crate::kernel::errno::SyscallResult;
+use crate::kernel::proc::{create_proc, exit, kill, Process, ProcessState, setgid, setuid, start_proc, wait};
+use crate::kernel::signal::{signal_exit_code, SIGKILL};
+use crate::kernel::sched::{thisproc, yield_};
+use crate::kernel::uaccess::UserPtr;
+use crate::kernel::vm::{Backing, PROT_READ, PROT_WRITE, Vma};
+use crate::{define_syscall, println};
 
 static mut MSG: [i32; 10001] = [0; 10001];
 
@@ -16,23 +27,23 @@ impl AsMessageBuffer<Msg> for Msg {}
 
 fn sender(start: usize) {
     let start = start as i32;
-    let msg_id = sys_msgget(114514, 0).expect("msgget failed");
+    let msg_id = msgget(114514, 0).expect("msgget failed");
     for i in start..(start + 100) {
         let mut k = Box::new(Msg {
             mtype: i + 1,
             sum: -i - 1,
         });
-        sys_msgsend(msg_id, k.as_message_buffer(), Msg::message_buffer_size(), 0).expect("msgsend failed");
+        msgsnd(msg_id, k.as_message_buffer(), Msg::message_buffer_size(), 0).expect("msgsend failed");
     }
     exit(0);
 }
 
 fn receiver(start: usize) {
     let start = start as i32;
-    let msg_id = sys_msgget(114514, 0).expect("msgget failed");
+    let msg_id = msgget(114514, 0).expect("msgget failed");
     for _ in start..(start + 1000) {
         let mut k: MaybeUninit<Msg> = MaybeUninit::uninit();
-        sys_msgrcv(msg_id, unsafe { k.assume_init_mut().as_message_buffer() }, Msg::message_buffer_size(), 0, 0).expect("msgrcv failed");
+        msgrcv(msg_id, unsafe { k.assume_init_mut().as_message_buffer() }, Msg::message_buffer_size(), 0, 0).expect("msgrcv failed");
         unsafe { MSG[k.assume_init_mut().mtype as usize] = k.assume_init_mut().sum; }
     }
     exit(0);
@@ -41,7 +52,7 @@ fn receiver(start: usize) {
 #[test_case]
 pub fn ipc_test() {
     println!("ipc test");
-    let msg_id = sys_msgget(114514, IPC_CREATE | IPC_EXCL).expect("msgget failed");
+    let msg_id = msgget(114514, IPC_CREATE | IPC_EXCL).expect("msgget failed");
     for i in 0..100 {
         let proc = create_proc();
         start_proc(proc, sender as *const fn(usize), i * 100);
@@ -52,9 +63,241 @@ pub fn ipc_test() {
     }
     while wait().is_some() {}
 
-    sys_msgctl(msg_id, IPC_RMID).expect("msgctl failed");
+    msgctl(msg_id, IPC_RMID).expect("msgctl failed");
     for i in 1i32..10001 {
         assert_eq!(unsafe { MSG[i as usize] }, -i);
     }
     println!("ipc test PASS");
-}
\ No newline at end of file
+}
+static mut DONE: Semaphore = Semaphore::uninit(0);
+// (result of the syscall, mtype, text) of the sender and the receiver
+static mut REPORTS: [[u64; 3]; 2] = [[0; 3]; 2];
+
+global_asm!(include_str!("user/msg.asm"));
+
+extern "C" {
+    fn msg_start();
+    fn msg_end();
+    fn trap_return(_: usize);
+}
+
+const BASE_ADDR: usize = 0x400000;
+const DATA_ADDR: usize = 0x800000;
+
+pub fn msg_report(args: [u64; 6]) -> SyscallResult {
+    unsafe {
+        REPORTS[args[0] as usize].copy_from_slice(&args[1..4]);
+        DONE.post();
+    }
+    Ok(0)
+}
+
+define_syscall!(119, msg_report);
+
+// Create a process sending to or receiving from the queue `msg_id`.
+fn create_msg_proc(msg_id: i32, receive: bool) -> &'static mut Process {
+    let proc = create_proc();
+    let mut q = msg_start as usize;
+    while q < msg_end as usize {
+        let pte = proc.pgdir.walk(BASE_ADDR + q - msg_start as usize, true).unwrap();
+        unsafe {
+            (*pte).set_addr(kernel2physical(q as u64) as usize, 3);
+            pte_flags::user_page(&mut *pte);
+        }
+        q += PAGE_SIZE;
+    }
+    proc.vmas.insert(Vma::new(DATA_ADDR, DATA_ADDR + PAGE_SIZE, PROT_READ | PROT_WRITE, Backing::Anonymous));
+    unsafe {
+        (*proc.user_context).x[0] = msg_id as u64;
+        (*proc.user_context).x[1] = receive as u64;
+        (*proc.user_context).elr_el1 = BASE_ADDR as u64;
+        (*proc.user_context).spsr_el1 = 0;
+    }
+    proc
+}
+
+#[test_case]
+pub fn ipc_syscall_test() {
+    println!("ipc_syscall_test: start");
+    unsafe {
+        DONE.init();
+    }
+    let msg_id = msgget(IPC_PRIVATE, 0).expect("msgget failed");
+    // Two user processes talk through the queue.
+    let receiver = start_proc(create_msg_proc(msg_id, true), trap_return as *const fn(usize), 0);
+    let sender = start_proc(create_msg_proc(msg_id, false), trap_return as *const fn(usize), 0);
+    unsafe {
+        assert!(DONE.get_or_wait());
+        assert!(DONE.get_or_wait());
+        assert_eq!(REPORTS[0], [0, 7, 0x1234]);
+        assert_eq!(REPORTS[1], [8, 7, 0x1234]);
+    }
+    for pid in [receiver, sender] {
+        assert_eq!(kill(pid, SIGKILL), Ok(()));
+    }
+    for _ in 0..2 {
+        let (_, code) = wait().unwrap();
+        assert_eq!(code, signal_exit_code(SIGKILL));
+    }
+
+    // A receiver blocked on an empty queue can be killed.
+    let proc = create_msg_proc(msg_id, true);
+    let pid = start_proc(proc, trap_return as *const fn(usize), 0);
+    while proc.state != ProcessState::Sleeping {
+        yield_();
+    }
+    assert_eq!(kill(pid, SIGKILL), Ok(()));
+    assert_eq!(wait(), Some((pid, signal_exit_code(SIGKILL))));
+    unsafe {
+        assert!(!DONE.try_get());
+    }
+    // It is not left waiting on the queue.
+    let mut k = Msg { mtype: 3, sum: 42 };
+    msgsnd(msg_id, k.as_message_buffer(), Msg::message_buffer_size(), IPC_NOWAIT).expect("msgsnd failed");
+    let mut k = Msg { mtype: 0, sum: 0 };
+    msgrcv(msg_id, k.as_message_buffer(), Msg::message_buffer_size(), 0, IPC_NOWAIT).expect("msgrcv failed");
+    assert_eq!((k.mtype, k.sum), (3, 42));
+
+    // A bad buffer is rejected.
+    assert_eq!(sys_msgsnd(msg_id, UserPtr::new(0x10), 8, 0), Err(EFAULT));
+    msgctl(msg_id, IPC_RMID).expect("msgctl failed");
+    println!("ipc_syscall_test: PASS");
+}
+
+#[test_case]
+pub fn ipc_perm_test() {
+    println!("ipc_perm_test: start");
+    let proc = thisproc();
+    let msg_id = msgget(1919810, IPC_CREATE | 0o640).expect("msgget failed");
+    let mut k = Msg { mtype: 5, sum: 1 };
+    msgsnd(msg_id, k.as_message_buffer(), Msg::message_buffer_size(), IPC_NOWAIT).expect("msgsnd failed");
+    let ds = msg_stat(msg_id).expect("msg_stat failed");
+    assert_eq!((ds.msg_perm.key, ds.msg_perm.mode), (1919810, 0o640));
+    assert_eq!((ds.msg_qnum, ds.msg_cbytes, ds.msg_qbytes), (1, 4, MSGMNB as u64));
+    assert_eq!((ds.msg_lspid, ds.msg_lrpid), (proc.pid as i32, 0));
+    msgrcv(msg_id, k.as_message_buffer(), Msg::message_buffer_size(), 0, IPC_NOWAIT).expect("msgrcv failed");
+    let ds = msg_stat(msg_id).expect("msg_stat failed");
+    assert_eq!((ds.msg_qnum, ds.msg_cbytes, ds.msg_lrpid), (0, 0, proc.pid as i32));
+
+    // The limit counts bytes.
+    let mut ds = ds;
+    ds.msg_qbytes = 6;
+    msg_set(msg_id, &ds).expect("msg_set failed");
+    msgsnd(msg_id, k.as_message_buffer(), Msg::message_buffer_size(), IPC_NOWAIT).expect("msgsnd failed");
+    assert_eq!(msgsnd(msg_id, k.as_message_buffer(), Msg::message_buffer_size(), IPC_NOWAIT), Err(EAGAIN));
+    // A message larger than the limit fails at once, instead of waiting forever.
+    let mut small = ds;
+    small.msg_qbytes = 3;
+    msg_set(msg_id, &small).expect("msg_set failed");
+    assert_eq!(msgsnd(msg_id, k.as_message_buffer(), Msg::message_buffer_size(), 0), Err(EINVAL));
+
+    // Give the queue to uid 1000 in group 100, readable by the group only.
+    ds.msg_perm.uid = 1000;
+    ds.msg_perm.gid = 100;
+    ds.msg_perm.mode = 0o640;
+    msg_set(msg_id, &ds).expect("msg_set failed");
+    proc.uid = 1001;
+    proc.gid = 100;
+    assert!(msg_stat(msg_id).is_ok());
+    assert_eq!(msgget(1919810, 0o600), Err(EACCES));
+    assert_eq!(msgsnd(msg_id, k.as_message_buffer(), Msg::message_buffer_size(), IPC_NOWAIT), Err(EACCES));
+    assert_eq!(msg_set(msg_id, &ds), Err(EPERM));
+    assert_eq!(msgctl(msg_id, IPC_RMID), Err(EPERM));
+    proc.gid = 101;
+    assert_eq!(msg_stat(msg_id).err(), Some(EACCES));
+    assert_eq!(msgrcv(msg_id, k.as_message_buffer(), Msg::message_buffer_size(), 0, IPC_NOWAIT), Err(EACCES));
+    // The owner can change the queue, but cannot raise its limit.
+    proc.uid = 1000;
+    ds.msg_qbytes = MSGMNB as u64 + 1;
+    assert_eq!(msg_set(msg_id, &ds), Err(EPERM));
+    ds.msg_qbytes = 64;
+    msg_set(msg_id, &ds).expect("msg_set failed");
+    msgrcv(msg_id, k.as_message_buffer(), Msg::message_buffer_size(), 0, IPC_NOWAIT).expect("msgrcv failed");
+    proc.uid = 0;
+    proc.gid = 0;
+
+    // Root may take any ids, but cannot get its privileges back then.
+    assert_eq!(setgid(100), Ok(()));
+    assert_eq!(setuid(1000), Ok(()));
+    assert_eq!((proc.uid, proc.gid), (1000, 100));
+    assert_eq!(setuid(1000), Ok(()));
+    assert_eq!(setuid(0), Err(EPERM));
+    assert_eq!(setgid(0), Err(EPERM));
+    proc.uid = 0;
+    proc.gid = 0;
+
+    // Through the syscall, the status and the limits are copied to user space.
+    const ADDR: usize = 0x4000000;
+    proc.vmas.insert(Vma::new(ADDR, ADDR + PAGE_SIZE, PROT_READ | PROT_WRITE, Backing::Anonymous));
+    assert_eq!(sys_msgctl(msg_id, IPC_STAT, ADDR), Ok(0));
+    let ds = UserPtr::<MsqidDs>::new(ADDR).read().unwrap();
+    assert_eq!((ds.msg_perm.uid, ds.msg_perm.cuid, ds.msg_qbytes), (1000, 0, 64));
+    assert!(sys_msgctl(msg_id, IPC_INFO, ADDR).is_ok());
+    let info = UserPtr::<MsgInfo>::new(ADDR).read().unwrap();
+    assert_eq!((info.msgmax, info.msgmnb), (MSGMAX as i32, MSGMNB as i32));
+    assert_eq!(sys_msgctl(msg_id, IPC_STAT, 0x10), Err(EFAULT));
+    // Receiving nothing leaves the buffer untouched.
+    UserPtr::<[u64; 2]>::new(ADDR).write([0x55, 0x66]).unwrap();
+    assert_eq!(sys_msgrcv(msg_id, UserPtr::new(ADDR), 8, 0, IPC_NOWAIT), Err(ENOMSG));
+    assert_eq!(UserPtr::<[u64; 2]>::new(ADDR).read(), Ok([0x55, 0x66]));
+    assert_eq!(sys_msgrcv(msg_id, UserPtr::new(0x10), 8, 0, IPC_NOWAIT), Err(EFAULT));
+    proc.vmas.unmap(&mut proc.pgdir, ADDR, ADDR + PAGE_SIZE);
+
+    msgctl(msg_id, IPC_RMID).expect("msgctl failed");
+    println!("ipc_perm_test: PASS");
+}
+
+// Send to the queue `msg_id` until it is full and blocks, and exit with the error.
+fn blocked_sender(msg_id: usize) {
+    let mut k = Msg { mtype: 1, sum: 0 };
+    loop {
+        if let Err(err) = msgsnd(msg_id as i32, k.as_message_buffer(), Msg::message_buffer_size(), 0) {
+            exit(err as isize);
+        }
+    }
+}
+
+#[test_case]
+pub fn ipc_ids_test() {
+    println!("ipc_ids_test: start");
+    let limits = msg_limits();
+    set_msg_limits(MsgLimits { msgmni: 100, ..limits }).expect("set_msg_limits failed");
+    let mut ids = Vec::new();
+    loop {
+        match msgget(IPC_PRIVATE, 0) {
+            Ok(id) => ids.push(id),
+            Err(err) => {
+                assert_eq!(err, ENOSPC);
+                break;
+            }
+        }
+    }
+    assert_eq!(ids.len(), 100);
+
+    // A removed id stays invalid after its slot is reused.
+    let mut k = Msg { mtype: 1, sum: 0 };
+    let stale = ids[42];
+    msgctl(stale, IPC_RMID).expect("msgctl failed");
+    ids[42] = msgget(IPC_PRIVATE, 0).expect("msgget failed");
+    assert_ne!(ids[42], stale);
+    assert_eq!(msgsnd(stale, k.as_message_buffer(), Msg::message_buffer_size(), IPC_NOWAIT), Err(EIDRM));
+    assert_eq!(msg_stat(stale).err(), Some(EIDRM));
+    assert_eq!(msgctl(stale, IPC_RMID), Err(EIDRM));
+    assert_eq!(msg_stat(-1).err(), Some(EINVAL));
+
+    // Removing a queue wakes up the blocked senders with EIDRM.
+    let mut ds = msg_stat(ids[0]).expect("msg_stat failed");
+    ds.msg_qbytes = 8;
+    msg_set(ids[0], &ds).expect("msg_set failed");
+    let proc = create_proc();
+    let pid = start_proc(proc, blocked_sender as *const fn(usize), ids[0] as usize);
+    while proc.state != ProcessState::Sleeping {
+        yield_();
+    }
+    for id in ids {
+        msgctl(id, IPC_RMID).expect("msgctl failed");
+    }
+    assert_eq!(wait(), Some((pid, EIDRM as isize)));
+    set_msg_limits(limits).expect("set_msg_limits failed");
+    println!("ipc_ids_test: PASS");
+}
@@ -2,5 +2,15 @@ pub mod allocator;
 pub mod list;
 pub mod proc_state;
 pub mod ipc;
+pub mod syscall;
 pub mod user_proc;
-pub mod sd;
\ No newline at end of file
+pub mod fork;
+pub mod exec;
+pub mod fault;
+pub mod vma;
+pub mod uaccess;
+pub mod signal;
+pub mod session;
+pub mod sd;
+pub mod fs;
+pub mod partition;
\ No newline at end of file
@@ -0,0 +1,114 @@
+use alloc::vec;
+use alloc::vec::Vec;
+use crate::kernel::gpt::{crc32, Guid};
+use crate::kernel::mbr::{PART_TYPE_EXTENDED_LBA, PART_TYPE_FAT32_LBA, PART_TYPE_GPT_PROTECTIVE, PART_TYPE_LINUX};
+use crate::kernel::partition::{Partition, PartitionType, scan, SECTOR_SIZE};
+use crate::println;
+
+type Disk = Vec<[u8; SECTOR_SIZE]>;
+
+const DISK_LEN: usize = 256;
+
+fn put_mbr_entry(sector: &mut [u8; SECTOR_SIZE], slot: usize, typ: u8, start: u32, size: u32) {
+    let entry = &mut sector[0x1BE + slot * 16..0x1BE + (slot + 1) * 16];
+    entry[0x4] = typ;
+    entry[0x8..0xC].copy_from_slice(&start.to_le_bytes());
+    entry[0xC..0x10].copy_from_slice(&size.to_le_bytes());
+    sector[0x1FE] = 0x55;
+    sector[0x1FF] = 0xAA;
+}
+
+fn scan_disk(disk: &Disk) -> Vec<Partition> {
+    scan(|block_no, data| data.copy_from_slice(&disk[block_no]))
+}
+
+// Write a GPT header at `lba` whose entry array (4 sectors, 16 entries) is at `entries_lba`.
+fn put_gpt(disk: &mut Disk, lba: usize, backup_lba: usize, entries_lba: usize, entries: &[(Guid, u64, u64)]) {
+    let mut array = vec![0u8; 16 * 128];
+    for (i, (typ, first, last)) in entries.iter().enumerate() {
+        let entry = &mut array[i * 128..(i + 1) * 128];
+        entry[0..16].copy_from_slice(&typ.0);
+        entry[32..40].copy_from_slice(&first.to_le_bytes());
+        entry[40..48].copy_from_slice(&last.to_le_bytes());
+    }
+    for (i, chunk) in array.chunks(SECTOR_SIZE).enumerate() {
+        disk[entries_lba + i].copy_from_slice(chunk);
+    }
+
+    let header = &mut disk[lba];
+    header.fill(0);
+    header[0..8].copy_from_slice(b"EFI PART");
+    header[8..12].copy_from_slice(&0x10000u32.to_le_bytes());
+    header[12..16].copy_from_slice(&92u32.to_le_bytes());
+    header[24..32].copy_from_slice(&(lba as u64).to_le_bytes());
+    header[32..40].copy_from_slice(&(backup_lba as u64).to_le_bytes());
+    header[40..48].copy_from_slice(&34u64.to_le_bytes());
+    header[48..56].copy_from_slice(&((DISK_LEN - 34) as u64).to_le_bytes());
+    header[72..80].copy_from_slice(&(entries_lba as u64).to_le_bytes());
+    header[80..84].copy_from_slice(&16u32.to_le_bytes());
+    header[84..88].copy_from_slice(&128u32.to_le_bytes());
+    header[88..92].copy_from_slice(&crc32(&array).to_le_bytes());
+    let crc = crc32(&header[..92]);
+    header[16..20].copy_from_slice(&crc.to_le_bytes());
+}
+
+#[test_case]
+pub fn partition_mbr_logical() {
+    println!("partition_mbr_logical_test: start");
+    let mut disk: Disk = vec![[0; SECTOR_SIZE]; DISK_LEN];
+    put_mbr_entry(&mut disk[0], 0, PART_TYPE_FAT32_LBA, 8, 32);
+    put_mbr_entry(&mut disk[0], 1, PART_TYPE_EXTENDED_LBA, 64, 128);
+    // Two logical partitions, each preceded by its EBR.
+    put_mbr_entry(&mut disk[64], 0, PART_TYPE_LINUX, 1, 31);
+    put_mbr_entry(&mut disk[64], 1, PART_TYPE_EXTENDED_LBA, 32, 64);
+    put_mbr_entry(&mut disk[96], 0, PART_TYPE_LINUX, 2, 62);
+
+    let partitions = scan_disk(&disk);
+    let summary: Vec<_> = partitions.iter().map(|p| (p.index, p.typ, p.start, p.size)).collect();
+    assert_eq!(summary, vec![
+        (1, PartitionType::Mbr(PART_TYPE_FAT32_LBA), 8, 32),
+        (5, PartitionType::Mbr(PART_TYPE_LINUX), 65, 31),
+        (6, PartitionType::Mbr(PART_TYPE_LINUX), 98, 62),
+    ]);
+    assert!(partitions[1].is_linux_data());
+
+    // An EBR chain with a loop must not hang.
+    put_mbr_entry(&mut disk[96], 1, PART_TYPE_EXTENDED_LBA, 0, 64);
+    scan_disk(&disk);
+
+    disk[0][0x1FF] = 0;
+    assert!(scan_disk(&disk).is_empty());
+    println!("partition_mbr_logical_test: PASS");
+}
+
+#[test_case]
+pub fn partition_gpt() {
+    println!("partition_gpt_test: start");
+    let last = DISK_LEN - 1;
+    let mut disk: Disk = vec![[0; SECTOR_SIZE]; DISK_LEN];
+    put_mbr_entry(&mut disk[0], 0, PART_TYPE_GPT_PROTECTIVE, 1, last as u32);
+    let entries = [(Guid::EFI_SYSTEM, 34, 63), (Guid::LINUX_FILESYSTEM, 64, 200)];
+    put_gpt(&mut disk, 1, last, 2, &entries);
+    put_gpt(&mut disk, last, 1, last - 4, &entries);
+
+    let check = |partitions: Vec<Partition>| {
+        let summary: Vec<_> = partitions.iter().map(|p| (p.index, p.typ, p.start, p.size)).collect();
+        assert_eq!(summary, vec![
+            (1, PartitionType::Gpt(Guid::EFI_SYSTEM), 34, 30),
+            (2, PartitionType::Gpt(Guid::LINUX_FILESYSTEM), 64, 137),
+        ]);
+    };
+    check(scan_disk(&disk));
+
+    // A corrupted primary entry array falls back to the backup table.
+    disk[2][0] ^= 1;
+    check(scan_disk(&disk));
+    // So does a corrupted primary header.
+    disk[2][0] ^= 1;
+    disk[1][40] ^= 1;
+    check(scan_disk(&disk));
+
+    disk[last][40] ^= 1;
+    assert!(scan_disk(&disk).is_empty());
+    println!("partition_gpt_test: PASS");
+}
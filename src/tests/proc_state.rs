@@ -1,7 +1,16 @@
 use core::mem::MaybeUninit;
 use crate::common::sem::Semaphore;
-use crate::kernel::proc::{create_proc, exit, start_proc, wait};
-use crate::kernel::sched::yield_;
+use crate::kernel::errno::Errno::{ECHILD, EINVAL};
+use crate::aarch64::mmu::PAGE_SIZE;
+use crate::kernel::KERNEL_STACK_SIZE;
+use crate::kernel::proc::{create_proc, exit, ExitStatus, kill, ProcessState, root_proc, start_proc, sys_wait4, wait,
+                          waitpid, WNOHANG};
+use crate::kernel::procinfo::{ps, snapshot};
+use crate::kernel::sched::{thisproc, yield_};
+use crate::kernel::vm::{Backing, PROT_READ, PROT_WRITE, Vma};
+use crate::kernel::signal::{SIGTERM, signal_exit_code};
+use crate::kernel::syscall::Flags;
+use crate::kernel::uaccess::UserPtr;
 use crate::println;
 
 static mut SEMAPHORES: [MaybeUninit<Semaphore>; 6] = MaybeUninit::uninit_array();
@@ -133,4 +142,96 @@ pub fn proc_test() {
     }
     assert_eq!(t, 1048575);
     println!("proc_test: pass");
-}
\ No newline at end of file
+}
+static mut WAIT_SEM: Semaphore = Semaphore::uninit(0);
+
+// Exit with `code`, after `WAIT_SEM` is posted if `code` is odd.
+unsafe fn waitpid_child(code: usize) {
+    if code & 1 != 0 {
+        WAIT_SEM.get_or_wait();
+    }
+    exit(code as isize);
+}
+
+#[test_case]
+pub fn waitpid_test() {
+    println!("waitpid_test: start");
+    unsafe {
+        WAIT_SEM.init();
+    }
+    let a = start_proc(create_proc(), waitpid_child as *const fn(usize), 3);
+    let b = start_proc(create_proc(), waitpid_child as *const fn(usize), 4);
+    // Waiting for `a` leaves `b` alone, even if `b` has exited.
+    assert_eq!(waitpid(a as isize, WNOHANG), Ok(None));
+    unsafe {
+        WAIT_SEM.post();
+    }
+    assert_eq!(waitpid(a as isize, 0), Ok(Some((a, ExitStatus { code: 3, signal: 0 }))));
+    assert_eq!(waitpid(-1, 0), Ok(Some((b, ExitStatus { code: 4, signal: 0 }))));
+    assert_eq!(waitpid(a as isize, WNOHANG), Err(ECHILD));
+    assert_eq!(waitpid(-1, WNOHANG), Err(ECHILD));
+
+    // A process group is waited for as a whole.
+    let p = create_proc();
+    p.pgid = 4242;
+    let c = start_proc(p, waitpid_child as *const fn(usize), 6);
+    assert_eq!(waitpid(0, WNOHANG), Err(ECHILD));
+    assert_eq!(waitpid(-4242, 0), Ok(Some((c, ExitStatus { code: 6, signal: 0 }))));
+
+    // The status word tells an exit code from a signal.
+    let d = start_proc(create_proc(), waitpid_child as *const fn(usize), 5);
+    assert_eq!(kill(d, SIGTERM), Ok(()));
+    let status = ExitStatus { code: signal_exit_code(SIGTERM), signal: SIGTERM };
+    assert_eq!(waitpid(d as isize, 0), Ok(Some((d, status))));
+    assert_eq!(status.encode(), SIGTERM as i32);
+    assert_eq!(ExitStatus { code: 3, signal: 0 }.encode(), 0x300);
+
+    let e = start_proc(create_proc(), waitpid_child as *const fn(usize), 8);
+    assert_eq!(sys_wait4(e as i32, UserPtr::new(0), Flags(0), 0x1000), Err(EINVAL));
+    assert_eq!(sys_wait4(e as i32, UserPtr::new(0), Flags(0), 0), Ok(e as u64));
+    assert_eq!(wait(), None);
+    println!("waitpid_test: PASS");
+}
+
+#[test_case]
+pub fn procinfo_test() {
+    println!("procinfo_test: start");
+    unsafe {
+        WAIT_SEM.init();
+    }
+    let p = create_proc();
+    // Give the child two user pages to count.
+    p.vmas.insert(Vma::new(0x1000000, 0x1000000 + 4 * PAGE_SIZE, PROT_READ | PROT_WRITE, Backing::Anonymous));
+    assert!(p.vmas.fault_in(&mut p.pgdir, 0x1000000, true, false));
+    assert!(p.vmas.fault_in(&mut p.pgdir, 0x1000000 + 3 * PAGE_SIZE, true, false));
+    p.sch_info.nice += 5;
+    let child = start_proc(p, waitpid_child as *const fn(usize), 9);
+
+    // Pages are only counted once the child sleeps, since it may run on another CPU until then.
+    let procs = loop {
+        let procs = snapshot();
+        if procs.iter().any(|info| info.pid == child as u64 && info.state == ProcessState::Sleeping) {
+            break procs;
+        }
+        yield_();
+    };
+    assert!(procs.windows(2).all(|w| w[0].pid < w[1].pid));
+    let me = thisproc().pid as u64;
+    let info = procs.iter().find(|info| info.pid == me).unwrap();
+    assert_eq!(info.ppid, root_proc().pid as u64);
+    assert_eq!(info.state, ProcessState::Running);
+    assert!(info.last_cpu >= 0);
+    assert!(info.stack_used > 0 && info.stack_used < KERNEL_STACK_SIZE as u64);
+    assert_eq!(info.pages, 0);
+    let info = procs.iter().find(|info| info.pid == child as u64).unwrap();
+    assert_eq!((info.ppid, info.pgid), (me, thisproc().pgid as u64));
+    assert_eq!((info.nice, info.pages), (5, 2));
+    ps();
+
+    unsafe {
+        WAIT_SEM.post();
+    }
+    assert_eq!(waitpid(child as isize, 0), Ok(Some((child, ExitStatus { code: 9, signal: 0 }))));
+    assert!(snapshot().iter().all(|info| info.pid != child as u64));
+    println!("procinfo_test: PASS");
+}
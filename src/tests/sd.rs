@@ -1,6 +1,6 @@
 use core::mem::MaybeUninit;
 use crate::aarch64::intrinsic::{get_timer_freq, get_timestamp};
-use crate::kernel::sd::{B_DIRTY, Buffer, sd_rw};
+use crate::kernel::sd::{B_DIRTY, B_VALID, Buffer, sd_rw, sd_submit, sd_wait};
 use crate::{dsb_sy, print, println};
 use crate::kernel::proc::{create_proc, exit, start_proc, wait};
 
@@ -24,23 +24,23 @@ fn rw_proc(start_buf_index: usize) {
         for i in (start_buf_index + 1)..(start_buf_index + (1 << 4)) {
             BS[start_buf_index].flags = 0;
             BS[start_buf_index].block_no = i as u32;
-            sd_rw(&mut BS[start_buf_index]);
+            sd_rw(&mut BS[start_buf_index]).unwrap();
             BS[i].flags = B_DIRTY;
             BS[i].block_no = i as u32;
             for (j, d) in BS[i].data.iter_mut().enumerate() {
                 *d = ((i * j) & 0xff) as u8;
             }
-            sd_rw(&mut BS[i]);
+            sd_rw(&mut BS[i]).unwrap();
 
             BS[i].data.fill(0);
             BS[i].flags = 0;
-            sd_rw(&mut BS[i]);
+            sd_rw(&mut BS[i]).unwrap();
             for (j, d) in BS[i].data.iter().enumerate() {
                 assert_eq!(*d, ((i * j) & 0xff) as u8);
             }
 
             BS[start_buf_index].flags = B_DIRTY;
-            sd_rw(&mut BS[start_buf_index]);
+            sd_rw(&mut BS[start_buf_index]).unwrap();
         }
     }
     exit(0);
@@ -73,24 +73,24 @@ pub fn sd_test() {
         for i in 1..BS.len() {
             BS[0].flags = 0;
             BS[0].block_no = i as u32;
-            sd_rw(&mut BS[0]);
+            sd_rw(&mut BS[0]).unwrap();
 
             BS[i].flags = B_DIRTY;
             BS[i].block_no = i as u32;
             for (j, d) in BS[i].data.iter_mut().enumerate() {
                 *d = ((i * j) & 0xff) as u8;
             }
-            sd_rw(&mut BS[i]);
+            sd_rw(&mut BS[i]).unwrap();
 
             BS[i].data.fill(0);
             BS[i].flags = 0;
-            sd_rw(&mut BS[i]);
+            sd_rw(&mut BS[i]).unwrap();
             for (j, d) in BS[i].data.iter().enumerate() {
                 assert_eq!(*d, ((i * j) & 0xff) as u8);
             }
 
             BS[0].flags = B_DIRTY;
-            sd_rw(&mut BS[0]);
+            sd_rw(&mut BS[0]).unwrap();
         }
         println!("OK");
 
@@ -101,7 +101,7 @@ pub fn sd_test() {
         for (i, b) in BS.iter_mut().enumerate() {
             b.flags = 0;
             b.block_no = i as u32;
-            sd_rw(b);
+            sd_rw(b).unwrap();
         }
         dsb_sy();
         let t = get_timestamp() - t;
@@ -116,7 +116,7 @@ pub fn sd_test() {
         for (i, b) in BS.iter_mut().enumerate() {
             b.flags = B_DIRTY;
             b.block_no = i as u32;
-            sd_rw(b);
+            sd_rw(b).unwrap();
         }
         dsb_sy();
         let t = get_timestamp() - t;
@@ -124,4 +124,39 @@ pub fn sd_test() {
         let speed: f64 = ((size_in_mb as u64) * freq) as f64;
         println!("{} MB/s", speed / (t as f64));
     }
-}
\ No newline at end of file
+}
+
+#[test_case]
+pub fn sd_batch_rw() {
+    println!("sd_batch_rw_test: start");
+    // Buffers of adjacent blocks submitted together are merged into multi-block transfers.
+    let batch = 1..(1 << 7);
+    unsafe {
+        for i in batch.clone() {
+            BS[i].init();
+            BS[i].flags = B_DIRTY;
+            BS[i].block_no = i as u32;
+            for (j, d) in BS[i].data.iter_mut().enumerate() {
+                *d = ((i + j) & 0xff) as u8;
+            }
+            sd_submit(&mut BS[i]);
+        }
+        for i in batch.clone() {
+            sd_wait(&mut BS[i]).unwrap();
+            assert_eq!(BS[i].flags, B_VALID);
+        }
+
+        for i in batch.clone() {
+            BS[i].data.fill(0);
+            BS[i].flags = 0;
+            sd_submit(&mut BS[i]);
+        }
+        for i in batch {
+            sd_wait(&mut BS[i]).unwrap();
+            for (j, d) in BS[i].data.iter().enumerate() {
+                assert_eq!(*d, ((i + j) & 0xff) as u8);
+            }
+        }
+    }
+    println!("sd_batch_rw_test: PASS");
+}
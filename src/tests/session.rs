@@ -0,0 +1,103 @@
+use crate::common::sem::Semaphore;
+use crate::kernel::errno::Errno::{EPERM, ESRCH, ENOTTY};
+use crate::kernel::proc::{create_proc, exit, ExitStatus, root_proc, start_proc, waitpid, WNOHANG};
+use crate::kernel::sched::{acquire_proc_lock, thisproc};
+use crate::kernel::session::{getpgid, getsid, kill_pgrp, setpgid, setsid, tcgetpgrp, tcgetsid, tcsetpgrp, tiocnotty,
+                             tiocsctty, tty_signal};
+use crate::kernel::signal::{signal_exit_code, SIGHUP, SIGINT, SIGTERM};
+use crate::println;
+
+// Never posted: a job blocks on it until a signal kills it.
+static mut BLOCK: Semaphore = Semaphore::uninit(0);
+static mut READY: Semaphore = Semaphore::uninit(0);
+
+unsafe fn job(stop: usize) {
+    if stop != 0 {
+        // As if stopped by SIGSTOP, which a kernel thread never handles.
+        {
+            let _lock = acquire_proc_lock(thisproc());
+            thisproc().signal.stopped = true;
+        }
+        READY.post();
+    }
+    BLOCK.get_or_wait();
+    exit(0);
+}
+
+// Start a job of `n` processes in a new group, and return the group.
+fn start_job(n: usize, stop: usize) -> usize {
+    let mut pgid = 0;
+    for _ in 0..n {
+        let p = create_proc();
+        assert_eq!(setpgid(p.pid, pgid), Ok(()));
+        pgid = p.pgid;
+        start_proc(p, job as *const fn(usize), stop);
+    }
+    pgid
+}
+
+fn killed_by(sig: usize) -> ExitStatus {
+    ExitStatus { code: signal_exit_code(sig), signal: sig }
+}
+
+// A shell, which leads a session with the console, runs jobs, and exits leaving a stopped job behind.
+unsafe fn shell(_: usize) {
+    let me = thisproc().pid;
+    assert_eq!(setsid(), Ok(me));
+    assert_eq!((getsid(0), getpgid(me)), (Ok(me), Ok(me)));
+    assert_eq!(setsid(), Err(EPERM));
+    assert_eq!(setpgid(0, 0), Err(EPERM));
+    assert_eq!(setpgid(root_proc().pid, 0), Err(ESRCH));
+    assert_eq!(tcgetpgrp(), Err(ENOTTY));
+    assert_eq!(tiocsctty(), Ok(()));
+    assert_eq!((tcgetpgrp(), tcgetsid()), (Ok(me), Ok(me)));
+
+    // Ctrl-C kills the foreground job as a whole.
+    let fg = start_job(2, 0);
+    assert_eq!(setpgid(fg, 4242), Err(EPERM));
+    assert_eq!(tcsetpgrp(4242), Err(EPERM));
+    assert_eq!(tcsetpgrp(fg), Ok(()));
+    assert_eq!(tcgetpgrp(), Ok(fg));
+    tty_signal(SIGINT);
+    for _ in 0..2 {
+        assert_eq!(waitpid(-(fg as isize), 0).unwrap().unwrap().1, killed_by(SIGINT));
+    }
+    assert_eq!(tcsetpgrp(me), Ok(()));
+
+    // So does `kill(-pgid)`, and leaves other groups alone.
+    let bg = start_job(3, 0);
+    let other = start_job(1, 0);
+    assert_eq!(kill_pgrp(bg, SIGTERM), Ok(()));
+    for _ in 0..3 {
+        assert_eq!(waitpid(-(bg as isize), 0).unwrap().unwrap().1, killed_by(SIGTERM));
+    }
+    assert_eq!(kill_pgrp(bg, SIGTERM), Err(ESRCH));
+    assert_eq!(waitpid(other as isize, WNOHANG), Ok(None));
+    assert_eq!(kill_pgrp(other, SIGTERM), Ok(()));
+    assert_eq!(waitpid(other as isize, 0).unwrap().unwrap().1, killed_by(SIGTERM));
+
+    // Our exit orphans this group, which then gets SIGHUP.
+    start_job(1, 1);
+    READY.get_or_wait();
+    exit(0);
+}
+
+#[test_case]
+pub fn session_test() {
+    println!("session_test: start");
+    unsafe {
+        BLOCK.init();
+        READY.init();
+    }
+    let shell = start_proc(create_proc(), shell as *const fn(usize), 0);
+    assert_eq!(waitpid(shell as isize, 0), Ok(Some((shell, ExitStatus { code: 0, signal: 0 }))));
+    // The stopped job now belongs to us.
+    assert_eq!(waitpid(-1, 0).unwrap().unwrap().1, killed_by(SIGHUP));
+
+    // The console is free again.
+    assert_eq!(getsid(0), Ok(root_proc().pid));
+    assert_eq!(tiocsctty(), Ok(()));
+    assert_eq!(tiocnotty(), Ok(()));
+    assert_eq!(tcgetpgrp(), Err(ENOTTY));
+    println!("session_test: PASS");
+}
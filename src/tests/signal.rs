@@ -0,0 +1,102 @@
+use core::arch::global_asm;
+use crate::aarch64::mmu::{kernel2physical, PAGE_SIZE};
+use crate::common::sem::Semaphore;
+use crate::cores::virtual_memory::{pte_flags, VirtualMemoryPageTable};
+use crate::kernel::errno::Errno::{EINVAL, ESRCH};
+use crate::kernel::errno::SyscallResult;
+use crate::kernel::proc::{create_proc, kill, Process, ProcessState, start_proc, wait};
+use crate::kernel::sched::{thisproc, yield_};
+use crate::kernel::signal::{NSIG, SigAction, sigaction, sigmask, signal_exit_code, SIGKILL, SIGCONT, SIGSTOP, SIGTERM, SIGUSR1, SIGUSR2};
+use crate::kernel::vm::{Backing, PROT_READ, PROT_WRITE, Vma};
+use crate::{define_syscall, println};
+
+static mut DONE: Semaphore = Semaphore::uninit(0);
+static mut REPORTS: [[u64; 2]; 3] = [[0; 2]; 3];
+
+global_asm!(include_str!("user/signal.asm"));
+
+extern "C" {
+    fn signal_start();
+    fn signal_end();
+    fn trap_return(_: usize);
+}
+
+const BASE_ADDR: usize = 0x400000;
+const DATA_ADDR: usize = 0x800000;
+
+pub fn signal_report(args: [u64; 6]) -> SyscallResult {
+    unsafe {
+        REPORTS[args[0] as usize].copy_from_slice(&args[1..3]);
+        DONE.post();
+    }
+    Ok(0)
+}
+
+define_syscall!(120, signal_report);
+
+// Create a process handling signals, or spinning if `spin`. It has a page of data and a page of stack.
+fn create_signal_proc(spin: bool) -> &'static mut Process {
+    let proc = create_proc();
+    let mut q = signal_start as usize;
+    while q < signal_end as usize {
+        let pte = proc.pgdir.walk(BASE_ADDR + q - signal_start as usize, true).unwrap();
+        unsafe {
+            (*pte).set_addr(kernel2physical(q as u64) as usize, 3);
+            pte_flags::user_page(&mut *pte);
+        }
+        q += PAGE_SIZE;
+    }
+    proc.vmas.insert(Vma::new(DATA_ADDR, DATA_ADDR + 2 * PAGE_SIZE, PROT_READ | PROT_WRITE, Backing::Anonymous));
+    unsafe {
+        (*proc.user_context).x[0] = spin as u64;
+        (*proc.user_context).elr_el1 = BASE_ADDR as u64;
+        (*proc.user_context).spsr_el1 = 0;
+        (*proc.user_context).sp_el0 = (DATA_ADDR + 2 * PAGE_SIZE) as u64;
+    }
+    proc
+}
+
+#[test_case]
+pub fn signal_test() {
+    println!("signal_test: start");
+    unsafe {
+        DONE.init();
+    }
+    // The handler runs on the user stack, and `rt_sigreturn` resumes the interrupted code.
+    let pid = start_proc(create_signal_proc(false), trap_return as *const fn(usize), 0);
+    unsafe {
+        assert!(DONE.get_or_wait());
+    }
+    assert_eq!(kill(pid, SIGUSR2), Ok(()));
+    assert_eq!(kill(pid, SIGUSR1), Ok(()));
+    unsafe {
+        assert!(DONE.get_or_wait());
+        assert!(DONE.get_or_wait());
+        assert_eq!(REPORTS[1][0], SIGUSR1 as u64);
+        assert_eq!(REPORTS[2], [0x1234, sigmask(SIGUSR2)]);
+    }
+    // Unblocking SIGUSR2 terminates the process by the default action.
+    assert_eq!(wait(), Some((pid, signal_exit_code(SIGUSR2))));
+
+    // A stopped process sleeps until SIGCONT.
+    let proc = create_signal_proc(true);
+    let pid = start_proc(proc, trap_return as *const fn(usize), 0);
+    assert_eq!(kill(pid, SIGSTOP), Ok(()));
+    while !proc.signal.stopped || proc.state != ProcessState::Sleeping {
+        yield_();
+    }
+    assert_eq!(kill(pid, SIGCONT), Ok(()));
+    while proc.signal.stopped {
+        yield_();
+    }
+    assert_eq!(kill(pid, SIGTERM), Ok(()));
+    assert_eq!(wait(), Some((pid, signal_exit_code(SIGTERM))));
+
+    assert_eq!(kill(pid, SIGTERM), Err(ESRCH));
+    assert_eq!(kill(thisproc().pid, NSIG + 1), Err(EINVAL));
+    assert_eq!(sigaction(SIGKILL, Some(SigAction::default())), Err(EINVAL));
+    // A handler needs a restorer.
+    let act = SigAction { handler: BASE_ADDR as u64, ..Default::default() };
+    assert_eq!(sigaction(SIGUSR1, Some(act)), Err(EINVAL));
+    println!("signal_test: PASS");
+}
@@ -0,0 +1,52 @@
+use core::mem::MaybeUninit;
+use crate::define_syscall;
+use crate::kernel::errno::Errno::{EBADF, EINVAL, ENOSYS};
+use crate::kernel::errno::SyscallResult;
+use crate::kernel::proc::UserContext;
+use crate::kernel::syscall::{Fd, Flags, print_syscall_table, set_syscall_trace, syscall_entry, syscall_info, syscall_trace,
+                             SYS_MUNMAP};
+use crate::kernel::uaccess::UserPtr;
+use crate::println;
+
+define_syscall!(118, fn typed_test(a: i32, fd: Fd, flags: Flags<0b101>, ptr: UserPtr<u64>) -> SyscallResult {
+    Ok((a as i64 + fd.0 as i64 + flags.0 as i64 + ptr.addr() as i64) as u64)
+});
+
+fn call(id: usize, args: [u64; 6]) -> u64 {
+    let mut context: UserContext = unsafe { MaybeUninit::zeroed().assume_init() };
+    context.x[..6].copy_from_slice(&args);
+    context.x[8] = id as u64;
+    syscall_entry(&mut context);
+    context.x[0]
+}
+
+#[test_case]
+pub fn syscall_test() {
+    println!("syscall_test: start");
+    assert_eq!(call(0, [0; 6]), 0x114514);
+    // Errors are returned as negative numbers.
+    assert_eq!(call(SYS_MUNMAP, [1, 1, 0, 0, 0, 0]), EINVAL.as_return_value());
+    assert_eq!(call(SYS_MUNMAP, [1, 1, 0, 0, 0, 0]) as i64, -22);
+    // Unknown syscalls do not panic.
+    assert_eq!(call(255, [0; 6]), ENOSYS.as_return_value());
+    assert_eq!(call(100000, [0; 6]), ENOSYS.as_return_value());
+
+    // Arguments are decoded by their types.
+    assert_eq!(call(118, [(-1i64) as u64, 10, 0b100, 0x1000, 0, 0]), 0x1000 + 10 + 0b100 - 1);
+    assert_eq!(call(118, [0, (-1i64) as u64, 0, 0, 0, 0]), EBADF.as_return_value());
+    assert_eq!(call(118, [0, 0, 0b10, 0, 0, 0]), EINVAL.as_return_value());
+    let info = syscall_info(118).unwrap();
+    assert_eq!(info.name, "typed_test");
+    assert!(info.signature.starts_with("a: i32, fd: Fd"));
+    assert_eq!(syscall_info(SYS_MUNMAP).unwrap().name, "sys_munmap");
+    assert_eq!(syscall_info(SYS_MUNMAP).unwrap().signature, "addr: usize, len: usize");
+    print_syscall_table();
+
+    // Tracing prints each call, and changes nothing else.
+    set_syscall_trace(true);
+    assert!(syscall_trace());
+    assert_eq!(call(0, [1, 2, 3, 4, 5, 6]), 0x114514);
+    set_syscall_trace(false);
+    assert!(!syscall_trace());
+    println!("syscall_test: PASS");
+}
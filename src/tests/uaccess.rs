@@ -0,0 +1,53 @@
+use crate::aarch64::mmu::PAGE_SIZE;
+use crate::cores::virtual_memory::{PageTableDirectory, VirtualMemoryPageTable};
+use crate::kernel::sched::thisproc;
+use crate::kernel::errno::Errno::EFAULT;
+use crate::kernel::uaccess::{copy_in, UserPtr, UserSlice};
+use crate::kernel::vm::{Backing, PROT_READ, PROT_WRITE, Vma, VmaTree};
+use crate::println;
+
+const ADDR: usize = 0x3000000;
+
+#[test_case]
+pub fn uaccess_test() {
+    println!("uaccess_test: start");
+    let proc = thisproc();
+    proc.vmas.insert(Vma::new(ADDR, ADDR + 2 * PAGE_SIZE, PROT_READ | PROT_WRITE, Backing::Anonymous));
+    proc.vmas.insert(Vma::new(ADDR + 2 * PAGE_SIZE, ADDR + 3 * PAGE_SIZE, PROT_READ, Backing::Anonymous));
+
+    // A value may cross pages.
+    let ptr = UserPtr::<u64>::new(ADDR + PAGE_SIZE - 4);
+    assert_eq!(ptr.write(0x1122334455667788), Ok(()));
+    assert_eq!(ptr.read(), Ok(0x1122334455667788));
+    assert_eq!(ptr.add(1).read(), Ok(0));
+    let slice = UserSlice::new(ADDR + 16, 5);
+    assert_eq!(slice.write(b"hello"), Ok(()));
+    let mut buf = [0u8; 5];
+    assert_eq!(slice.read(&mut buf), Ok(()));
+    assert_eq!(&buf, b"hello");
+
+    // Bad pointers are rejected instead of faulting in the kernel.
+    let value = 0u64;
+    for addr in [0x10, ADDR + 3 * PAGE_SIZE, &value as *const u64 as usize, usize::MAX - 3] {
+        assert_eq!(UserPtr::<u64>::new(addr).read(), Err(EFAULT));
+    }
+    assert_eq!(UserPtr::<u64>::new(ADDR + 2 * PAGE_SIZE).read(), Ok(0));
+    assert_eq!(UserPtr::<u64>::new(ADDR + 2 * PAGE_SIZE).write(1), Err(EFAULT));
+
+    // Writing a page shared copy-on-write copies it first.
+    let mut pgdir = PageTableDirectory::new();
+    let mut vmas = VmaTree::uninit();
+    vmas.init();
+    pgdir.copy_from(&proc.pgdir);
+    proc.vmas.copy_to(&mut vmas);
+    assert_eq!(ptr.write(42), Ok(()));
+    assert_eq!(ptr.read(), Ok(42));
+    let mut buf = [0u8; 8];
+    assert_eq!(copy_in(&mut pgdir, &mut vmas, &mut buf, ptr.addr()), Ok(()));
+    assert_eq!(u64::from_le_bytes(buf), 0x1122334455667788);
+    vmas.clear(&mut pgdir);
+    pgdir.free();
+
+    proc.vmas.unmap(&mut proc.pgdir, ADDR, ADDR + 3 * PAGE_SIZE);
+    println!("uaccess_test: PASS");
+}
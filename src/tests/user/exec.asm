@@ -0,0 +1,23 @@
+.global exec_start
+.global exec_end
+
+// Copied into an ELF file by the exec test, and loaded at 0x400000.
+.align 12
+exec_start:
+    // argc
+    ldr x0, [sp]
+    // The first character of argv[1].
+    ldr x1, [sp, #16]
+    ldrb w1, [x1]
+    // Initialized data and bss.
+    mov x2, #0x800000
+    ldr x2, [x2]
+    mov x3, #0x801000
+    ldr x3, [x3]
+    mov x8, #116
+    svc #0
+spin:
+    b spin
+
+.align 3
+exec_end:
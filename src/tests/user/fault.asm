@@ -0,0 +1,18 @@
+.global fault_start
+.global fault_end
+
+.align 12
+fault_start:
+    // Write to and read back from the address in x0.
+    mov x4, x0
+    mov x1, #42
+    str x1, [x4]
+    ldr x1, [x4]
+    mov x0, x4
+    mov x8, #117
+    svc #0
+spin:
+    b spin
+
+.align 12
+fault_end:
@@ -0,0 +1,27 @@
+.global fork_start
+.global fork_end
+
+.align 12
+fork_start:
+    mov x8, #220
+    svc #0
+    mov x4, x0
+    // Write the result of fork into the data page, which should not be shared.
+    mov x5, #0x800000
+    str x4, [x5]
+    mov x0, #10000
+    mov x1, #0
+    mov x2, #1
+delay:
+    add x1, x1, x2
+    cmp x0, x1
+    bne delay
+    ldr x1, [x5]
+    mov x0, x4
+    mov x8, #115
+    svc #0
+spin:
+    b spin
+
+.align 12
+fork_end:
@@ -0,0 +1,43 @@
+.global msg_start
+.global msg_end
+
+.align 12
+msg_start:
+    // x0: the message queue id, x1: 0 to send, or 1 to receive.
+    mov x19, x0
+    mov x20, x1
+    mov x21, #0x800000
+    cbnz x20, receive
+    // msgsnd(id, buf, 8, 0) with mtype 7 and text 0x1234.
+    mov x2, #7
+    str x2, [x21]
+    mov x2, #0x1234
+    str x2, [x21, #8]
+    mov x0, x19
+    mov x1, x21
+    mov x2, #8
+    mov x3, #0
+    mov x8, #189
+    svc #0
+    b report
+receive:
+    // msgrcv(id, buf, 8, 0, 0)
+    mov x0, x19
+    mov x1, x21
+    mov x2, #8
+    mov x3, #0
+    mov x4, #0
+    mov x8, #188
+    svc #0
+report:
+    mov x1, x0
+    mov x0, x20
+    ldr x2, [x21]
+    ldr x3, [x21, #8]
+    mov x8, #119
+    svc #0
+spin:
+    b spin
+
+.align 12
+msg_end:
@@ -0,0 +1,81 @@
+.global signal_start
+.global signal_end
+
+.align 12
+signal_start:
+    // x0: 0 to handle signals, or 1 to spin.
+    cbnz x0, spin
+    mov x21, #0x800000
+    // rt_sigaction(SIGUSR1, act, 0, 8), with act at x21: handler, SA_RESTORER, restorer and no mask.
+    adr x1, handler
+    str x1, [x21]
+    mov x1, #0x4000000
+    str x1, [x21, #8]
+    adr x1, restorer
+    str x1, [x21, #16]
+    str xzr, [x21, #24]
+    mov x0, #10
+    mov x1, x21
+    mov x2, #0
+    mov x3, #8
+    mov x8, #134
+    svc #0
+    // rt_sigprocmask(SIG_BLOCK, set, 0, 8), with set at x21 + 32 holding SIGUSR2.
+    mov x1, #0x800
+    str x1, [x21, #32]
+    mov x0, #0
+    add x1, x21, #32
+    mov x2, #0
+    mov x3, #8
+    mov x8, #135
+    svc #0
+    // Report that we are ready, and wait for the handler to set the flag at x21 + 40.
+    mov x19, #0x1234
+    mov x0, #0
+    mov x1, #0
+    mov x2, #0
+    mov x8, #120
+    svc #0
+wait:
+    ldr x2, [x21, #40]
+    cbz x2, wait
+    // rt_sigpending(x21 + 48, 8)
+    add x0, x21, #48
+    mov x1, #8
+    mov x8, #136
+    svc #0
+    // Report x19, which the handler clobbers, and the pending signals.
+    mov x0, #2
+    mov x1, x19
+    ldr x2, [x21, #48]
+    mov x8, #120
+    svc #0
+    // rt_sigprocmask(SIG_UNBLOCK, set, 0, 8), after which SIGUSR2 terminates us.
+    mov x0, #1
+    add x1, x21, #32
+    mov x2, #0
+    mov x3, #8
+    mov x8, #135
+    svc #0
+spin:
+    b spin
+
+handler:
+    // x0: the signal number. Report it and set the flag.
+    mov x1, x0
+    mov x19, #0
+    mov x0, #1
+    mov x2, #0
+    mov x8, #120
+    svc #0
+    mov x2, #1
+    str x2, [x21, #40]
+    ret
+
+restorer:
+    // rt_sigreturn()
+    mov x8, #139
+    svc #0
+
+.align 12
+signal_end:
@@ -4,7 +4,9 @@ use crate::aarch64::mmu::{kernel2physical, PAGE_SIZE, physical2kernel};
 use crate::common::sem::Semaphore;
 use crate::cores::virtual_memory::{PageTableDirectory, pte_flags, VirtualMemoryPageTable};
 use crate::kernel::mem::{kalloc_page, kfree_page};
+use crate::kernel::errno::SyscallResult;
 use crate::kernel::proc::{create_proc, kill, start_proc, wait};
+use crate::kernel::signal::{signal_exit_code, SIGKILL};
 use crate::{define_syscall, get_cpu_id, println};
 
 static mut DONE: Semaphore = Semaphore::uninit(0);
@@ -55,12 +57,12 @@ static mut PROC_CNT: [u64; 22] = [0; 22];
 static mut CPU_CNT: [u64; 4] = [0; 4];
 static mut STOP: bool = false;
 
-pub fn report(args: [u64; 6]) -> u64 {
+pub fn report(args: [u64; 6]) -> SyscallResult {
     let id = args[0];
     assert!(id < 22);
     unsafe {
         if STOP {
-            return 0;
+            return Ok(0);
         }
         PROC_CNT[id as usize] += 1;
         CPU_CNT[get_cpu_id()] += 1;
@@ -69,7 +71,7 @@ pub fn report(args: [u64; 6]) -> u64 {
             DONE.post();
         }
     }
-    return 0;
+    return Ok(0);
 }
 
 define_syscall!(114, report);
@@ -105,11 +107,11 @@ pub fn user_proc_test() {
     }
     println!("done");
     for pid in pids {
-        assert!(kill(pid));
+        assert_eq!(kill(pid, SIGKILL), Ok(()));
     }
     for _ in pids {
         let (_, code) = wait().unwrap();
-        assert_eq!(code, -1);
+        assert_eq!(code, signal_exit_code(SIGKILL));
     }
     println!("user proc test: PASS");
     unsafe {
@@ -119,5 +121,10 @@ pub fn user_proc_test() {
         for i in 0..PROC_CNT.len() {
             println!("proc {}: cnt {}", i, PROC_CNT[i as usize]);
         }
+        // The load is balanced, so that every CPU runs at least half its even share.
+        let total: u64 = CPU_CNT.iter().sum();
+        for cnt in CPU_CNT {
+            assert!(cnt * 2 * CPU_CNT.len() as u64 >= total);
+        }
     }
 }
\ No newline at end of file
@@ -0,0 +1,68 @@
+use crate::aarch64::mmu::{physical2kernel, PAGE_SIZE};
+use crate::cores::virtual_memory::{AccessPermission, PageTableDirectory, VirtualMemoryPageTable};
+use crate::kernel::mem::page_ref_count;
+use crate::kernel::errno::Errno::ENOMEM;
+use crate::kernel::vm::{Backing, PROT_READ, PROT_WRITE, Vma, VmaTree};
+use crate::println;
+
+const ADDR: usize = 0x1000000;
+
+fn page_at(pgdir: &mut PageTableDirectory, va: usize) -> Option<*mut u8> {
+    pgdir.walk(va, false).map(|pte| physical2kernel(unsafe { (*pte).addr(3) } as u64) as *mut u8)
+}
+
+#[test_case]
+pub fn vma_test() {
+    println!("vma_test: start");
+    let mut pgdir = PageTableDirectory::new();
+    let mut vmas = VmaTree::uninit();
+    vmas.init();
+    vmas.insert(Vma::new(ADDR, ADDR + 8 * PAGE_SIZE, PROT_READ | PROT_WRITE, Backing::Anonymous));
+    assert!(!vmas.is_free(ADDR + 7 * PAGE_SIZE, ADDR + 9 * PAGE_SIZE));
+
+    // Pages are allocated on the first access only.
+    assert!(page_at(&mut pgdir, ADDR + PAGE_SIZE).is_none());
+    assert!(vmas.fault_in(&mut pgdir, ADDR + PAGE_SIZE + 8, true, false));
+    let page = page_at(&mut pgdir, ADDR + PAGE_SIZE).unwrap();
+    assert_eq!(page_ref_count(page), 1);
+    assert!(!vmas.fault_in(&mut pgdir, ADDR + 8 * PAGE_SIZE, false, false));
+    assert!(!vmas.fault_in(&mut pgdir, ADDR, false, true));
+
+    // Changing the protection of the middle splits the area.
+    assert!(vmas.fault_in(&mut pgdir, ADDR + 2 * PAGE_SIZE, true, false));
+    assert_eq!(vmas.protect(&mut pgdir, ADDR + 2 * PAGE_SIZE, ADDR + 4 * PAGE_SIZE, PROT_READ), Ok(()));
+    assert_eq!(vmas.len(), 3);
+    assert!(!vmas.fault_in(&mut pgdir, ADDR + 3 * PAGE_SIZE, true, false));
+    assert!(vmas.fault_in(&mut pgdir, ADDR + 3 * PAGE_SIZE, false, false));
+    let pte = unsafe { &*pgdir.walk(ADDR + 2 * PAGE_SIZE, false).unwrap() };
+    assert!(matches!(pte.access_permission(), AccessPermission::EL1rEL0r));
+    let pte = unsafe { &*pgdir.walk(ADDR + PAGE_SIZE, false).unwrap() };
+    assert!(matches!(pte.access_permission(), AccessPermission::El1rwEl0rw));
+    // Nothing changes if the range is not fully mapped.
+    assert_eq!(vmas.protect(&mut pgdir, ADDR + 7 * PAGE_SIZE, ADDR + 9 * PAGE_SIZE, PROT_READ), Err(ENOMEM));
+    assert_eq!(vmas.len(), 3);
+
+    // Unmapping frees the pages and leaves a hole.
+    vmas.unmap(&mut pgdir, ADDR + PAGE_SIZE, ADDR + 3 * PAGE_SIZE);
+    assert_eq!(page_ref_count(page), 0);
+    assert!(page_at(&mut pgdir, ADDR + PAGE_SIZE).is_none());
+    assert!(vmas.find(ADDR + 2 * PAGE_SIZE).is_none());
+    assert_eq!(vmas.find(ADDR + 3 * PAGE_SIZE).unwrap().prot, PROT_READ);
+    assert_eq!(vmas.len(), 3);
+    assert_eq!(vmas.find_free(ADDR, 2 * PAGE_SIZE), Some(ADDR + PAGE_SIZE));
+    assert_eq!(vmas.find_free(ADDR, 3 * PAGE_SIZE), Some(ADDR + 8 * PAGE_SIZE));
+
+    // A VMA spanning many sub page tables, only one of which exists, is unmapped without walking the rest.
+    let far = ADDR + (1 << 39);
+    vmas.insert(Vma::new(far - (1 << 30), far + (1 << 30), PROT_READ | PROT_WRITE, Backing::Anonymous));
+    assert!(vmas.fault_in(&mut pgdir, far, true, false));
+    assert_eq!(pgdir.count_pages(), 2);
+
+    // Clearing frees the page tables too, leaving only the root.
+    vmas.clear(&mut pgdir);
+    assert_eq!(vmas.len(), 0);
+    assert_eq!(pgdir.count_pages(), 0);
+    assert!(pgdir.get_page_table().iter().all(|entry| !entry.valid()));
+    pgdir.free();
+    println!("vma_test: PASS");
+}